@@ -0,0 +1,99 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A file written to a temporary file in the same directory and renamed over `path` by
+/// `commit`, so `path` is never left half-written.
+///
+/// The temporary file is removed when it is dropped without `commit`, the previous
+/// contents of `path` stay untouched.
+pub struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    temp_path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file_name = path.file_name().ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`{}` is not a file path", path.display()),
+        ))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", process::id()));
+        let temp_path = path.with_file_name(temp_name);
+        let file = File::create(&temp_path)?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            temp_path,
+            committed: false,
+        })
+    }
+
+    /// Replaces `path` with the written contents.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Same as `fs::write`, but atomic.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(contents.as_ref())?;
+    file.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_replaces_file() -> io::Result<()> {
+        let path = Path::new("./test_atomic_commit.txt");
+        fs::write(path, "old contents, longer than the new ones")?;
+        write(path, "new")?;
+        assert_eq!(fs::read_to_string(path)?, "new");
+        fs::remove_file(path)
+    }
+
+    #[test]
+    fn test_drop_keeps_old_file() -> io::Result<()> {
+        let path = Path::new("./test_atomic_drop.txt");
+        fs::write(path, "old")?;
+        let mut file = AtomicFile::create(path)?;
+        file.write_all(b"half written")?;
+        let temp_path = file.temp_path.clone();
+        assert!(temp_path.exists());
+        drop(file);
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read_to_string(path)?, "old");
+        fs::remove_file(path)
+    }
+}
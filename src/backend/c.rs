@@ -0,0 +1,332 @@
+use std::collections::BTreeSet;
+
+use super::{label_symbol, return_symbol, Backend, StaticAllocator};
+
+const PRELUDE: &str = "\
+/* generated by vmtranslator */
+#include <stdint.h>
+
+/* Hack RAM, every 16 bits address is valid */
+static uint16_t ram[65536];
+
+#define SP ram[0]
+#define LCL ram[1]
+#define ARG ram[2]
+#define THIS ram[3]
+#define THAT ram[4]
+#define R13 ram[13]
+#define R14 ram[14]
+#define TOP ram[(uint16_t)(SP - 1)]
+#define AT(base, index) ram[(uint16_t)((base) + (index))]
+#define BOOL(x) ((x) ? 0xffff : 0)
+
+static void push(uint16_t value) {
+    ram[SP] = value;
+    SP++;
+}
+
+static uint16_t pop(void) {
+    SP--;
+    return ram[SP];
+}
+
+";
+
+/// Translates VM commands to a self-contained C99 program.
+///
+/// Every VM function is a labelled block inside `main`, `goto`/`if-goto` are C `goto`s and a
+/// return address is the number of its call site, `return` jumps back through a `switch`.
+/// The program exits with the low byte of the value `Sys.init` returns.
+pub struct CBackend {
+    source_filename: Option<String>,
+    current_function: String,
+    current_function_call_count: u32,
+    return_labels: Vec<String>,
+    statics: StaticAllocator,
+    defined_functions: BTreeSet<String>,
+    called_functions: BTreeSet<String>,
+}
+
+impl CBackend {
+    pub fn new() -> Self {
+        Self {
+            source_filename: None,
+            current_function: "Bootstrap".to_string(),
+            current_function_call_count: 0,
+            return_labels: vec![],
+            statics: StaticAllocator::default(),
+            defined_functions: BTreeSet::new(),
+            called_functions: BTreeSet::new(),
+        }
+    }
+
+    /// C expression of a segment entry, it can be read and assigned.
+    fn segment(&mut self, segment: &str, index: i32) -> String {
+        match segment {
+            "local" => format!("AT(LCL, {index})"),
+            "argument" => format!("AT(ARG, {index})"),
+            "this" => format!("AT(THIS, {index})"),
+            "that" => format!("AT(THAT, {index})"),
+            "temp" => format!("ram[{}]", 5 + index),
+            "pointer" => format!("ram[{}]", 3 + index),
+            "static" => {
+                assert!(self.source_filename.is_some());
+                let source_file = self.source_filename.as_ref().unwrap();
+                format!("ram[{}]", self.statics.address(source_file, index))
+            }
+            _ => panic!("push/pop segment syntax error: unknow segment `{segment}`"),
+        }
+    }
+
+    fn _gen_label(&self, label: &str) -> String {
+        identifier("l_", &label_symbol(&self.current_function, label))
+    }
+
+    fn _write_bootstrap(&mut self) -> String {
+        String::new()
+            + PRELUDE
+            + "int main(void) {\n"
+            + "    uint16_t d;\n"
+            + "    SP = 256;\n"
+            + &self._write_call("Sys.init", 0)
+            + "    /* exit(low byte of the value Sys.init returns) */\n"
+            + "    return TOP & 255;\n"
+    }
+
+    fn _write_arithmetic(&mut self, cmd: &str) -> String {
+        let code = match cmd {
+            "add" => "    d = pop(); TOP += d;\n",
+            "sub" => "    d = pop(); TOP -= d;\n",
+            "and" => "    d = pop(); TOP &= d;\n",
+            "or" => "    d = pop(); TOP |= d;\n",
+            "neg" => "    TOP = -TOP;\n",
+            "not" => "    TOP = ~TOP;\n",
+            "eq" => "    d = pop(); TOP = BOOL(TOP == d);\n",
+            "gt" => "    d = pop(); TOP = BOOL((int16_t)TOP > (int16_t)d);\n",
+            "lt" => "    d = pop(); TOP = BOOL((int16_t)TOP < (int16_t)d);\n",
+            cmd => panic!("arithmetic command syntax error: unknow command `{cmd}`"),
+        };
+        format!("    /* {cmd} */\n") + code
+    }
+
+    fn _write_function(&mut self, function_name: &str, n_vars: u32) -> String {
+        let mut s = format!("{}:\n", identifier("f_", function_name));
+        for _ in 0..n_vars {
+            s += "    push(0);\n";
+        }
+        self.current_function = function_name.to_string();
+        self.current_function_call_count = 0;
+        self.defined_functions.insert(function_name.to_string());
+        s
+    }
+
+    fn _write_call(&mut self, function_name: &str, n_args: u32) -> String {
+        let return_id = self.return_labels.len();
+        assert!(
+            return_id < 1 << 16,
+            "too many call sites for 16 bits return addresses"
+        );
+        let return_label = identifier(
+            "r_",
+            &return_symbol(&self.current_function, self.current_function_call_count),
+        );
+        self.return_labels.push(return_label.clone());
+        self.current_function_call_count += 1;
+        self.called_functions.insert(function_name.to_string());
+
+        String::new()
+            + &format!("    /* call {function_name} {n_args} */\n")
+            + &format!("    push({return_id});\n")
+            + "    push(LCL);\n"
+            + "    push(ARG);\n"
+            + "    push(THIS);\n"
+            + "    push(THAT);\n"
+            + &format!("    ARG = SP - 5 - {n_args};\n")
+            + "    LCL = SP;\n"
+            + &format!("    goto {};\n", identifier("f_", function_name))
+            + &format!("{return_label}:\n")
+    }
+
+    fn _write_return(&mut self) -> String {
+        String::new()
+            + "    /* return */\n"
+            + "    R13 = LCL;\n"
+            + "    R14 = AT(R13, -5);\n"
+            + "    AT(ARG, 0) = pop();\n"
+            + "    SP = ARG + 1;\n"
+            + "    THAT = AT(R13, -1);\n"
+            + "    THIS = AT(R13, -2);\n"
+            + "    ARG = AT(R13, -3);\n"
+            + "    LCL = AT(R13, -4);\n"
+            + "    goto vm_return;\n"
+    }
+
+    fn _write_close(&mut self) -> String {
+        let mut s = String::new();
+        if self.called_functions.contains("Sys.halt")
+            && !self.defined_functions.contains("Sys.halt")
+        {
+            s += "    /* Sys.halt is not defined by the program, stop it */\n";
+            s += &format!("{}:\n", identifier("f_", "Sys.halt"));
+            s += "    return 0;\n";
+        }
+        s += "vm_return:\n";
+        s += "    switch (R14) {\n";
+        for (return_id, label) in self.return_labels.iter().enumerate() {
+            s += &format!("    case {return_id}: goto {label};\n");
+        }
+        s += "    }\n";
+        s += "    return 0;\n";
+        s += "}\n";
+        s
+    }
+}
+
+impl Default for CBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// VM names may contain `.`, `$` and `:`, escape them to a valid C identifier.
+fn identifier(prefix: &str, name: &str) -> String {
+    let mut s = prefix.to_string();
+    for c in name.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => s.push(c),
+            '_' => s += "__",
+            c => s += &format!("_{:02X}", c as u32),
+        }
+    }
+    s
+}
+
+impl Backend for CBackend {
+    fn extension(&self) -> &'static str {
+        "c"
+    }
+
+    fn comment_delimiters(&self) -> (&'static str, &'static str) {
+        ("/*", "*/")
+    }
+
+    fn set_source_file(&mut self, source_file: &str) {
+        self.source_filename = Some(source_file.to_string());
+    }
+
+    fn bootstrap(&mut self) -> String {
+        self._write_bootstrap()
+    }
+
+    fn push(&mut self, segment: &str, index: i32) -> String {
+        let value = match segment {
+            "constant" => index.to_string(),
+            _ => self.segment(segment, index),
+        };
+        format!("    /* push {segment} {index} */\n    push({value});\n")
+    }
+
+    fn pop(&mut self, segment: &str, index: i32) -> String {
+        let target = self.segment(segment, index);
+        format!("    /* pop {segment} {index} */\n    d = pop(); {target} = d;\n")
+    }
+
+    fn arithmetic(&mut self, cmd: &str, _id: &str) -> String {
+        self._write_arithmetic(cmd)
+    }
+
+    fn label(&mut self, label: &str) -> String {
+        format!("{}:\n", self._gen_label(label))
+    }
+
+    fn goto(&mut self, label: &str) -> String {
+        format!("    goto {};\n", self._gen_label(label))
+    }
+
+    fn if_goto(&mut self, label: &str) -> String {
+        format!("    if (pop()) goto {};\n", self._gen_label(label))
+    }
+
+    fn function(&mut self, function_name: &str, n_vars: u32) -> String {
+        self._write_function(function_name, n_vars)
+    }
+
+    fn call(&mut self, function_name: &str, n_args: u32) -> String {
+        self._write_call(function_name, n_args)
+    }
+
+    fn ret(&mut self) -> String {
+        self._write_return()
+    }
+
+    fn finish(&mut self) -> String {
+        self._write_close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier("f_", "Main.main"), "f_Main_2Emain");
+        assert_eq!(
+            identifier("l_", "Main.f$IF_TRUE0"),
+            "l_Main_2Ef_24IF__TRUE0"
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_calls_sys_init() {
+        let mut backend = CBackend::new();
+        let s = backend.bootstrap();
+        assert!(s.contains("    SP = 256;\n"));
+        assert!(s.contains("    goto f_Sys_2Einit;\nr_Bootstrap_24ret_2E0:\n"));
+    }
+
+    #[test]
+    #[ignore = "needs a C compiler, run with `cargo test -- --ignored`"]
+    fn test_run_native() -> std::io::Result<()> {
+        let mut backend = CBackend::new();
+        let mut s = backend.bootstrap();
+        backend.set_source_file("Main.vm");
+        s += &backend.function("Sys.init", 0);
+        s += &backend.push("constant", 7);
+        s += &backend.pop("static", 0);
+        s += &backend.push("constant", 20);
+        s += &backend.call("Main.double", 1);
+        s += &backend.push("static", 0);
+        s += &backend.arithmetic("add", "0");
+        s += &backend.ret();
+        s += &backend.function("Main.double", 1);
+        s += &backend.push("argument", 0);
+        s += &backend.pop("local", 0);
+        s += &backend.push("local", 0);
+        s += &backend.push("local", 0);
+        s += &backend.arithmetic("add", "1");
+        s += &backend.ret();
+        s += &backend.finish();
+
+        let dir = env::temp_dir().join(format!("vmtranslator_c_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let source = dir.join("Main.c");
+        let binary = dir.join("Main");
+        fs::write(&source, s)?;
+        let built = Command::new("cc")
+            .args(["-std=c99", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status();
+        if !matches!(built, Ok(status) if status.success()) {
+            fs::remove_dir_all(&dir)?;
+            panic!("`cc` can't compile the program: {built:?}");
+        }
+        let status = Command::new(&binary).status()?;
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(status.code(), Some(47));
+        Ok(())
+    }
+}
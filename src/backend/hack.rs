@@ -0,0 +1,646 @@
+use std::collections::HashMap;
+
+use super::{label_symbol, return_symbol, static_symbol, Backend};
+
+/// Translates VM commands to Hack assembly.
+///
+/// R13 to R15 are reserved to the translation, like in the nand2tetris VM specification: pops
+/// and comparisons keep a value in R13, and returns the frame in R13 and the return address
+/// in R14. Programs that access them through `pointer`, `this` or `that` see them change.
+pub struct HackBackend {
+    source_filename: Option<String>,
+    current_function: Option<String>,
+    current_function_call_count: u32, // 每个函数内call的次数，用来分配不同的返回地址
+    mem_seg_map: HashMap<String, String>,
+}
+
+impl HackBackend {
+    pub fn new() -> Self {
+        let mem_seg_map = HashMap::from([
+            ("local".to_string(), "LCL".to_string()),
+            ("argument".to_string(), "ARG".to_string()),
+            ("this".to_string(), "THIS".to_string()),
+            ("that".to_string(), "THAT".to_string()),
+        ]);
+
+        Self {
+            source_filename: None,
+            current_function: Some("Bootstrap".to_string()),
+            current_function_call_count: 0,
+            mem_seg_map,
+        }
+    }
+
+    fn set_current_function(&mut self, current_function: &str) {
+        self.current_function = Some(current_function.to_string());
+    }
+
+    fn _write_arithmetic(&mut self, cmd: &str, id: &str) -> String {
+        match cmd {
+            "add" => self._write_binary(cmd, "M=D+M", "x = x + y"),
+            "sub" => self._write_binary(cmd, "M=M-D", "x = x - y"),
+            "and" => self._write_binary(cmd, "M=D&M", "x = x & y"),
+            "or" => self._write_binary(cmd, "M=D|M", "x = x | y"),
+            "neg" => self._write_unary(cmd, "M=-M", "x = -x"),
+            "not" => self._write_unary(cmd, "M=!M", "x = !x"),
+            "eq" => self._write_comparison(cmd, "JEQ", id),
+            "gt" => self._write_comparison(cmd, "JGT", id),
+            "lt" => self._write_comparison(cmd, "JLT", id),
+            cmd => panic!("arithmetic command syntax error: unknow command `{cmd}`"),
+        }
+    }
+
+    /// `x op y`, computed in the stack word of `x`.
+    fn _write_binary(&mut self, cmd: &str, instruction: &str, comment: &str) -> String {
+        String::new()
+            + &format!("// start ======= {cmd}\n")
+            + "// D=y, SP--\n"
+            + "@SP\n"
+            + "AM=M-1\n"
+            + "D=M\n"
+            + &format!("// {comment}\n")
+            + "A=A-1\n"
+            + instruction
+            + "\n"
+            + &format!("// end ======= {cmd}\n")
+            + "\n"
+    }
+
+    /// `op x`, computed in the stack word of `x`.
+    fn _write_unary(&mut self, cmd: &str, instruction: &str, comment: &str) -> String {
+        String::new()
+            + &format!("// start ======= {cmd}\n")
+            + &format!("// {comment}\n")
+            + "@SP\n"
+            + "A=M-1\n"
+            + instruction
+            + "\n"
+            + &format!("// end ======= {cmd}\n")
+            + "\n"
+    }
+
+    /// `x = x cmp y`, true(-1) when `jump` jumps on `x - y`. When `x` and `y` have different
+    /// signs `x - y` may overflow, and `D` is 1 or -1 instead.
+    fn _write_comparison(&mut self, cmd: &str, jump: &str, id: &str) -> String {
+        String::new()
+            + &format!("// start ======= {cmd}\n")
+            + "// R13=y, SP--\n"
+            + "@SP\n"
+            + "AM=M-1\n"
+            + "D=M\n"
+            + "@R13\n"
+            + "M=D\n"
+            + "// D=x\n"
+            + "@SP\n"
+            + "A=M-1\n"
+            + "D=M\n"
+            + &format!("@X_NEGATIVE_{id}\n")
+            + "D;JLT\n"
+            + "// x >= 0, D=1 if y < 0\n"
+            + "@R13\n"
+            + "D=M\n"
+            + &format!("@SUBTRACT_{id}\n")
+            + "D;JGE\n"
+            + "D=1\n"
+            + &format!("@COMPARE_{id}\n")
+            + "0;JMP\n"
+            + &format!("(X_NEGATIVE_{id})\n")
+            + "// x < 0, D=-1 if y >= 0\n"
+            + "@R13\n"
+            + "D=M\n"
+            + &format!("@SUBTRACT_{id}\n")
+            + "D;JLT\n"
+            + "D=-1\n"
+            + &format!("@COMPARE_{id}\n")
+            + "0;JMP\n"
+            + &format!("(SUBTRACT_{id})\n")
+            + "// same signs, D=x-y\n"
+            + "@R13\n"
+            + "D=M\n"
+            + "@SP\n"
+            + "A=M-1\n"
+            + "D=M-D\n"
+            + &format!("(COMPARE_{id})\n")
+            + "// x=true(-1), false(0) unless D tests true\n"
+            + "@SP\n"
+            + "A=M-1\n"
+            + "M=-1\n"
+            + &format!("@CONTINUE_{id}\n")
+            + &format!("D;{jump}\n")
+            + "@SP\n"
+            + "A=M-1\n"
+            + "M=0\n"
+            + &format!("(CONTINUE_{id})\n")
+            + &format!("// end ======= {cmd}\n")
+            + "\n"
+    }
+
+    fn _write_push(&mut self, arg1: &str, arg2: i32) -> String {
+        match arg1 {
+            "constant" => {
+                String::new()
+                    + &format!("// start ======== push {arg1} {arg2}\n")
+                    + &format!("// D={arg2}\n")
+                    + &format!("@{arg2}\n")
+                    + "D=A\n"
+                    + "// stack[SP]=D\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + "// SP++\n"
+                    + "@SP\n"
+                    + "M=M+1\n"
+                    + &format!("// end ======== push {arg1} {arg2}\n")
+                    + "\n"
+            }
+            "temp" => {
+                let ram_address = arg2 + 5;
+                String::new()
+                    + &format!("// start ======== push {arg1} {arg2}\n")
+                    + &format!("// D={arg1}+{arg2}\n")
+                    + &format!("@{ram_address}\n")
+                    + "D=M\n"
+                    + "// stack[SP]=D\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + "// SP++\n"
+                    + "@SP\n"
+                    + "M=M+1\n"
+                    + &format!("// end ======== push {arg1} {arg2}\n")
+                    + "\n"
+            }
+            "static" => {
+                assert!(self.source_filename.is_some());
+                let static_var_id = static_symbol(self.source_filename.as_ref().unwrap(), arg2);
+                String::new()
+                    + &format!("// start ======== push {arg1} {arg2}\n")
+                    + &format!("// D={arg1}+{arg2}\n")
+                    + &format!("@{static_var_id}\n")
+                    + "D=M\n"
+                    + "// stack[SP]=D\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + "// SP++\n"
+                    + "@SP\n"
+                    + "M=M+1\n"
+                    + &format!("// end ======== push {arg1} {arg2}\n")
+                    + "\n"
+            }
+            "pointer" => {
+                let pointer_address = if arg2 == 0 { 3 } else { 4 };
+                String::new()
+                    + &format!("// start ======== push {arg1} {arg2}\n")
+                    + &format!("// D= value of {arg1} {arg2}\n")
+                    + &format!("@{pointer_address}\n")
+                    + "D=M\n"
+                    + "// stack[SP]=D\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + "// SP++\n"
+                    + "@SP\n"
+                    + "M=M+1\n"
+                    + &format!("// end ======== push {arg1} {arg2}\n")
+                    + "\n"
+            }
+            _ => {
+                assert!(self.mem_seg_map.contains_key(arg1));
+                let arg1 = self.mem_seg_map[arg1].clone();
+                String::new()
+                    + &format!("// start ======== push {arg1} {arg2}\n")
+                    + &format!("// D={arg1}+{arg2}\n")
+                    + &format!("@{arg1}\n")
+                    + "D=M\n"
+                    + &format!("@{arg2}\n")
+                    + "D=D+A\n"
+                    + "A=D\n"
+                    + "D=M\n"
+                    + "// stack[SP]=D\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + "// SP++\n"
+                    + "@SP\n"
+                    + "M=M+1\n"
+                    + &format!("// end ======== push {arg1} {arg2}\n")
+                    + "\n"
+            }
+        }
+    }
+
+    fn _write_pop(&mut self, arg1: &str, arg2: i32) -> String {
+        match arg1 {
+            "temp" => {
+                let ram_address = arg2 + 5;
+                String::new()
+                    + &format!("// start ======== pop {arg1} {arg2}\n")
+                    + "// SP--\n"
+                    + "@SP\n"
+                    + "M=M-1\n"
+                    + &format!("// R13=addr({arg1}+{arg2})\n")
+                    + &format!("@{ram_address}\n")
+                    + "D=A\n" // temp hold the register itself, not address
+                    + "@R13\n"
+                    + "M=D\n"
+                    + "// D=stack[SP]\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "D=M\n"
+                    + "// *R13=D\n"
+                    + "@R13\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + &format!("// end ======== pop {arg1} {arg2}\n")
+                    + "\n"
+            }
+            "static" => {
+                assert!(self.source_filename.is_some());
+                let static_var_id = static_symbol(self.source_filename.as_ref().unwrap(), arg2);
+                String::new()
+                    + &format!("// start ======== pop {arg1} {arg2}\n")
+                    + "// SP--\n"
+                    + "@SP\n"
+                    + "M=M-1\n"
+                    + "// D=stack[SP]\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "D=M\n"
+                    + "// *{filename}.i=D\n"
+                    + &format!("@{static_var_id}\n")
+                    + "M=D\n"
+                    + &format!("// end ======== pop {arg1} {arg2}\n")
+                    + "\n"
+            }
+            "pointer" => {
+                let pointer_address = if arg2 == 0 { 3 } else { 4 };
+                String::new()
+                    + &format!("// start ======== pop {arg1} {arg2}\n")
+                    + "// SP--\n"
+                    + "@SP\n"
+                    + "M=M-1\n"
+                    + "// D=stack[SP]\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "D=M\n"
+                    + &format!("// *({arg1} {arg2})=D\n")
+                    + &format!("@{pointer_address}\n")
+                    + "M=D\n"
+                    + &format!("// end ======== pop {arg1} {arg2}\n")
+                    + "\n"
+            }
+            _ => {
+                assert!(self.mem_seg_map.contains_key(arg1));
+                let arg1 = self.mem_seg_map[arg1].clone();
+                String::new()
+                    + &format!("// start ======== pop {arg1} {arg2}\n")
+                    + "// SP--\n"
+                    + "@SP\n"
+                    + "M=M-1\n"
+                    + &format!("// R13=addr({arg1}+{arg2})\n")
+                    + &format!("@{arg1}\n")
+                    + "D=M\n"
+                    + &format!("@{arg2}\n")
+                    + "D=D+A\n"
+                    + "@R13\n"
+                    + "M=D\n"
+                    + "// D=stack[SP]\n"
+                    + "@SP\n"
+                    + "A=M\n"
+                    + "D=M\n"
+                    + "// *R13=D\n"
+                    + "@R13\n"
+                    + "A=M\n"
+                    + "M=D\n"
+                    + &format!("// end ======== pop {arg1} {arg2}\n")
+                    + "\n"
+            }
+        }
+    }
+
+    fn _gen_label(&self, label: &str) -> String {
+        let function = self.current_function.clone().unwrap_or_default();
+        label_symbol(&function, label)
+    }
+
+    fn _write_label(&mut self, label: &str) -> String {
+        format!("({})\n", self._gen_label(label))
+    }
+
+    fn _write_goto(&mut self, label: &str) -> String {
+        format!("@{}\n", self._gen_label(label)) + "0;JMP\n"
+    }
+
+    fn _write_if(&mut self, label: &str) -> String {
+        String::new()
+            + "@SP\n"
+            + "AM=M-1\n"
+            + "D=M\n"
+            + &format!("@{}\n", self._gen_label(label))
+            + "D;JNE\n"
+    }
+
+    fn _gen_fn_name(&self, function_name: &str) -> String {
+        function_name.to_string()
+    }
+
+    fn _write_function(&mut self, function_name: &str, n_vars: u32) -> String {
+        let mut s = String::new();
+        s += &format!("({})\n", self._gen_fn_name(function_name));
+        for _ in 0..n_vars {
+            s += &self._write_push("constant", 0);
+        }
+        self.current_function_call_count = 0;
+        self.set_current_function(function_name);
+        s
+    }
+
+    fn _gen_return_address(&mut self) -> String {
+        let s = return_symbol(
+            &self._gen_fn_name(self.current_function.as_ref().unwrap()),
+            self.current_function_call_count,
+        );
+        self.current_function_call_count += 1;
+        s
+    }
+
+    fn _write_call(&mut self, function_name: &str, n_args: u32) -> String {
+        let return_address = self._gen_return_address();
+        String::new()
+            + "// start call ========================\n"
+            + "// push return_address\n"
+            + &format!("@{return_address}\n")
+            + "D=A\n"
+            + "@SP\n"
+            + "A=M\n"
+            + "M=D\n"
+            + "@SP\n"
+            + "M=M+1\n"
+            + "// push LCL\n"
+            + "@LCL\n"
+            + "D=M\n"
+            + "@SP\n"
+            + "A=M\n"
+            + "M=D\n"
+            + "@SP\n"
+            + "M=M+1\n"
+            + "// push ARG\n"
+            + "@ARG\n"
+            + "D=M\n"
+            + "@SP\n"
+            + "A=M\n"
+            + "M=D\n"
+            + "@SP\n"
+            + "M=M+1\n"
+            + "// push THIS\n"
+            + "@THIS\n"
+            + "D=M\n"
+            + "@SP\n"
+            + "A=M\n"
+            + "M=D\n"
+            + "@SP\n"
+            + "M=M+1\n"
+            + "// push THAT\n"
+            + "@THAT\n"
+            + "D=M\n"
+            + "@SP\n"
+            + "A=M\n"
+            + "M=D\n"
+            + "@SP\n"
+            + "M=M+1\n"
+            + "// ARG=SP-5-n_args\n"
+            + "@SP\n"
+            + "D=M\n"
+            + "@5\n"
+            + "D=D-A\n"
+            + &format!("@{n_args}\n")
+            + "D=D-A\n"
+            + "@ARG\n"
+            + "M=D\n"
+            + "// LCL=SP\n"
+            + "@SP\n"
+            + "D=M\n"
+            + "@LCL\n"
+            + "M=D\n"
+            + "// goto function_name\n"
+            + &format!("@{}\n", self._gen_fn_name(function_name))
+            + "0;JMP\n"
+            + &format!("({})\n", return_address)
+            + "// end call ========================\n"
+    }
+
+    fn _write_return(&mut self) -> String {
+        String::new()
+            + "// start return ========================\n"
+            + "// frame(R13)=LCL\n"
+            + "@LCL\n"
+            + "D=M\n"
+            + "@R13\n"
+            + "M=D\n"
+            + "// return_address(R14)=*(frame-5)\n"
+            + "@R13\n"
+            + "D=M\n"
+            + "@5\n"
+            + "A=D-A\n"
+            + "D=M\n"
+            + "@R14\n"
+            + "M=D\n"
+            + "// *ARG=pop()\n"
+            + "@SP\n"
+            + "AM=M-1\n"
+            + "D=M\n"
+            + "@ARG\n"
+            + "A=M\n"
+            + "M=D\n"
+            + "// SP=ARG+1\n"
+            + "@ARG\n"
+            + "D=M+1\n"
+            + "@SP\n"
+            + "M=D\n"
+            + "// THAT=*(frame-1)\n"
+            + "@R13\n"
+            + "D=M\n"
+            + "@1\n"
+            + "A=D-A\n"
+            + "D=M\n"
+            + "@THAT\n"
+            + "M=D\n"
+            + "// THIS=*(frame-2)\n"
+            + "@R13\n"
+            + "D=M\n"
+            + "@2\n"
+            + "A=D-A\n"
+            + "D=M\n"
+            + "@THIS\n"
+            + "M=D\n"
+            + "// ARG=*(frame-3)\n"
+            + "@R13\n"
+            + "D=M\n"
+            + "@3\n"
+            + "A=D-A\n"
+            + "D=M\n"
+            + "@ARG\n"
+            + "M=D\n"
+            + "// LCL=*(frame-4)\n"
+            + "@R13\n"
+            + "D=M\n"
+            + "@4\n"
+            + "A=D-A\n"
+            + "D=M\n"
+            + "@LCL\n"
+            + "M=D\n"
+            + "// goto return_address\n"
+            + "@R14\n"
+            + "A=M\n"
+            + "0;JMP\n"
+            + "// end return ========================\n"
+    }
+
+    fn _write_bootstrap(&mut self) -> String {
+        // bootstrap code, call Sys.init
+        String::new() + "@256\n" + "D=A\n" + "@SP\n" + "M=D\n" + &self._write_call("Sys.init", 0)
+    }
+
+    fn _write_close(&mut self) -> String {
+        String::new() + "// end the program\n" + "(END)\n" + "@END\n" + "0;JMP\n"
+    }
+}
+
+impl Default for HackBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for HackBackend {
+    fn extension(&self) -> &'static str {
+        "asm"
+    }
+
+    fn comment_delimiters(&self) -> (&'static str, &'static str) {
+        ("//", "")
+    }
+
+    fn set_source_file(&mut self, source_file: &str) {
+        self.source_filename = Some(source_file.to_string());
+    }
+
+    fn bootstrap(&mut self) -> String {
+        self._write_bootstrap()
+    }
+
+    fn push(&mut self, segment: &str, index: i32) -> String {
+        self._write_push(segment, index)
+    }
+
+    fn pop(&mut self, segment: &str, index: i32) -> String {
+        self._write_pop(segment, index)
+    }
+
+    fn arithmetic(&mut self, cmd: &str, id: &str) -> String {
+        self._write_arithmetic(cmd, id)
+    }
+
+    fn label(&mut self, label: &str) -> String {
+        self._write_label(label)
+    }
+
+    fn goto(&mut self, label: &str) -> String {
+        self._write_goto(label)
+    }
+
+    fn if_goto(&mut self, label: &str) -> String {
+        self._write_if(label)
+    }
+
+    fn function(&mut self, function_name: &str, n_vars: u32) -> String {
+        self._write_function(function_name, n_vars)
+    }
+
+    fn call(&mut self, function_name: &str, n_args: u32) -> String {
+        self._write_call(function_name, n_args)
+    }
+
+    fn ret(&mut self) -> String {
+        self._write_return()
+    }
+
+    fn finish(&mut self) -> String {
+        self._write_close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{emulator::Emulator, hack_asm::assemble};
+
+    use super::*;
+
+    /// Runs the code `commands` writes, with the stack at 256.
+    fn run(commands: impl FnOnce(&mut HackBackend) -> String) -> Emulator {
+        let mut backend = HackBackend::new();
+        backend.set_source_file("Main.vm");
+        let asm = "@256\nD=A\n@SP\nM=D\n".to_string() + &commands(&mut backend);
+        let mut emulator = Emulator::new(assemble(&asm).unwrap().words);
+        emulator.run(10000).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_bootstrap_calls_sys_init() {
+        let mut backend = HackBackend::new();
+        let buf = backend.bootstrap();
+        assert!(buf.starts_with("@256\nD=A\n@SP\nM=D\n"));
+        assert!(buf.contains("@Sys.init\n0;JMP\n(Bootstrap$ret.0)\n"));
+    }
+
+    #[test]
+    fn test_labels_are_scoped_by_function() {
+        let mut backend = HackBackend::new();
+        backend.set_source_file("Main.vm");
+        backend.function("Main.main", 0);
+        assert_eq!(backend.label("LOOP"), "(Main.main$LOOP)\n");
+        assert_eq!(backend.goto("LOOP"), "@Main.main$LOOP\n0;JMP\n");
+        assert!(backend.call("Main.f", 1).contains("(Main.main$ret.0)\n"));
+        assert!(backend.call("Main.f", 1).contains("(Main.main$ret.1)\n"));
+    }
+
+    #[test]
+    fn test_comparisons_dont_overflow() {
+        // 20000 - -20000 overflows to a negative number
+        for (cmd, x, y) in [("gt", 20000, -20000), ("lt", -20000, 20000)] {
+            let emulator = run(|backend| {
+                let mut s = String::new();
+                for value in [x, y] {
+                    s += &backend.push("constant", i32::abs(value));
+                    if value < 0 {
+                        s += &backend.arithmetic("neg", "0");
+                    }
+                }
+                s + &backend.arithmetic(cmd, "1")
+            });
+            assert_eq!(emulator.ram[0], 257, "{cmd}");
+            assert_eq!(emulator.ram[256], -1, "{x} {cmd} {y}");
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_keeps_temp() {
+        let emulator = run(|backend| {
+            let mut s = String::new();
+            for i in 0..4 {
+                s += &backend.push("constant", 100 + i);
+                s += &backend.pop("temp", i);
+            }
+            s += &backend.push("constant", 5);
+            s += &backend.push("constant", 6);
+            s += &backend.arithmetic("add", "0");
+            s += &backend.push("constant", 7);
+            s += &backend.arithmetic("eq", "1");
+            s + &backend.arithmetic("not", "2")
+        });
+        assert_eq!(&emulator.ram[5..9], &[100, 101, 102, 103]);
+        assert_eq!((emulator.ram[0], emulator.ram[256]), (257, -1));
+    }
+}
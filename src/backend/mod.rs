@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+mod c;
+mod hack;
+mod wat;
+mod x86_64;
+
+pub use c::CBackend;
+pub use hack::HackBackend;
+pub use wat::WatBackend;
+pub use x86_64::X86Backend;
+
+/// A code generation target for VM programs.
+///
+/// `CodeWriter` drives a backend with the commands produced by the parser, every method
+/// returns the target code for one VM command and `CodeWriter` writes it out.
+pub trait Backend {
+    /// File extension of the generated target file, without the dot.
+    fn extension(&self) -> &'static str;
+
+    /// Whether every method returns the code of its VM command, which the source map, the debug
+    /// information and `--comments vm` need. A backend which generates all its code in
+    /// `finish` can't tell where the code of a command is.
+    fn writes_per_command(&self) -> bool {
+        true
+    }
+
+    /// Start and end of a comment that takes a whole line, e.g. `("/*", "*/")`.
+    fn comment_delimiters(&self) -> (&'static str, &'static str);
+
+    fn set_source_file(&mut self, source_file: &str);
+
+    /// Code that runs before everything else: set SP=256 and call `Sys.init`.
+    fn bootstrap(&mut self) -> String;
+
+    fn push(&mut self, segment: &str, index: i32) -> String;
+
+    fn pop(&mut self, segment: &str, index: i32) -> String;
+
+    /// `id` is unique per VM command, it can be used to generate unique labels.
+    fn arithmetic(&mut self, cmd: &str, id: &str) -> String;
+
+    fn label(&mut self, label: &str) -> String;
+
+    fn goto(&mut self, label: &str) -> String;
+
+    fn if_goto(&mut self, label: &str) -> String;
+
+    fn function(&mut self, function_name: &str, n_vars: u32) -> String;
+
+    fn call(&mut self, function_name: &str, n_args: u32) -> String;
+
+    fn ret(&mut self) -> String;
+
+    /// Code that ends the program.
+    fn finish(&mut self) -> String;
+}
+
+/// Names of the backends `new_backend` knows.
+pub const BACKENDS: [&str; 4] = ["hack", "x86-64", "c", "wat"];
+
+pub fn new_backend(name: &str) -> Option<Box<dyn Backend>> {
+    match name {
+        "hack" => Some(Box::new(HackBackend::new())),
+        "x86-64" => Some(Box::new(X86Backend::new())),
+        "c" => Some(Box::new(CBackend::new())),
+        "wat" => Some(Box::new(WatBackend::new())),
+        _ => None,
+    }
+}
+
+/// Symbol of a VM label inside a function, `{function}${label}`.
+pub fn label_symbol(function: &str, label: &str) -> String {
+    format!("{function}${label}")
+}
+
+/// Symbol of the n-th return address inside a function, `{function}$ret.{n}`.
+pub fn return_symbol(function: &str, n: u32) -> String {
+    format!("{function}$ret.{n}")
+}
+
+/// Symbol of a static variable, `{file}.{index}`.
+pub fn static_symbol(source_file: &str, index: i32) -> String {
+    format!("{source_file}.{index}")
+}
+
+/// Allocates static variables in RAM from address 16 in order of first use, like the Hack
+/// assembler does, so every backend has the same RAM layout.
+#[derive(Default)]
+pub struct StaticAllocator {
+    addresses: HashMap<String, u32>,
+}
+
+impl StaticAllocator {
+    const STATIC_BASE: u32 = 16;
+
+    pub fn address(&mut self, source_file: &str, index: i32) -> u32 {
+        let next = Self::STATIC_BASE + self.addresses.len() as u32;
+        *self
+            .addresses
+            .entry(static_symbol(source_file, index))
+            .or_insert(next)
+    }
+
+    /// Address of a static variable already allocated.
+    pub fn get(&self, source_file: &str, index: i32) -> Option<u32> {
+        self.addresses
+            .get(&static_symbol(source_file, index))
+            .copied()
+    }
+}
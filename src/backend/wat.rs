@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use super::{label_symbol, return_symbol, Backend, StaticAllocator};
+
+// word n of Hack RAM is at byte 2*n of the linear memory, 2 pages hold every 16 bits address
+const PRELUDE: &str = "\
+(module
+  ;; Hack RAM, word n is at byte 2*n: SP, LCL, ARG, THIS, THAT at 0..4, temp at 5..12,
+  ;; statics from 16, stack from 256, screen at 16384, keyboard at 24576
+  (memory (export \"memory\") 2)
+
+  (func $get (param $address i32) (result i32)
+    (i32.load16_u (i32.shl (i32.and (local.get $address) (i32.const 0xffff)) (i32.const 1))))
+
+  (func $set (param $address i32) (param $value i32)
+    (i32.store16
+      (i32.shl (i32.and (local.get $address) (i32.const 0xffff)) (i32.const 1))
+      (local.get $value)))
+
+  (func $push (param $value i32)
+    (call $set (call $get (i32.const 0)) (local.get $value))
+    (call $set (i32.const 0) (i32.add (call $get (i32.const 0)) (i32.const 1))))
+
+  (func $pop (result i32)
+    (call $set (i32.const 0) (i32.sub (call $get (i32.const 0)) (i32.const 1)))
+    (call $get (call $get (i32.const 0))))
+
+  ;; sign extend a 16 bits word
+  (func $signed (param $value i32) (result i32)
+    (i32.shr_s (i32.shl (local.get $value) (i32.const 16)) (i32.const 16)))
+
+";
+
+const ENTRY_BLOCK: &str = "Bootstrap";
+
+/// A straight-line piece of code, control flow can only enter it from the top.
+struct Block {
+    name: String,
+    code: String,
+}
+
+/// Translates VM commands to a WebAssembly text module.
+///
+/// WebAssembly has no `goto`, so the program is split into blocks at every function,
+/// label and return address. `run` loops over a `br_table` that dispatches to the block
+/// whose number is in `$pc`, a jump sets `$pc` and branches back to the dispatch loop.
+/// Return addresses pushed by `call` are block numbers. The module is only known at the
+/// end of the program, every method buffers its code and `finish` returns the whole module.
+pub struct WatBackend {
+    source_filename: Option<String>,
+    current_function: String,
+    current_function_call_count: u32,
+    blocks: Vec<Block>,
+    block_ids: HashMap<String, usize>,
+    statics: StaticAllocator,
+}
+
+impl WatBackend {
+    pub fn new() -> Self {
+        let mut backend = Self {
+            source_filename: None,
+            current_function: ENTRY_BLOCK.to_string(),
+            current_function_call_count: 0,
+            blocks: vec![],
+            block_ids: HashMap::new(),
+            statics: StaticAllocator::default(),
+        };
+        // `run` starts with $pc=0
+        backend.begin_block(ENTRY_BLOCK);
+        backend
+    }
+
+    fn block_id(&mut self, name: &str) -> usize {
+        let next = self.block_ids.len();
+        let id = *self.block_ids.entry(name.to_string()).or_insert(next);
+        assert!(id < 1 << 16, "too many blocks for 16 bits return addresses");
+        id
+    }
+
+    fn begin_block(&mut self, name: &str) {
+        self.block_id(name);
+        self.blocks.push(Block {
+            name: name.to_string(),
+            code: String::new(),
+        });
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.blocks.last_mut().unwrap().code += code;
+    }
+
+    fn jump(&mut self, name: &str) -> String {
+        format!(
+            "i32.const {}\nlocal.set $pc\nbr $dispatch\n",
+            self.block_id(name)
+        )
+    }
+
+    /// Pushes the RAM address of a segment entry.
+    fn segment_address(&mut self, segment: &str, index: i32) -> String {
+        let pointer =
+            |address| format!("i32.const {address}\ncall $get\ni32.const {index}\ni32.add\n");
+        match segment {
+            "local" => pointer(1),
+            "argument" => pointer(2),
+            "this" => pointer(3),
+            "that" => pointer(4),
+            "temp" => format!("i32.const {}\n", 5 + index),
+            "pointer" => format!("i32.const {}\n", 3 + index),
+            "static" => {
+                assert!(self.source_filename.is_some());
+                let source_file = self.source_filename.as_ref().unwrap();
+                format!("i32.const {}\n", self.statics.address(source_file, index))
+            }
+            _ => panic!("push/pop segment syntax error: unknow segment `{segment}`"),
+        }
+    }
+
+    fn _gen_label(&self, label: &str) -> String {
+        label_symbol(&self.current_function, label)
+    }
+
+    fn _write_push(&mut self, segment: &str, index: i32) -> String {
+        let value = match segment {
+            "constant" => format!("i32.const {index}\n"),
+            _ => self.segment_address(segment, index) + "call $get\n",
+        };
+        format!(";; push {segment} {index}\n") + &value + "call $push\n"
+    }
+
+    fn _write_pop(&mut self, segment: &str, index: i32) -> String {
+        String::new()
+            + &format!(";; pop {segment} {index}\n")
+            + "call $pop\n"
+            + "local.set $d\n"
+            + &self.segment_address(segment, index)
+            + "local.get $d\n"
+            + "call $set\n"
+    }
+
+    fn _write_arithmetic(&mut self, cmd: &str) -> String {
+        let binary = |op: &str| format!("call $pop\nlocal.set $d\ncall $pop\nlocal.get $d\n{op}\n");
+        let compare = |op: &str| {
+            // 0 - (x op y) is -1 for true and 0 for false
+            format!(
+                "call $pop\ncall $signed\nlocal.set $d\ni32.const 0\n\
+                 call $pop\ncall $signed\nlocal.get $d\n{op}\ni32.sub\n"
+            )
+        };
+        let code = match cmd {
+            "add" => binary("i32.add"),
+            "sub" => binary("i32.sub"),
+            "and" => binary("i32.and"),
+            "or" => binary("i32.or"),
+            "neg" => "i32.const 0\ncall $pop\ni32.sub\n".to_string(),
+            "not" => "call $pop\ni32.const -1\ni32.xor\n".to_string(),
+            "eq" => compare("i32.eq"),
+            "gt" => compare("i32.gt_s"),
+            "lt" => compare("i32.lt_s"),
+            cmd => panic!("arithmetic command syntax error: unknow command `{cmd}`"),
+        };
+        format!(";; {cmd}\n") + &code + "call $push\n"
+    }
+
+    fn _write_call(&mut self, function_name: &str, n_args: u32) -> String {
+        let return_address =
+            return_symbol(&self.current_function, self.current_function_call_count);
+        self.current_function_call_count += 1;
+
+        let mut s = format!(";; call {function_name} {n_args}\n");
+        s += &format!("i32.const {}\ncall $push\n", self.block_id(&return_address));
+        // push LCL, ARG, THIS, THAT
+        for address in 1..=4 {
+            s += &format!("i32.const {address}\ncall $get\ncall $push\n");
+        }
+        s += ";; ARG=SP-5-n_args\n";
+        s += &format!(
+            "i32.const 2\ni32.const 0\ncall $get\ni32.const {}\ni32.sub\ncall $set\n",
+            5 + n_args
+        );
+        s += ";; LCL=SP\n";
+        s += "i32.const 1\ni32.const 0\ncall $get\ncall $set\n";
+        s += &self.jump(function_name);
+        self.emit(&s);
+        self.begin_block(&return_address);
+        String::new()
+    }
+
+    fn _write_return(&mut self) -> String {
+        let mut s = String::new()
+            + ";; return\n"
+            + ";; frame(R13)=LCL\n"
+            + "i32.const 13\ni32.const 1\ncall $get\ncall $set\n"
+            + ";; return_address(R14)=*(frame-5)\n"
+            + "i32.const 14\ni32.const 13\ncall $get\ni32.const 5\ni32.sub\ncall $get\ncall $set\n"
+            + ";; *ARG=pop()\n"
+            + "i32.const 2\ncall $get\ncall $pop\ncall $set\n"
+            + ";; SP=ARG+1\n"
+            + "i32.const 0\ni32.const 2\ncall $get\ni32.const 1\ni32.add\ncall $set\n";
+        // THAT, THIS, ARG, LCL = *(frame-1), *(frame-2), *(frame-3), *(frame-4)
+        for (pointer, offset) in [(4, 1), (3, 2), (2, 3), (1, 4)] {
+            s += &format!(
+                "i32.const {pointer}\ni32.const 13\ncall $get\ni32.const {offset}\ni32.sub\ncall $get\ncall $set\n"
+            );
+        }
+        s + ";; goto return_address\n"
+            + "i32.const 14\ncall $get\n"
+            + "local.set $pc\n"
+            + "br $dispatch\n"
+    }
+
+    fn _write_close(&mut self) -> String {
+        // a call to a function that is not defined traps, except Sys.halt which stops the program
+        if self.block_ids.contains_key("Sys.halt")
+            && !self.blocks.iter().any(|b| b.name == "Sys.halt")
+        {
+            self.begin_block("Sys.halt");
+            self.emit("i32.const 0\nreturn\n");
+        }
+        let defined = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.name.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let mut targets = vec!["$undefined".to_string(); self.block_ids.len()];
+        for (name, id) in &self.block_ids {
+            if let Some(i) = defined.get(name) {
+                targets[*id] = format!("$b{i}");
+            }
+        }
+
+        let mut body = String::new();
+        body += "loop $dispatch\n";
+        body += "block $undefined\n";
+        for i in (0..self.blocks.len()).rev() {
+            body += &format!("block $b{i}\n");
+        }
+        body += "local.get $pc\n";
+        body += &format!("br_table {} $undefined\n", targets.join(" "));
+        for (i, block) in self.blocks.iter().enumerate() {
+            body += "end\n";
+            body += &format!(";; block {i}: {}\n", block.name);
+            body += &block.code;
+        }
+        body += "end\n";
+        body += "unreachable\n";
+        body += "end\n";
+
+        let mut s = String::new() + PRELUDE;
+        s += "  (func $run (export \"run\") (result i32)\n";
+        s += "    (local $pc i32)\n";
+        s += "    (local $d i32)\n";
+        for line in body.lines() {
+            s += &format!("    {line}\n");
+        }
+        s += "    unreachable)\n";
+        s += ")\n";
+        s
+    }
+}
+
+impl Default for WatBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for WatBackend {
+    fn extension(&self) -> &'static str {
+        "wat"
+    }
+
+    /// The module is built in `finish`, from the blocks of every function.
+    fn writes_per_command(&self) -> bool {
+        false
+    }
+
+    fn comment_delimiters(&self) -> (&'static str, &'static str) {
+        (";;", "")
+    }
+
+    fn set_source_file(&mut self, source_file: &str) {
+        self.source_filename = Some(source_file.to_string());
+    }
+
+    fn bootstrap(&mut self) -> String {
+        self.emit(";; SP=256\ni32.const 0\ni32.const 256\ncall $set\n");
+        self._write_call("Sys.init", 0);
+        // `run` returns the value Sys.init returns
+        self.emit(
+            "i32.const 0\ncall $get\ni32.const 1\ni32.sub\ncall $get\ncall $signed\nreturn\n",
+        );
+        String::new()
+    }
+
+    fn push(&mut self, segment: &str, index: i32) -> String {
+        let s = self._write_push(segment, index);
+        self.emit(&s);
+        String::new()
+    }
+
+    fn pop(&mut self, segment: &str, index: i32) -> String {
+        let s = self._write_pop(segment, index);
+        self.emit(&s);
+        String::new()
+    }
+
+    fn arithmetic(&mut self, cmd: &str, _id: &str) -> String {
+        let s = self._write_arithmetic(cmd);
+        self.emit(&s);
+        String::new()
+    }
+
+    fn label(&mut self, label: &str) -> String {
+        // the current block falls through into the new one
+        let name = self._gen_label(label);
+        self.begin_block(&name);
+        String::new()
+    }
+
+    fn goto(&mut self, label: &str) -> String {
+        let name = self._gen_label(label);
+        let s = self.jump(&name);
+        self.emit(&s);
+        String::new()
+    }
+
+    fn if_goto(&mut self, label: &str) -> String {
+        let name = self._gen_label(label);
+        let s = String::new() + "call $pop\n" + "if\n" + &self.jump(&name) + "end\n";
+        self.emit(&s);
+        String::new()
+    }
+
+    fn function(&mut self, function_name: &str, n_vars: u32) -> String {
+        self.begin_block(function_name);
+        self.current_function = function_name.to_string();
+        self.current_function_call_count = 0;
+        for _ in 0..n_vars {
+            let s = self._write_push("constant", 0);
+            self.emit(&s);
+        }
+        String::new()
+    }
+
+    fn call(&mut self, function_name: &str, n_args: u32) -> String {
+        self._write_call(function_name, n_args)
+    }
+
+    fn ret(&mut self) -> String {
+        let s = self._write_return();
+        self.emit(&s);
+        String::new()
+    }
+
+    fn finish(&mut self) -> String {
+        self._write_close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate() -> String {
+        let mut backend = WatBackend::new();
+        backend.bootstrap();
+        backend.set_source_file("Main.vm");
+        backend.function("Sys.init", 0);
+        backend.push("constant", 3);
+        backend.call("Main.f", 1);
+        backend.call("Sys.halt", 0);
+        backend.function("Main.f", 1);
+        backend.label("LOOP");
+        backend.push("argument", 0);
+        backend.if_goto("LOOP");
+        backend.push("static", 0);
+        backend.arithmetic("lt", "0");
+        backend.ret();
+        backend.finish()
+    }
+
+    #[test]
+    fn test_module_exports() {
+        let s = translate();
+        assert!(s.starts_with("(module\n"));
+        assert!(s.contains("(memory (export \"memory\") 2)"));
+        assert!(s.contains("(func $run (export \"run\") (result i32)"));
+    }
+
+    #[test]
+    fn test_blocks_are_balanced() {
+        let s = translate();
+        let opened = s
+            .lines()
+            .filter(|line| {
+                matches!(
+                    line.split_whitespace().next(),
+                    Some("block" | "loop" | "if")
+                )
+            })
+            .count();
+        let closed = s.lines().filter(|line| line.trim() == "end").count();
+        assert_eq!(opened, closed);
+        assert_eq!(s.matches('(').count(), s.matches(')').count());
+    }
+
+    #[test]
+    fn test_dispatch_table() {
+        let s = translate();
+        // Bootstrap, Bootstrap$ret.0, Sys.init, Sys.init$ret.0, Sys.init$ret.1, Main.f,
+        // Main.f$LOOP and the Sys.halt stub
+        assert!(s.contains("br_table $b0 $b1 $b2 $b3 $b5 $b4 $b7 $b6 $undefined\n"));
+        assert!(s.contains(";; block 7: Sys.halt\n    i32.const 0\n    return\n"));
+    }
+
+    #[test]
+    fn test_segments_follow_hack_ram() {
+        let s = translate();
+        // argument 0 is RAM[ARG]+0, static 0 is the first static at RAM[16]
+        assert!(s.contains(";; push argument 0\n    i32.const 2\n    call $get\n    i32.const 0\n"));
+        assert!(s.contains(";; push static 0\n    i32.const 16\n    call $get\n"));
+    }
+}
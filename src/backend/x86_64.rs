@@ -0,0 +1,494 @@
+use std::collections::BTreeSet;
+
+use super::{label_symbol, return_symbol, Backend, StaticAllocator};
+
+// RAM layout is the same as Hack: SP, LCL, ARG, THIS, THAT in RAM[0..5], temp in RAM[5..13],
+// statics from RAM[16], stack from RAM[256], screen from RAM[16384], keyboard at RAM[24576].
+const RAM_WORDS: u32 = 65536; // every 16 bits address is valid, no bounds check needed
+const TEMP_BASE: i32 = 5;
+
+// %rbx always hold the base address of `ram`, word n is at n*2(%rbx)
+const RUNTIME_MACROS: &str = "\
+    .macro PUSH_AX
+    movzwl (%rbx), %ecx
+    movw %ax, (%rbx,%rcx,2)
+    incw (%rbx)
+    .endm
+
+    .macro POP_AX
+    decw (%rbx)
+    movzwl (%rbx), %ecx
+    movw (%rbx,%rcx,2), %ax
+    .endm
+
+    # x = x op y, y is the stack top
+    .macro BINARY op
+    decw (%rbx)
+    movzwl (%rbx), %ecx
+    movw (%rbx,%rcx,2), %dx
+    \\op %dx, -2(%rbx,%rcx,2)
+    .endm
+
+    .macro UNARY op
+    movzwl (%rbx), %ecx
+    \\op -2(%rbx,%rcx,2)
+    .endm
+
+    # x = (x cc y) ? -1 : 0, signed compare without overflow
+    .macro COMPARE cc
+    decw (%rbx)
+    movzwl (%rbx), %ecx
+    movw (%rbx,%rcx,2), %dx
+    cmpw %dx, -2(%rbx,%rcx,2)
+    set\\cc %al
+    movzbw %al, %ax
+    negw %ax
+    movw %ax, -2(%rbx,%rcx,2)
+    .endm
+
+    # eax = address of segment `base` + index
+    .macro SEGMENT_ADDR base, index
+    movzwl \\base(%rbx), %eax
+    addl $\\index, %eax
+    andl $0xffff, %eax
+    .endm
+
+";
+
+/// Translates VM commands to x86-64 GNU assembler for Linux.
+///
+/// The generated program has no dependencies, it can be built with
+/// `cc -nostdlib -static -o Main Main.s` and exits with the low byte of the value
+/// `Sys.init` returns, or 0 when `Sys.halt` is called and not defined by the program.
+pub struct X86Backend {
+    source_filename: Option<String>,
+    current_function: String,
+    current_function_call_count: u32,
+    // return addresses are pushed as an index of `ret_table`, they must fit in a 16 bits word
+    return_labels: Vec<String>,
+    statics: StaticAllocator,
+    defined_functions: BTreeSet<String>,
+    called_functions: BTreeSet<String>,
+}
+
+impl X86Backend {
+    pub fn new() -> Self {
+        Self {
+            source_filename: None,
+            current_function: "Bootstrap".to_string(),
+            current_function_call_count: 0,
+            return_labels: vec![],
+            statics: StaticAllocator::default(),
+            defined_functions: BTreeSet::new(),
+            called_functions: BTreeSet::new(),
+        }
+    }
+
+    /// Segment pointer address of local/argument/this/that.
+    fn segment_pointer(segment: &str) -> Option<i32> {
+        match segment {
+            "local" => Some(1),
+            "argument" => Some(2),
+            "this" => Some(3),
+            "that" => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Byte offset of a fixed RAM address from %rbx.
+    fn fixed_address(&mut self, segment: &str, index: i32) -> i32 {
+        match segment {
+            "temp" => (TEMP_BASE + index) * 2,
+            "pointer" => (3 + index) * 2,
+            "static" => {
+                assert!(self.source_filename.is_some());
+                let source_file = self.source_filename.as_ref().unwrap();
+                self.statics.address(source_file, index) as i32 * 2
+            }
+            _ => panic!("push/pop segment syntax error: unknow segment `{segment}`"),
+        }
+    }
+
+    fn _gen_label(&self, label: &str) -> String {
+        symbol(&label_symbol(&self.current_function, label))
+    }
+
+    fn _write_bootstrap(&mut self) -> String {
+        String::new()
+            + RUNTIME_MACROS
+            + "    .text\n"
+            + "    .globl _start\n"
+            + "_start:\n"
+            + "    leaq ram(%rip), %rbx\n"
+            + "    # SP=256\n"
+            + "    movw $256, (%rbx)\n"
+            + &self._write_call("Sys.init", 0)
+            + "    # exit(low byte of the value Sys.init returns)\n"
+            + "    movzwl (%rbx), %ecx\n"
+            + "    movzwl -2(%rbx,%rcx,2), %edi\n"
+            + "    andl $255, %edi\n"
+            + "    jmp vm_exit\n"
+    }
+
+    fn _write_push(&mut self, segment: &str, index: i32) -> String {
+        let load = match segment {
+            "constant" => format!("    movw ${index}, %ax\n"),
+            _ => match Self::segment_pointer(segment) {
+                Some(pointer) => {
+                    format!("    SEGMENT_ADDR {}, {index}\n", pointer * 2)
+                        + "    movw (%rbx,%rax,2), %ax\n"
+                }
+                None => format!(
+                    "    movw {}(%rbx), %ax\n",
+                    self.fixed_address(segment, index)
+                ),
+            },
+        };
+        format!("    # push {segment} {index}\n") + &load + "    PUSH_AX\n"
+    }
+
+    fn _write_pop(&mut self, segment: &str, index: i32) -> String {
+        let store = match Self::segment_pointer(segment) {
+            Some(pointer) => {
+                format!("    SEGMENT_ADDR {}, {index}\n", pointer * 2)
+                    + "    movw %dx, (%rbx,%rax,2)\n"
+            }
+            None => format!(
+                "    movw %dx, {}(%rbx)\n",
+                self.fixed_address(segment, index)
+            ),
+        };
+        String::new()
+            + &format!("    # pop {segment} {index}\n")
+            + "    POP_AX\n"
+            + "    movw %ax, %dx\n"
+            + &store
+    }
+
+    fn _write_arithmetic(&mut self, cmd: &str) -> String {
+        let code = match cmd {
+            "add" => "    BINARY addw\n",
+            "sub" => "    BINARY subw\n",
+            "and" => "    BINARY andw\n",
+            "or" => "    BINARY orw\n",
+            "neg" => "    UNARY negw\n",
+            "not" => "    UNARY notw\n",
+            "eq" => "    COMPARE e\n",
+            "gt" => "    COMPARE g\n",
+            "lt" => "    COMPARE l\n",
+            cmd => panic!("arithmetic command syntax error: unknow command `{cmd}`"),
+        };
+        format!("    # {cmd}\n") + code
+    }
+
+    fn _write_function(&mut self, function_name: &str, n_vars: u32) -> String {
+        let mut s = format!("{}:\n", symbol(function_name));
+        for _ in 0..n_vars {
+            s += &self._write_push("constant", 0);
+        }
+        self.current_function = function_name.to_string();
+        self.current_function_call_count = 0;
+        self.defined_functions.insert(function_name.to_string());
+        s
+    }
+
+    fn _write_call(&mut self, function_name: &str, n_args: u32) -> String {
+        let return_id = self.return_labels.len();
+        assert!(
+            return_id < 1 << 16,
+            "too many call sites for 16 bits return addresses"
+        );
+        let return_label = symbol(&return_symbol(
+            &self.current_function,
+            self.current_function_call_count,
+        ));
+        self.return_labels.push(return_label.clone());
+        self.current_function_call_count += 1;
+        self.called_functions.insert(function_name.to_string());
+
+        let mut s = format!("    # call {function_name} {n_args}\n");
+        s += &format!("    movw ${return_id}, %ax\n");
+        s += "    PUSH_AX\n";
+        // push LCL, ARG, THIS, THAT
+        for address in 1..=4 {
+            s += &format!("    movw {}(%rbx), %ax\n", address * 2);
+            s += "    PUSH_AX\n";
+        }
+        s + "    # ARG=SP-5-n_args\n"
+            + "    movw (%rbx), %ax\n"
+            + &format!("    subw ${}, %ax\n", 5 + n_args)
+            + "    movw %ax, 4(%rbx)\n"
+            + "    # LCL=SP\n"
+            + "    movw (%rbx), %ax\n"
+            + "    movw %ax, 2(%rbx)\n"
+            + &format!("    jmp {}\n", symbol(function_name))
+            + &format!("{return_label}:\n")
+    }
+
+    fn _write_return(&mut self) -> String {
+        String::new()
+            + "    # return\n"
+            + "    # frame(R13)=LCL\n"
+            + "    movw 2(%rbx), %ax\n"
+            + "    movw %ax, 26(%rbx)\n"
+            + "    # return_address(R14)=*(frame-5)\n"
+            + "    SEGMENT_ADDR 26, -5\n"
+            + "    movw (%rbx,%rax,2), %ax\n"
+            + "    movw %ax, 28(%rbx)\n"
+            + "    # *ARG=pop()\n"
+            + "    POP_AX\n"
+            + "    movzwl 4(%rbx), %ecx\n"
+            + "    movw %ax, (%rbx,%rcx,2)\n"
+            + "    # SP=ARG+1\n"
+            + "    incl %ecx\n"
+            + "    movw %cx, (%rbx)\n"
+            + "    # THAT, THIS, ARG, LCL = *(frame-1), *(frame-2), *(frame-3), *(frame-4)\n"
+            + "    SEGMENT_ADDR 26, -1\n"
+            + "    movw (%rbx,%rax,2), %dx\n"
+            + "    movw %dx, 8(%rbx)\n"
+            + "    movw -2(%rbx,%rax,2), %dx\n"
+            + "    movw %dx, 6(%rbx)\n"
+            + "    movw -4(%rbx,%rax,2), %dx\n"
+            + "    movw %dx, 4(%rbx)\n"
+            + "    movw -6(%rbx,%rax,2), %dx\n"
+            + "    movw %dx, 2(%rbx)\n"
+            + "    # goto return_address\n"
+            + "    movzwl 28(%rbx), %eax\n"
+            + "    leaq ret_table(%rip), %rdx\n"
+            + "    jmp *(%rdx,%rax,8)\n"
+    }
+
+    fn _write_close(&mut self) -> String {
+        let mut s = String::new();
+        if self.called_functions.contains("Sys.halt")
+            && !self.defined_functions.contains("Sys.halt")
+        {
+            s += "    # Sys.halt is not defined by the program, stop it\n";
+            s += &format!("{}:\n", symbol("Sys.halt"));
+            s += "    xorl %edi, %edi\n";
+        }
+        s += "vm_exit:\n";
+        s += "    movl $60, %eax\n";
+        s += "    syscall\n";
+        s += "\n    .section .rodata\n";
+        s += "    .align 8\n";
+        s += "ret_table:\n";
+        for label in &self.return_labels {
+            s += &format!("    .quad {label}\n");
+        }
+        s += "\n    .bss\n";
+        s += "    .align 2\n";
+        s += &format!("ram:\n    .zero {}\n", RAM_WORDS * 2);
+        s
+    }
+}
+
+impl Default for X86Backend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// VM names are prefixed so they can't clash with the runtime symbols
+/// (`_start`, `vm_exit`, `ret_table`, `ram`), and may contain `:`, which GNU
+/// as does not accept in a symbol.
+fn symbol(name: &str) -> String {
+    format!("vm.{}", name.replace(':', "$$"))
+}
+
+impl Backend for X86Backend {
+    fn extension(&self) -> &'static str {
+        "s"
+    }
+
+    fn comment_delimiters(&self) -> (&'static str, &'static str) {
+        ("#", "")
+    }
+
+    fn set_source_file(&mut self, source_file: &str) {
+        self.source_filename = Some(source_file.to_string());
+    }
+
+    fn bootstrap(&mut self) -> String {
+        self._write_bootstrap()
+    }
+
+    fn push(&mut self, segment: &str, index: i32) -> String {
+        self._write_push(segment, index)
+    }
+
+    fn pop(&mut self, segment: &str, index: i32) -> String {
+        self._write_pop(segment, index)
+    }
+
+    fn arithmetic(&mut self, cmd: &str, _id: &str) -> String {
+        self._write_arithmetic(cmd)
+    }
+
+    fn label(&mut self, label: &str) -> String {
+        format!("{}:\n", self._gen_label(label))
+    }
+
+    fn goto(&mut self, label: &str) -> String {
+        format!("    jmp {}\n", self._gen_label(label))
+    }
+
+    fn if_goto(&mut self, label: &str) -> String {
+        String::new()
+            + "    POP_AX\n"
+            + "    testw %ax, %ax\n"
+            + &format!("    jnz {}\n", self._gen_label(label))
+    }
+
+    fn function(&mut self, function_name: &str, n_vars: u32) -> String {
+        self._write_function(function_name, n_vars)
+    }
+
+    fn call(&mut self, function_name: &str, n_args: u32) -> String {
+        self._write_call(function_name, n_args)
+    }
+
+    fn ret(&mut self) -> String {
+        self._write_return()
+    }
+
+    fn finish(&mut self) -> String {
+        self._write_close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command};
+
+    use super::*;
+
+    fn translate(program: &[(&str, &str)]) -> String {
+        let mut backend = X86Backend::new();
+        let mut s = backend.bootstrap();
+        for (i, line) in program.iter().enumerate() {
+            let (cmd, args) = line;
+            let args = args.split(' ').collect::<Vec<_>>();
+            s += &match *cmd {
+                "file" => {
+                    backend.set_source_file(args[0]);
+                    String::new()
+                }
+                "push" => backend.push(args[0], args[1].parse().unwrap()),
+                "pop" => backend.pop(args[0], args[1].parse().unwrap()),
+                "function" => backend.function(args[0], args[1].parse().unwrap()),
+                "call" => backend.call(args[0], args[1].parse().unwrap()),
+                "label" => backend.label(args[0]),
+                "goto" => backend.goto(args[0]),
+                "if-goto" => backend.if_goto(args[0]),
+                "return" => backend.ret(),
+                cmd => backend.arithmetic(cmd, &i.to_string()),
+            };
+        }
+        s + &backend.finish()
+    }
+
+    #[test]
+    fn test_statics_are_allocated_from_16() {
+        let s = translate(&[
+            ("file", "Main.vm"),
+            ("function", "Sys.init 0"),
+            ("push", "static 3"),
+            ("pop", "static 0"),
+            ("push", "static 3"),
+        ]);
+        assert_eq!(s.matches("movw 32(%rbx), %ax").count(), 2);
+        assert!(s.contains("movw %dx, 34(%rbx)"));
+    }
+
+    #[test]
+    fn test_return_addresses_are_table_indexes() {
+        let s = translate(&[
+            ("file", "Main.vm"),
+            ("function", "Sys.init 0"),
+            ("call", "Main.f 0"),
+            ("return", ""),
+            ("function", "Main.f 0"),
+            ("push", "constant 1"),
+            ("return", ""),
+        ]);
+        assert!(
+            s.contains("ret_table:\n    .quad vm.Bootstrap$ret.0\n    .quad vm.Sys.init$ret.0\n")
+        );
+        assert!(s.contains("    movw $1, %ax\n    PUSH_AX\n"));
+    }
+
+    #[test]
+    fn test_symbols_dont_clash_with_runtime() {
+        let s = translate(&[
+            ("file", "Main.vm"),
+            ("function", "Sys.init 0"),
+            ("call", "ret_table 0"),
+            ("return", ""),
+            ("function", "ret_table 0"),
+            ("label", "vm_exit"),
+            ("goto", "vm_exit"),
+        ]);
+        assert_eq!(s.matches("\nret_table:\n").count(), 1);
+        assert_eq!(s.matches("\nvm_exit:\n").count(), 1);
+        assert!(s.contains("\nvm.ret_table:\n"));
+        assert!(s.contains("    jmp vm.ret_table\n"));
+        assert!(s.contains("\nvm.ret_table$vm_exit:\n"));
+    }
+
+    #[test]
+    #[ignore = "needs a C toolchain, run with `cargo test -- --ignored`"]
+    fn test_run_native() -> std::io::Result<()> {
+        let s = translate(&[
+            ("file", "Main.vm"),
+            ("function", "Sys.init 0"),
+            ("push", "constant 10"),
+            ("call", "Main.fibonacci 1"),
+            ("push", "constant 3"),
+            ("push", "constant 5"),
+            ("lt", ""),
+            ("if-goto", "LESS"),
+            ("push", "constant 100"),
+            ("add", ""),
+            ("label", "LESS"),
+            ("return", ""),
+            ("function", "Main.fibonacci 0"),
+            ("push", "argument 0"),
+            ("push", "constant 2"),
+            ("lt", ""),
+            ("if-goto", "N_LT_2"),
+            ("push", "argument 0"),
+            ("push", "constant 2"),
+            ("sub", ""),
+            ("call", "Main.fibonacci 1"),
+            ("push", "argument 0"),
+            ("push", "constant 1"),
+            ("sub", ""),
+            ("call", "Main.fibonacci 1"),
+            ("add", ""),
+            ("return", ""),
+            ("label", "N_LT_2"),
+            ("push", "argument 0"),
+            ("return", ""),
+        ]);
+        let dir = env::temp_dir().join(format!("vmtranslator_x86_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let source = dir.join("Main.s");
+        let binary = dir.join("Main");
+        fs::write(&source, s)?;
+        let built = Command::new("cc")
+            .args(["-nostdlib", "-static", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status();
+        if !matches!(built, Ok(status) if status.success()) {
+            fs::remove_dir_all(&dir)?;
+            panic!("`cc` can't build the program: {built:?}");
+        }
+        let status = Command::new(&binary).status()?;
+        fs::remove_dir_all(&dir)?;
+        // fib(10) == 55
+        assert_eq!(status.code(), Some(55));
+        Ok(())
+    }
+}
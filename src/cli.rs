@@ -0,0 +1,244 @@
+use std::{error::Error, fmt, io, process::ExitCode};
+
+/// The run or the checks of the program failed: invalid VM code, a runtime error, a guard
+/// violation, a divergence, a failed test, `check` errors or unformatted files.
+pub const EXIT_FAILURE: u8 = 1;
+/// The command line is wrong: an unknown command or flag, a missing or invalid value.
+pub const EXIT_USAGE: u8 = 2;
+/// An input can't be read or an output can't be written.
+pub const EXIT_IO: u8 = 3;
+
+/// A command line the commands don't understand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+/// A usage error with `message`.
+pub fn usage(message: impl Into<String>) -> Box<dyn Error> {
+    Box::new(UsageError(message.into()))
+}
+
+/// The exit code of an error of a command.
+pub fn exit_code(e: &(dyn Error + 'static)) -> ExitCode {
+    ExitCode::from(if e.is::<UsageError>() {
+        EXIT_USAGE
+    } else if e.is::<io::Error>() {
+        EXIT_IO
+    } else {
+        EXIT_FAILURE
+    })
+}
+
+pub const VERSION: &str = concat!("vmtranslator ", env!("CARGO_PKG_VERSION"));
+
+pub const HELP: &str = "\
+vmtranslator, the compiler backend of the Jack language
+
+Usage: vmtranslator <command> [<options>] <input>
+       vmtranslator [<translate options>] <input>
+
+Commands:
+  translate  Translate a .vm file or folder to Hack assembly or another backend
+  check      Check .vm files for errors without translating them
+  fmt        Format .vm files in place
+  run        Run a VM program on the VM interpreter
+  emulate    Run a .vm, .asm or .hack program on the Hack CPU emulator
+  debug      Debug a VM program on the interpreter or the emulator
+  test       Run nand2tetris .tst test scripts
+  difftest   Compare the interpreter and the emulator running a VM program
+  fuzz       Difftest random VM programs
+  minimize   Reduce a VM program the translation diverges on
+
+Options:
+  -h, --help     Print the help, of a command with `vmtranslator <command> --help`
+  -V, --version  Print the version
+
+Exit codes:
+  0  success
+  1  the program or its checks failed
+  2  the command line is wrong
+  3  an input can't be read or an output can't be written
+";
+
+const TRANSLATE_HELP: &str = "\
+Translate a .vm file or folder
+
+Usage: vmtranslator translate [<options>] <input>
+
+`Main.vm` is translated to `Main.asm` next to it, a folder `Prog/` to `Prog/Prog.asm`.
+
+Options:
+  --emit <backend>       hack, x86-64, c, wat, ir-json or listing [default: hack]
+  -O <level>, -O<level>  0 translates every command, 1 leaves out the functions no call
+                         from Sys.init reaches [default: 0]
+  -o <file>              Write the output to <file>, `-` for stdout
+  --out-dir <dir>        Write the output to <dir>
+  --comments <comments>  none, vm or verbose [default: verbose]
+  --source-map           Write the source map next to the output, `<output>.map`
+  --debug-info           Write the debug information next to the output, `<output>.dbg.json`
+";
+
+const CHECK_HELP: &str = "\
+Check .vm files for errors without translating them
+
+Usage: vmtranslator check <input>...
+
+Prints every error as `<file>:<line>: <message>`, and fails when there is one.
+";
+
+const FMT_HELP: &str = "\
+Format .vm files in place
+
+Usage: vmtranslator fmt [--check] <input>...
+
+Commands get single spaces and no indentation, comments are kept.
+
+Options:
+  --check  Don't write the files, print the unformatted ones and fail when there is one
+";
+
+const RUN_HELP: &str = "\
+Run a VM program on the VM interpreter
+
+Usage: vmtranslator run [<options>] <input>
+
+Options:
+  --steps <n>                  Stop after <n> VM commands [default: 10000000]
+  --ram <range>                Print RAM words, like `256` or `256..260` [default: 0..16]
+  --trace                      Print every VM command executed and its effect
+  --trace-function <name>      Trace the function <name>, or the functions `Class.*`
+  --screen <file>              Write the screen to a .pbm or .png file at the end
+  --screen-at <steps>          Capture the screen after <steps> commands too
+  --show-screen <view>         Print the screen as blocks or ascii
+  --keyboard <script>          Press keys from a keyboard script
+  --guard                      Stop when the program leaves its memory
+  --stack-limit <address>      Highest SP of the guard [default: 2048]
+  --pointer-writes <range>     A region `pop this` and `pop that` may write to
+  --coverage <file>            Write the VM code coverage as an lcov report
+";
+
+const EMULATE_HELP: &str = "\
+Run a .vm, .asm or .hack program on the Hack CPU emulator
+
+Usage: vmtranslator emulate [<options>] <input>
+
+.vm inputs are translated first, only they can be traced, guarded and covered.
+
+Options:
+  --cycles <n>                 Stop after <n> instructions [default: 100000000]
+  --ram <range>                Print RAM words, like `256` or `256..260` [default: 0..16]
+  --trace                      Print every VM command executed and its effect
+  --trace-function <name>      Trace the function <name>, or the functions `Class.*`
+  --profile                    Print the cycles spent in every function and VM line
+  --profile-folded <file>      Write the profile as folded stacks for flame graphs
+  --screen <file>              Write the screen to a .pbm or .png file at the end
+  --screen-at <cycles>         Capture the screen after <cycles> instructions too
+  --show-screen <view>         Print the screen as blocks or ascii
+  --keyboard <script>          Press keys from a keyboard script
+  --guard                      Stop when the program leaves its memory
+  --stack-limit <address>      Highest SP of the guard [default: 2048]
+  --pointer-writes <range>     A region `pop this` and `pop that` may write to
+  --coverage <file>            Write the VM code coverage as an lcov report
+";
+
+const DEBUG_HELP: &str = "\
+Debug a VM program, with commands read from stdin
+
+Usage: vmtranslator debug [--hack] <input>
+
+Options:
+  --hack  Debug the Hack translation on the CPU emulator
+";
+
+const TEST_HELP: &str = "\
+Run nand2tetris .tst test scripts and compare their output with their .cmp files
+
+Usage: vmtranslator test [--vm] <script or folder>...
+
+Options:
+  --vm  Run the scripts loading .vm files on the VM interpreter, they are skipped otherwise
+";
+
+const DIFFTEST_HELP: &str = "\
+Run a VM program on the interpreter and its translation on the emulator, and report the
+first command after which they differ
+
+Usage: vmtranslator difftest [--steps <n>] <input>
+
+Options:
+  --steps <n>  Stop after <n> VM commands [default: 10000000]
+";
+
+const FUZZ_HELP: &str = "\
+Generate random VM programs and difftest them
+
+Usage: vmtranslator fuzz [<options>]
+
+Options:
+  --seed <n>   Seed of the first program [default: the clock]
+  --count <n>  Programs to generate [default: 100]
+  --steps <n>  Stop every program after <n> VM commands [default: 100000]
+  --out <dir>  Keep the diverging programs in <dir>/seed-<n> [default: fuzz]
+  --minimize   Minimize the diverging programs to <dir>/seed-<n>.min
+";
+
+const MINIMIZE_HELP: &str = "\
+Reduce a VM program the translation diverges on to a minimal one
+
+Usage: vmtranslator minimize [<options>] <input>
+
+Options:
+  --steps <n>  Stop every difftest after <n> VM commands [default: 10000000]
+  --out <dir>  Write the minimal program to <dir> [default: minimized]
+";
+
+/// The help of `command`.
+pub fn command_help(command: &str) -> Option<&'static str> {
+    Some(match command {
+        "translate" => TRANSLATE_HELP,
+        "check" => CHECK_HELP,
+        "fmt" => FMT_HELP,
+        "run" => RUN_HELP,
+        "emulate" => EMULATE_HELP,
+        "debug" => DEBUG_HELP,
+        "test" => TEST_HELP,
+        "difftest" => DIFFTEST_HELP,
+        "fuzz" => FUZZ_HELP,
+        "minimize" => MINIMIZE_HELP,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(
+            exit_code(&*usage("`--steps` need a step count")),
+            ExitCode::from(2)
+        );
+        let e: Box<dyn Error> = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(exit_code(&*e), ExitCode::from(3));
+        let e: Box<dyn Error> = "the translation diverged from the interpreter".into();
+        assert_eq!(exit_code(&*e), ExitCode::from(1));
+    }
+
+    #[test]
+    fn test_command_help() {
+        for line in HELP.lines().skip_while(|line| *line != "Commands:").skip(1) {
+            let Some(command) = line.split_whitespace().next() else {
+                break;
+            };
+            assert!(command_help(command).is_some(), "{command}");
+        }
+    }
+}
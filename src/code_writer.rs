@@ -1,685 +1,223 @@
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
     io::{self, Write},
     path::Path,
+    str::FromStr,
 };
 
-pub struct CodeWriter {
-    file: File,
-    target_filename: String,
-    source_filename: Option<String>,
-    current_function: Option<String>,
-    current_function_call_count: u32, // 每个函数内call的次数，用来分配不同的返回地址
-    mem_seg_map: HashMap<String, String>,
-}
-
-impl CodeWriter {
-    pub fn new(path: &Path) -> io::Result<Self> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(path)?;
-        let mem_seg_map = HashMap::from([
-            ("local".to_string(), "LCL".to_string()),
-            ("argument".to_string(), "ARG".to_string()),
-            ("this".to_string(), "THIS".to_string()),
-            ("that".to_string(), "THAT".to_string()),
-        ]);
-
-        let target_filename = path
-            .file_name()
-            .ok_or(io::Error::new(io::ErrorKind::NotFound, "no filename"))?
-            .to_str()
-            .unwrap()
-            .to_string();
-
-        // bootstrap code, call Sys.init
-        let buf = String::new() + "@256\n" + "D=A\n" + "@SP\n" + "M=D\n";
-
-        file.write_all(buf.as_bytes())?;
-        let mut _self = Self {
-            file,
-            target_filename,
-            source_filename: None,
-            current_function: Some("Bootstrap".to_string()),
-            current_function_call_count: 0,
-            mem_seg_map,
-        };
+use crate::{
+    atomic_file::AtomicFile,
+    backend::Backend,
+    debug_info::DebugInfo,
+    source_map::{SourceLocation, SourceMap, SourceMapEntry},
+};
 
-        _self.write_call("Sys.init", 0)?;
+/// How much commentary the generated code has.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Comments {
+    /// Bare instructions, no comments and blank lines.
+    None,
+    /// Only the VM command, with its file and line number, above its translation.
+    Vm,
+    /// Everything the backend annotates its code with.
+    Verbose,
+}
 
-        Ok(_self)
+impl FromStr for Comments {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Comments::None),
+            "vm" => Ok(Comments::Vm),
+            "verbose" => Ok(Comments::Verbose),
+            _ => Err(format!(
+                "unknow comments level `{s}`, expect one of: none, vm, verbose"
+            )),
+        }
     }
+}
 
-    pub fn set_source_file(&mut self, source_file: &str) {
-        self.source_filename = Some(source_file.to_string());
-    }
+pub struct CodeWriter<W: Write = AtomicFile> {
+    out: W,
+    backend: Box<dyn Backend>,
+    comments: Comments,
+    source_filename: Option<String>,
+    current_function: String,
+    source_location: Option<SourceLocation>, // VM command of the code being written
+    lines_written: usize,
+    source_map: SourceMap,
+    debug_info: DebugInfo,
+}
 
-    fn set_current_function(&mut self, current_function: &str) {
-        self.current_function = Some(current_function.to_string());
+impl CodeWriter {
+    /// Writes the code to `path`, which is only replaced by `commit`.
+    pub fn new(path: &Path, backend: Box<dyn Backend>, comments: Comments) -> io::Result<Self> {
+        let file = AtomicFile::create(path)?;
+        Self::from_writer(file, backend, comments)
     }
 
-    fn _write_arithmetic(&mut self, cmd: &str, id: &str) -> String {
-        const TEMP_BASE: i32 = 5;
-        match cmd {
-            "add" => {
-                String::new()
-                    + "// start ======= add\n"
-                    + &self._write_pop("temp", 2)
-                    + &self._write_pop("temp", 3)
-                    + "// start ======= temp0 = temp1 + temp0\n"
-                    + &format!("@{}\n", TEMP_BASE+2)
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{}\n", TEMP_BASE+2)
-                    + "M=D+M\n" // D: x, M: y
-                    + "// end ======= temp0 = temp1 + temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 2)
-                    + "// end ======= add\n"
-                    + "\n"
-            }
-            "sub" => {
-                String::new()
-                    + "// start ======= sub\n"
-                    + &self._write_pop("temp", 0)
-                    + &self._write_pop("temp", 1)
-                    + "// start ======= temp0 = temp1 - temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=D-M\n" // D: x, M: y
-                    + "// end ======= temp0 = temp1 - temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= sub\n"
-                    + "\n"
-            }
-            "neg" => {
-                String::new()
-                    + "// start ======= neg\n"
-                    + &self._write_pop("temp", 0)
-                    + "// start ======= temp0 = -temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=-M\n"
-                    + "// end ======= temp0 = -temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= neg\n"
-                    + "\n"
-            }
-            "eq" => {
-                String::new()
-                    + "// start ======= eq\n"
-                    + &self._write_pop("temp", 0)
-                    + &self._write_pop("temp", 1)
-                    + "// start ======= temp0 := temp1 == temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=D-M\n" // D: x, M: y
-                    + "D=M\n" // if D(x-y)==0, M=true(-1), else M=false(0)
-                    + "M=0\n" // bool=false(0)
-                    + &format!("@HIT_{}\n", id)
-                    + "D;JEQ\n"
-                    + &format!("@CONTINUE_{}\n", id)
-                    + "0;JMP\n"
-                    + &format!("(HIT_{})\n", id) // test hit, bool=true(-1)
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=-1\n"
-                    + &format!("(CONTINUE_{})\n", id)
-                    + "// end ======= temp0 := temp1 == temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= eq\n"
-                    + "\n"
-            }
-            "gt" => {
-                String::new()
-                    + "// start ======= gt\n"
-                    + &self._write_pop("temp", 0)
-                    + &self._write_pop("temp", 1)
-                    + "// start ======= temp0 := temp1 > temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=D-M\n" // D: x, M: y
-                    + "D=M\n" // if D(x-y) > 0, M=true(-1), else M=false(0)
-                    + "M=0\n" // bool=false(0)
-                    + &format!("@HIT_{}\n", id)
-                    + "D;JGT\n"
-                    + &format!("@CONTINUE_{}\n", id)
-                    + "0;JMP\n"
-                    + &format!("(HIT_{})\n", id) // test hit, bool=true(-1)
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=-1\n"
-                    + &format!("(CONTINUE_{})\n", id)
-                    + "// end ======= temp0 := temp1 > temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= gt\n"
-                    + "\n"
-            }
-            "lt" => {
-                String::new()
-                    + "// start ======= lt\n"
-                    + &self._write_pop("temp", 0)
-                    + &self._write_pop("temp", 1)
-                    + "// start ======= temp0 := temp1 < temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=D-M\n" // D: x, M: y
-                    + "D=M\n" // if D(x-y) < 0, M=true(-1), else M=false(0)
-                    + "M=0\n" // bool=false(0)
-                    + &format!("@HIT_{}\n", id)
-                    + "D;JLT\n"
-                    + &format!("@CONTINUE_{}\n", id)
-                    + "0;JMP\n"
-                    + &format!("(HIT_{})\n", id) // test hit, bool=true(-1)
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=-1\n"
-                    + &format!("(CONTINUE_{})\n", id)
-                    + "// end ======= temp0 := temp1 < temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= lt\n"
-                    + "\n"
-            }
-            "and" => {
-                String::new()
-                    + "// start ======= and\n"
-                    + &self._write_pop("temp", 0)
-                    + &self._write_pop("temp", 1)
-                    + "// start ======= temp0 = temp1 & temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=D&M\n" // D: x, M: y
-                    + "// end ======= temp0 = temp1 & temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= and\n"
-                    + "\n"
-            }
-            "or" => {
-                String::new()
-                    + "// start ======= or\n"
-                    + &self._write_pop("temp", 0)
-                    + &self._write_pop("temp", 1)
-                    + "// start ======= temp0 = temp1 | temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "A=A+1\n"
-                    + "D=M\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=D|M\n" // D: x, M: y
-                    + "// end ======= temp0 = temp1 | temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= or\n"
-                    + "\n"
-            }
-            "not" => {
-                String::new()
-                    + "// start ======= not\n"
-                    + &self._write_pop("temp", 0)
-                    + "// start ======= temp0 = !temp0\n"
-                    + &format!("@{TEMP_BASE}\n")
-                    + "M=!M\n"
-                    + "// end ======= temp0 = !temp0\n"
-                    + "\n"
-                    + &&self._write_push("temp", 0)
-                    + "// end ======= not\n"
-                    + "\n"
-            }
-            cmd => panic!("arithmetic command syntax error: unknow command `{cmd}`"),
-        }
+    /// Replaces the output file with the written code, call it after `close`.
+    pub fn commit(self) -> io::Result<()> {
+        self.into_inner().commit()
     }
+}
 
-    pub fn write_arithmetic(&mut self, cmd: &str, id: &str) -> io::Result<()> {
-        let buf = self._write_arithmetic(cmd, id);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
-    }
+impl<W: Write> CodeWriter<W> {
+    pub fn from_writer(out: W, backend: Box<dyn Backend>, comments: Comments) -> io::Result<Self> {
+        let mut _self = Self::from_writer_without_bootstrap(out, backend, comments);
 
-    fn _write_push_pop(&mut self, cmd: &str, arg1: &str, arg2: i32) -> String {
-        match cmd {
-            "push" => self._write_push(arg1, arg2),
-            "pop" => self._write_pop(arg1, arg2),
-            _ => panic!("push/pop cmd snytax error"),
-        }
-    }
+        // the bootstrap code calls Sys.init
+        _self.debug_info.add_call("Sys.init", 0);
+        let buf = _self.backend.bootstrap();
+        _self.write(&buf)?;
 
-    fn _write_push(&mut self, arg1: &str, arg2: i32) -> String {
-        match arg1 {
-            "constant" => {
-                String::new()
-                    + &format!("// start ======== push {arg1} {arg2}\n")
-                    + &format!("// D={arg2}\n")
-                    + &format!("@{arg2}\n")
-                    + &"D=A\n"
-                    + &format!("// stack[SP]=D\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// SP++\n")
-                    + &"@SP\n"
-                    + &"M=M+1\n"
-                    + &format!("// end ======== push {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            "temp" => {
-                let ram_address = arg2 + 5;
-                String::new()
-                    + &format!("// start ======== push {arg1} {arg2}\n")
-                    + &format!("// D={arg1}+{arg2}\n")
-                    + &format!("@{ram_address}\n")
-                    + &"D=M\n"
-                    + &format!("// stack[SP]=D\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// SP++\n")
-                    + &"@SP\n"
-                    + &"M=M+1\n"
-                    + &format!("// end ======== push {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            "static" => {
-                assert!(self.source_filename.is_some());
-                let static_var_id = format!("{}.{arg2}", self.source_filename.clone().unwrap());
-                String::new()
-                    + &format!("// start ======== push {arg1} {arg2}\n")
-                    + &format!("// D={arg1}+{arg2}\n")
-                    + &format!("@{static_var_id}\n")
-                    + &"D=M\n"
-                    + &format!("// stack[SP]=D\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// SP++\n")
-                    + &"@SP\n"
-                    + &"M=M+1\n"
-                    + &format!("// end ======== push {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            "pointer" => {
-                let pointer_address = if arg2 == 0 { 3 } else { 4 };
-                String::new()
-                    + &format!("// start ======== push {arg1} {arg2}\n")
-                    + &format!("// D= value of {arg1} {arg2}\n")
-                    + &format!("@{pointer_address}\n")
-                    + &"D=M\n"
-                    + &format!("// stack[SP]=D\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// SP++\n")
-                    + &"@SP\n"
-                    + &"M=M+1\n"
-                    + &format!("// end ======== push {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            _ => {
-                assert!(self.mem_seg_map.contains_key(arg1));
-                let arg1 = self.mem_seg_map[arg1].clone();
-                String::new()
-                    + &format!("// start ======== push {arg1} {arg2}\n")
-                    + &format!("// D={arg1}+{arg2}\n")
-                    + &format!("@{arg1}\n")
-                    + &"D=M\n"
-                    + &format!("@{arg2}\n")
-                    + &"D=D+A\n"
-                    + &"A=D\n"
-                    + &"D=M\n"
-                    + &format!("// stack[SP]=D\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// SP++\n")
-                    + &"@SP\n"
-                    + &"M=M+1\n"
-                    + &format!("// end ======== push {arg1} {arg2}\n")
-                    + &"\n"
-            }
-        }
+        Ok(_self)
     }
 
-    fn _write_pop(&mut self, arg1: &str, arg2: i32) -> String {
-        match arg1 {
-            "temp" => {
-                let ram_address = arg2 + 5;
-                String::new()
-                    + &format!("// start ======== pop {arg1} {arg2}\n")
-                    + &format!("// SP--\n")
-                    + &"@SP\n"
-                    + &"M=M-1\n"
-                    + &format!("// R13=addr({arg1}+{arg2})\n")
-                    + &format!("@{ram_address}\n")
-                    + &"D=A\n" // temp hold the register itself, not address
-                    + &"@R13\n"
-                    + &"M=D\n"
-                    + &format!("// D=stack[SP]\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"D=M\n"
-                    + &format!("// *R13=D\n")
-                    + &"@R13\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// end ======== pop {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            "static" => {
-                assert!(self.source_filename.is_some());
-                let static_var_id = format!("{}.{arg2}", self.source_filename.clone().unwrap());
-                String::new()
-                    + &format!("// start ======== pop {arg1} {arg2}\n")
-                    + &format!("// SP--\n")
-                    + &"@SP\n"
-                    + &"M=M-1\n"
-                    + &format!("// D=stack[SP]\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"D=M\n"
-                    + &format!("// *{{filename}}.i=D\n")
-                    + &format!("@{static_var_id}\n")
-                    + &"M=D\n"
-                    + &format!("// end ======== pop {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            "pointer" => {
-                let pointer_address = if arg2 == 0 { 3 } else { 4 };
-                String::new()
-                    + &format!("// start ======== pop {arg1} {arg2}\n")
-                    + &format!("// SP--\n")
-                    + &"@SP\n"
-                    + &"M=M-1\n"
-                    + &format!("// D=stack[SP]\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"D=M\n"
-                    + &format!("// *({arg1} {arg2})=D\n")
-                    + &format!("@{pointer_address}\n")
-                    + &"M=D\n"
-                    + &format!("// end ======== pop {arg1} {arg2}\n")
-                    + &"\n"
-            }
-            _ => {
-                assert!(self.mem_seg_map.contains_key(arg1));
-                let arg1 = self.mem_seg_map[arg1].clone();
-                String::new()
-                    + &format!("// start ======== pop {arg1} {arg2}\n")
-                    + &format!("// SP--\n")
-                    + &"@SP\n"
-                    + &"M=M-1\n"
-                    + &format!("// R13=addr({arg1}+{arg2})\n")
-                    + &format!("@{arg1}\n")
-                    + &"D=M\n"
-                    + &format!("@{arg2}\n")
-                    + &"D=D+A\n"
-                    + &"@R13\n"
-                    + &"M=D\n"
-                    + &format!("// D=stack[SP]\n")
-                    + &"@SP\n"
-                    + &"A=M\n"
-                    + &"D=M\n"
-                    + &format!("// *R13=D\n")
-                    + &"@R13\n"
-                    + &"A=M\n"
-                    + &"M=D\n"
-                    + &format!("// end ======== pop {arg1} {arg2}\n")
-                    + &"\n"
-            }
+    /// The program starts at the first translated command instead of `Sys.init`.
+    pub fn from_writer_without_bootstrap(
+        out: W,
+        backend: Box<dyn Backend>,
+        comments: Comments,
+    ) -> Self {
+        Self {
+            out,
+            backend,
+            comments,
+            source_filename: None,
+            current_function: String::new(),
+            source_location: None,
+            lines_written: 0,
+            source_map: SourceMap::new(),
+            debug_info: DebugInfo::new(),
         }
     }
 
-    pub fn write_push_pop(&mut self, cmd: &str, arg1: &str, arg2: i32) -> io::Result<()> {
-        let buf = self._write_push_pop(cmd, arg1, arg2);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn set_source_file(&mut self, source_file: &str) {
+        self.backend.set_source_file(source_file);
+        self.source_filename = Some(source_file.to_string());
+        self.current_function = String::new();
+        self.source_location = None;
     }
 
-    fn _gen_label(&self, label: &str) -> String {
-        // let mut file = "".to_string();
-        // if let Some(f) = self.source_filename.clone() {
-        //     file = f[..(f.len() - 3)].to_string();
-        // };
-        let mut function = "".to_string();
-        if let Some(fun) = self.current_function.clone() {
-            function = fun;
-        };
-
-        format!("{function}${label}")
+    /// Sets the VM command the next `write_*` translates, for the source map.
+    pub fn set_source_line(&mut self, line: usize, command: &str) {
+        assert!(self.source_filename.is_some());
+        self.source_location = Some(SourceLocation {
+            file: self.source_filename.clone().unwrap(),
+            line,
+            command: command.to_string(),
+        });
     }
 
-    fn _write_label(&mut self, label: &str) -> String {
-        format!("({})\n", self._gen_label(label))
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
     }
 
-    pub fn write_label(&mut self, label: &str) -> io::Result<()> {
-        let buf = self._write_label(label);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
     }
 
-    fn _write_goto(&mut self, label: &str) -> String {
-        format!("@{}\n", self._gen_label(label)) + "0;JMP\n"
+    /// The writer the code is written to, call it after `close`.
+    pub fn into_inner(self) -> W {
+        self.out
     }
 
-    pub fn write_goto(&mut self, label: &str) -> io::Result<()> {
-        let buf = self._write_goto(label);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn write_arithmetic(&mut self, cmd: &str, id: &str) -> io::Result<()> {
+        let buf = self.backend.arithmetic(cmd, id);
+        self.write(&buf)
     }
 
-    fn _write_if(&mut self, label: &str) -> String {
-        String::new()
-            + "@SP\n"
-            + "AM=M-1\n"
-            + "D=M\n"
-            + &format!("@{}\n", self._gen_label(label))
-            + "D;JNE\n"
+    pub fn write_push_pop(&mut self, cmd: &str, arg1: &str, arg2: i32) -> io::Result<()> {
+        if let ("static", Some(source_file)) = (arg1, &self.source_filename) {
+            self.debug_info.add_static(source_file, arg2);
+        }
+        let buf = match cmd {
+            "push" => self.backend.push(arg1, arg2),
+            "pop" => self.backend.pop(arg1, arg2),
+            _ => panic!("push/pop cmd snytax error"),
+        };
+        self.write(&buf)
     }
 
-    pub fn write_if(&mut self, label: &str) -> io::Result<()> {
-        let buf = self._write_if(label);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn write_label(&mut self, label: &str) -> io::Result<()> {
+        self.debug_info.add_label(label);
+        let buf = self.backend.label(label);
+        self.write(&buf)
     }
 
-    fn _gen_fn_name(&self, function_name: &str) -> String {
-        // let mut file = "".to_string();
-        // if let Some(f) = self.source_filename.clone() {
-        //     file = f[..(f.len() - 3)].to_string();
-        // };
-        format!("{function_name}")
+    pub fn write_goto(&mut self, label: &str) -> io::Result<()> {
+        let buf = self.backend.goto(label);
+        self.write(&buf)
     }
 
-    fn _write_function(&mut self, function_name: &str, n_vars: u32) -> String {
-        let mut s = String::new();
-        s += &format!("({})\n", self._gen_fn_name(function_name));
-        for _ in 0..n_vars {
-            s += &self._write_push("constant", 0);
-        }
-        self.current_function_call_count = 0;
-        self.set_current_function(function_name);
-        s
+    pub fn write_if(&mut self, label: &str) -> io::Result<()> {
+        let buf = self.backend.if_goto(label);
+        self.write(&buf)
     }
 
     pub fn write_function(&mut self, function_name: &str, n_vars: u32) -> io::Result<()> {
-        let buf = self._write_function(function_name, n_vars);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
-    }
-
-    fn _gen_return_address(&mut self) -> String {
-        let s = format!(
-            "{}$ret.{}",
-            self._gen_fn_name(&self.current_function.clone().unwrap()),
-            self.current_function_call_count
-        );
-        self.current_function_call_count += 1;
-        s
+        self.current_function = function_name.to_string();
+        let source_file = self.source_filename.clone().unwrap_or_default();
+        let line = self.source_location.as_ref().map_or(0, |l| l.line);
+        self.debug_info
+            .add_function(function_name, n_vars, &source_file, line);
+        let buf = self.backend.function(function_name, n_vars);
+        self.write(&buf)
     }
 
-    fn _write_call(&mut self, function_name: &str, n_args: u32) -> String {
-        let return_address = self._gen_return_address();
-        String::new()
-            + "// start call ========================\n"
-            + "// push return_address\n"
-            + &format!("@{return_address}\n")
-            + "D=A\n"
-            + "@SP\n"
-            + "A=M\n"
-            + "M=D\n"
-            + "@SP\n"
-            + "M=M+1\n"
-            + "// push LCL\n"
-            + "@LCL\n"
-            + "D=M\n"
-            + "@SP\n"
-            + "A=M\n"
-            + "M=D\n"
-            + "@SP\n"
-            + "M=M+1\n"
-            + "// push ARG\n"
-            + "@ARG\n"
-            + "D=M\n"
-            + "@SP\n"
-            + "A=M\n"
-            + "M=D\n"
-            + "@SP\n"
-            + "M=M+1\n"
-            + "// push THIS\n"
-            + "@THIS\n"
-            + "D=M\n"
-            + "@SP\n"
-            + "A=M\n"
-            + "M=D\n"
-            + "@SP\n"
-            + "M=M+1\n"
-            + "// push THAT\n"
-            + "@THAT\n"
-            + "D=M\n"
-            + "@SP\n"
-            + "A=M\n"
-            + "M=D\n"
-            + "@SP\n"
-            + "M=M+1\n"
-            + "// ARG=SP-5-n_args\n"
-            + "@SP\n"
-            + "D=M\n"
-            + "@5\n"
-            + "D=D-A\n"
-            + &format!("@{n_args}\n")
-            + "D=D-A\n"
-            + "@ARG\n"
-            + "M=D\n"
-            + "// LCL=SP\n"
-            + "@SP\n"
-            + "D=M\n"
-            + "@LCL\n"
-            + "M=D\n"
-            + "// goto function_name\n"
-            + &format!("@{}\n", self._gen_fn_name(function_name))
-            + "0;JMP\n"
-            + &format!("({})\n", return_address)
-            + "// end call ========================\n"
+    pub fn write_call(&mut self, function_name: &str, n_args: u32) -> io::Result<()> {
+        self.debug_info.add_call(function_name, n_args);
+        let buf = self.backend.call(function_name, n_args);
+        self.write(&buf)
     }
 
-    pub fn write_call(&mut self, function_name: &str, n_args: u32) -> io::Result<()> {
-        let buf = self._write_call(function_name, n_args);
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn write_return(&mut self) -> io::Result<()> {
+        let buf = self.backend.ret();
+        self.write(&buf)
     }
 
-    fn _write_return(&mut self) -> String {
-        String::new()
-            + "// start return ========================\n"
-            + "// frame(R13)=LCL\n"
-            + "@LCL\n"
-            + "D=M\n"
-            + "@R13\n"
-            + "M=D\n"
-            + "// return_address(R14)=*(frame-5)\n"
-            + "@R13\n"
-            + "D=M\n"
-            + "@5\n"
-            + "A=D-A\n"
-            + "D=M\n"
-            + "@R14\n"
-            + "M=D\n"
-            + "// *ARG=pop()\n"
-            + "@SP\n"
-            + "AM=M-1\n"
-            + "D=M\n"
-            + "@ARG\n"
-            + "A=M\n"
-            + "M=D\n"
-            + "// SP=ARG+1\n"
-            + "@ARG\n"
-            + "D=M+1\n"
-            + "@SP\n"
-            + "M=D\n"
-            + "// THAT=*(frame-1)\n"
-            + "@R13\n"
-            + "D=M\n"
-            + "@1\n"
-            + "A=D-A\n"
-            + "D=M\n"
-            + "@THAT\n"
-            + "M=D\n"
-            + "// THIS=*(frame-2)\n"
-            + "@R13\n"
-            + "D=M\n"
-            + "@2\n"
-            + "A=D-A\n"
-            + "D=M\n"
-            + "@THIS\n"
-            + "M=D\n"
-            + "// ARG=*(frame-3)\n"
-            + "@R13\n"
-            + "D=M\n"
-            + "@3\n"
-            + "A=D-A\n"
-            + "D=M\n"
-            + "@ARG\n"
-            + "M=D\n"
-            + "// LCL=*(frame-4)\n"
-            + "@R13\n"
-            + "D=M\n"
-            + "@4\n"
-            + "A=D-A\n"
-            + "D=M\n"
-            + "@LCL\n"
-            + "M=D\n"
-            + "// goto return_address\n"
-            + "@R14\n"
-            + "A=M\n"
-            + "0;JMP\n"
-            + "// end return ========================\n"
+    pub fn close(&mut self) -> io::Result<()> {
+        self.source_location = None;
+        let buf = self.backend.finish();
+        self.write(&buf)
     }
 
-    pub fn write_return(&mut self) -> io::Result<()> {
-        let buf = self._write_return();
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    // privates
+    fn write(&mut self, buf: &str) -> io::Result<()> {
+        let buf = match self.comments {
+            Comments::Verbose => buf.to_string(),
+            Comments::None => self.strip_comments(buf),
+            Comments::Vm => match &self.source_location {
+                Some(location) if !buf.is_empty() => {
+                    let (start, end) = self.backend.comment_delimiters();
+                    let comment =
+                        format!("{}:{}: {}", location.file, location.line, location.command);
+                    let comment = format!("{start} {comment} {end}");
+                    comment.trim_end().to_string() + "\n" + &self.strip_comments(buf)
+                }
+                _ => self.strip_comments(buf),
+            },
+        };
+        let lines = buf.lines().count();
+        if let (Some(location), true) = (&self.source_location, lines > 0) {
+            self.source_map.add(SourceMapEntry {
+                first_line: self.lines_written + 1,
+                last_line: self.lines_written + lines,
+                function: self.current_function.clone(),
+                location: location.clone(),
+            });
+        }
+        self.lines_written += lines;
+        self.out.write_all(buf.as_bytes())
     }
 
-    pub fn close(&mut self) -> io::Result<()> {
-        let buf =
-            String::new() + &format!("// end the program\n") + &"(END)\n" + &"@END\n" + &"0;JMP\n";
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    fn strip_comments(&self, buf: &str) -> String {
+        let (start, _) = self.backend.comment_delimiters();
+        buf.lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with(start))
+            .map(|line| line.to_string() + "\n")
+            .collect()
     }
 }
 
@@ -687,15 +225,113 @@ impl CodeWriter {
 mod tests {
     use std::fs;
 
+    use crate::backend::HackBackend;
+
     use super::*;
 
     #[test]
     fn test_write_to_file() -> io::Result<()> {
         let file_path = Path::new("./test.asm");
-        let mut code_writer = CodeWriter::new(file_path)?;
+        let mut code_writer =
+            CodeWriter::new(file_path, Box::new(HackBackend::new()), Comments::Verbose)?;
         code_writer.write_arithmetic("add", "1")?;
         code_writer.close()?;
+        code_writer.commit()?;
+        fs::remove_file(file_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map() -> io::Result<()> {
+        let file_path = Path::new("./test_source_map.asm");
+        let mut code_writer =
+            CodeWriter::new(file_path, Box::new(HackBackend::new()), Comments::Verbose)?;
+        code_writer.set_source_file("Main.vm");
+        code_writer.set_source_line(1, "function Main.main 0");
+        code_writer.write_function("Main.main", 0)?;
+        code_writer.set_source_line(2, "push constant 1");
+        code_writer.write_push_pop("push", "constant", 1)?;
+        code_writer.close()?;
+        let entries = code_writer.source_map().entries.clone();
+        code_writer.commit()?;
+        let contents = fs::read_to_string(file_path)?;
         fs::remove_file(file_path)?;
+
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(lines[entries[0].first_line - 1], "(Main.main)");
+        assert_eq!(entries[0].first_line, entries[0].last_line);
+        assert_eq!(entries[1].first_line, entries[0].last_line + 1);
+        assert_eq!(lines[entries[1].first_line], "// D=1");
+        assert_eq!(entries[1].function, "Main.main");
+        assert_eq!(entries[1].location.line, 2);
+        assert_eq!(entries[1].location.command, "push constant 1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_replaces_output() -> io::Result<()> {
+        let file_path = Path::new("./test_rewrite.asm");
+        let mut contents = vec![];
+        for _ in 0..2 {
+            let mut code_writer =
+                CodeWriter::new(file_path, Box::new(HackBackend::new()), Comments::None)?;
+            code_writer.close()?;
+            code_writer.commit()?;
+            contents.push(fs::read_to_string(file_path)?);
+        }
+        fs::remove_file(file_path)?;
+        assert_eq!(contents[0], contents[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_write_keeps_output() -> io::Result<()> {
+        let file_path = Path::new("./test_failed_write.asm");
+        fs::write(file_path, "old")?;
+        let mut code_writer =
+            CodeWriter::new(file_path, Box::new(HackBackend::new()), Comments::None)?;
+        code_writer.write_arithmetic("add", "1")?;
+        drop(code_writer);
+        let contents = fs::read_to_string(file_path)?;
+        fs::remove_file(file_path)?;
+        assert_eq!(contents, "old");
+        Ok(())
+    }
+
+    fn write_with_comments(comments: Comments) -> io::Result<String> {
+        let file_path = format!("./test_comments_{comments:?}.asm");
+        let file_path = Path::new(&file_path);
+        let mut code_writer = CodeWriter::new(file_path, Box::new(HackBackend::new()), comments)?;
+        code_writer.set_source_file("Main.vm");
+        code_writer.set_source_line(1, "function Main.main 0");
+        code_writer.write_function("Main.main", 0)?;
+        code_writer.set_source_line(2, "push constant 1");
+        code_writer.write_push_pop("push", "constant", 1)?;
+        code_writer.close()?;
+        code_writer.commit()?;
+        let contents = fs::read_to_string(file_path)?;
+        fs::remove_file(file_path)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn test_comments_none() -> io::Result<()> {
+        let contents = write_with_comments(Comments::None)?;
+        assert!(contents
+            .ends_with("(Main.main)\n@1\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n(END)\n@END\n0;JMP\n"));
+        assert!(!contents.contains("//"));
+        assert!(!contents.contains("\n\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments_vm() -> io::Result<()> {
+        let contents = write_with_comments(Comments::Vm)?;
+        assert!(contents.contains(
+            "// Main.vm:1: function Main.main 0\n(Main.main)\n// Main.vm:2: push constant 1\n@1\n"
+        ));
+        assert_eq!(contents.matches("//").count(), 2);
         Ok(())
     }
 }
@@ -0,0 +1,292 @@
+use std::{collections::BTreeMap, fmt::Write, path::PathBuf};
+
+use crate::{
+    ir::{Command, Program},
+    source_map::SourceMap,
+};
+
+const SP: usize = 0;
+
+/// What a VM command of the report is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Command,
+    Function(String),
+    /// An `if-goto`, and the times it jumped and it didn't.
+    Branch {
+        taken: u64,
+        not_taken: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    hits: u64,
+    kind: Kind,
+}
+
+/// The VM commands a run executed, and the `if-goto` commands which jumped or not, by file
+/// and line. Labels aren't executed and aren't in the report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// The files of the program, by name, with their path.
+    files: BTreeMap<String, (PathBuf, BTreeMap<usize, Line>)>,
+}
+
+impl Coverage {
+    /// `paths` are the files of `program`, in its order.
+    pub fn new(program: &Program, paths: &[PathBuf]) -> Self {
+        let mut files = BTreeMap::new();
+        for (file, path) in program.files.iter().zip(paths) {
+            let mut lines = BTreeMap::new();
+            for instruction in &file.instructions {
+                let kind = match &instruction.command {
+                    Command::Label(_) => continue,
+                    Command::Function(name, _) => Kind::Function(name.clone()),
+                    Command::IfGoto(_) => Kind::Branch {
+                        taken: 0,
+                        not_taken: 0,
+                    },
+                    _ => Kind::Command,
+                };
+                lines.insert(instruction.line, Line { hits: 0, kind });
+            }
+            files.insert(file.name.clone(), (path.clone(), lines));
+        }
+        Self { files }
+    }
+
+    /// To call before the command at `file:line` is executed, an `if-goto` jumps when the top
+    /// of the stack isn't 0.
+    pub fn hit(&mut self, file: &str, line: usize, ram: &[i16]) {
+        let Some(line) = self
+            .files
+            .get_mut(file)
+            .and_then(|(_, lines)| lines.get_mut(&line))
+        else {
+            return;
+        };
+        line.hits += 1;
+        if let Kind::Branch { taken, not_taken } = &mut line.kind {
+            let top = (ram[SP] as u16 as usize)
+                .checked_sub(1)
+                .and_then(|address| ram.get(address));
+            if top.is_some_and(|&top| top != 0) {
+                *taken += 1;
+            } else {
+                *not_taken += 1;
+            }
+        }
+    }
+
+    /// The report in the lcov tracefile format, a record per file with its functions
+    /// (`FN`, `FNDA`), its branches (`BRDA`, taken first) and its lines (`DA`).
+    pub fn lcov(&self) -> String {
+        let mut s = String::from("TN:\n");
+        for (path, lines) in self.files.values() {
+            let _ = writeln!(s, "SF:{}", path.display());
+            let functions = lines.iter().filter_map(|(number, line)| match &line.kind {
+                Kind::Function(name) => Some((number, name, line.hits)),
+                _ => None,
+            });
+            for (number, name, _) in functions.clone() {
+                let _ = writeln!(s, "FN:{number},{name}");
+            }
+            for (_, name, hits) in functions.clone() {
+                let _ = writeln!(s, "FNDA:{hits},{name}");
+            }
+            let _ = writeln!(s, "FNF:{}", functions.clone().count());
+            let _ = writeln!(s, "FNH:{}", functions.filter(|f| f.2 > 0).count());
+
+            let (mut found, mut hit) = (0, 0);
+            for (number, line) in lines {
+                if let Kind::Branch { taken, not_taken } = line.kind {
+                    for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                        let count = match line.hits {
+                            0 => "-".to_string(),
+                            _ => count.to_string(),
+                        };
+                        let _ = writeln!(s, "BRDA:{number},0,{branch},{count}");
+                    }
+                    found += 2;
+                    hit += (taken > 0) as usize + (not_taken > 0) as usize;
+                }
+            }
+            let _ = writeln!(s, "BRF:{found}\nBRH:{hit}");
+
+            for (number, line) in lines {
+                let _ = writeln!(s, "DA:{number},{}", line.hits);
+            }
+            let _ = writeln!(s, "LF:{}", lines.len());
+            let _ = writeln!(s, "LH:{}", lines.values().filter(|l| l.hits > 0).count());
+            s += "end_of_record\n";
+        }
+        s
+    }
+
+    /// One line with the lines, branches and functions covered.
+    pub fn summary(&self) -> String {
+        let lines = || self.files.values().flat_map(|(_, lines)| lines.values());
+        let percent = |hit: usize, found: usize| hit as f64 * 100.0 / found.max(1) as f64;
+        let (lines_hit, lines_found) = (lines().filter(|l| l.hits > 0).count(), lines().count());
+        let (mut branches_hit, mut branches_found) = (0, 0);
+        let (mut functions_hit, mut functions_found) = (0, 0);
+        for line in lines() {
+            match line.kind {
+                Kind::Branch { taken, not_taken } => {
+                    branches_found += 2;
+                    branches_hit += (taken > 0) as usize + (not_taken > 0) as usize;
+                }
+                Kind::Function(_) => {
+                    functions_found += 1;
+                    functions_hit += (line.hits > 0) as usize;
+                }
+                Kind::Command => {}
+            }
+        }
+        format!(
+            "coverage: {lines_hit} of {lines_found} lines ({:.1}%), \
+             {branches_hit} of {branches_found} branches ({:.1}%), \
+             {functions_hit} of {functions_found} functions",
+            percent(lines_hit, lines_found),
+            percent(branches_hit, branches_found),
+        )
+    }
+}
+
+/// Records the coverage of the Hack translation of a program running on the CPU emulator,
+/// followed through its source map.
+pub struct HackCoverage<'a> {
+    pub coverage: Coverage,
+    source_map: &'a SourceMap,
+    /// Source map entry of every ROM address.
+    entries: Vec<Option<usize>>,
+    /// The entries without instructions before every ROM address, like the `function`
+    /// commands without locals, they are executed when the program gets to the address.
+    before: Vec<Vec<usize>>,
+}
+
+impl<'a> HackCoverage<'a> {
+    /// `source_map` comes with the assembly lines of the ROM addresses.
+    pub fn new(coverage: Coverage, source_map: &'a SourceMap, lines: &[usize]) -> Self {
+        let entries = source_map.entry_indexes(lines);
+        let mut has_code = vec![false; source_map.entries.len()];
+        for &entry in entries.iter().flatten() {
+            has_code[entry] = true;
+        }
+        let mut before = vec![vec![]; lines.len()];
+        for (i, entry) in source_map.entries.iter().enumerate() {
+            if !has_code[i] {
+                let address = lines.partition_point(|&line| line <= entry.last_line);
+                if let Some(before) = before.get_mut(address) {
+                    before.push(i);
+                }
+            }
+        }
+        Self {
+            coverage,
+            source_map,
+            entries,
+            before,
+        }
+    }
+
+    /// To call before every instruction.
+    pub fn step(&mut self, pc: u16, ram: &[i16]) {
+        let pc = pc as usize;
+        let Some(entry) = self.entries.get(pc).copied().flatten() else {
+            return;
+        };
+        if pc > 0 && self.entries[pc - 1] == Some(entry) {
+            return;
+        }
+        for &entry in self.before.get(pc).into_iter().flatten().chain([&entry]) {
+            let location = &self.source_map.entries[entry].location;
+            self.coverage.hit(&location.file, location.line, ram);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fs};
+
+    use crate::{
+        emulator::Emulator, hack_asm::assemble, interpreter::Interpreter,
+        translate::translate_to_hack,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_coverage() -> Result<(), Box<dyn Error>> {
+        let path = PathBuf::from("./test_coverage_Sys.vm");
+        fs::write(
+            &path,
+            "function Sys.init 0\n\
+             push constant 2\n\
+             call Sys.twice 1\n\
+             label END\n\
+             goto END\n\
+             function Sys.twice 0\n\
+             push argument 0\n\
+             if-goto POSITIVE\n\
+             push constant 0\n\
+             return\n\
+             label POSITIVE\n\
+             push argument 0\n\
+             push argument 0\n\
+             add\n\
+             return\n\
+             function Sys.unused 0\n\
+             push constant 0\n\
+             return\n",
+        )?;
+        let paths = [path.clone()];
+        let program = Program::load(&paths)?;
+
+        let mut vm = Interpreter::new(&program)?;
+        vm.bootstrap()?;
+        let mut vm_coverage = Coverage::new(&program, &paths);
+        for _ in 0..100 {
+            if let Some(location) = vm.location(vm.pc) {
+                vm_coverage.hit(&location.file, location.line, &vm.ram);
+            }
+            if vm.step()?.is_some() {
+                break;
+            }
+        }
+
+        let (asm, source_map) = translate_to_hack(&paths, true)?;
+        let assembly = assemble(&asm)?;
+        let mut hack_coverage = HackCoverage::new(
+            Coverage::new(&program, &paths),
+            &source_map,
+            &assembly.lines,
+        );
+        let mut emulator = Emulator::new(assembly.words);
+        for _ in 0..1000 {
+            hack_coverage.step(emulator.pc, &emulator.ram);
+            if emulator.step()?.is_some() {
+                break;
+            }
+        }
+        fs::remove_file(&path)?;
+
+        let lcov = vm_coverage.lcov();
+        assert_eq!(lcov, hack_coverage.coverage.lcov());
+        for record in [
+            "FNDA:1,Sys.twice\nFNDA:0,Sys.unused\nFNF:3\nFNH:2\n",
+            "BRDA:8,0,0,1\nBRDA:8,0,1,0\nBRF:2\nBRH:1\n",
+            "DA:9,0\nDA:10,0\nDA:12,1\n",
+            "LF:16\nLH:11\n",
+        ] {
+            assert!(lcov.contains(record), "{record} in\n{lcov}");
+        }
+        assert_eq!(
+            vm_coverage.summary(),
+            "coverage: 11 of 16 lines (68.8%), 1 of 2 branches (50.0%), 2 of 3 functions"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    backend::{label_symbol, return_symbol, static_symbol},
+    json::Json,
+};
+
+const VERSION: i64 = 1;
+
+/// Debug information of one VM function.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    /// Symbol of the first instruction of the function.
+    pub entry_label: String,
+    pub file: String,
+    /// 1-based line number of the `function` command.
+    pub line: usize,
+    pub n_locals: u32,
+    /// Symbols of the VM labels, `{function}${label}`.
+    pub labels: Vec<String>,
+    /// Symbols of the return addresses of the calls made by this function, `{function}$ret.{n}`.
+    pub return_labels: Vec<String>,
+}
+
+/// Functions, labels and static symbols of a translated program.
+///
+/// It is written as a JSON sidecar, emulators and debuggers use it to show function names
+/// and segment contents instead of raw RAM addresses.
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    pub functions: Vec<FunctionInfo>,
+    /// Static symbols `{file}.{index}` used by every source file.
+    pub statics: BTreeMap<String, BTreeSet<String>>,
+    // largest argument count seen at the call sites of every function
+    call_args: BTreeMap<String, u32>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_function(&mut self, name: &str, n_locals: u32, file: &str, line: usize) {
+        self.functions.push(FunctionInfo {
+            name: name.to_string(),
+            entry_label: name.to_string(),
+            file: file.to_string(),
+            line,
+            n_locals,
+            labels: vec![],
+            return_labels: vec![],
+        });
+    }
+
+    /// Labels and calls belong to the last added function, they are ignored before the first one.
+    pub fn add_label(&mut self, label: &str) {
+        if let Some(function) = self.functions.last_mut() {
+            function.labels.push(label_symbol(&function.name, label));
+        }
+    }
+
+    pub fn add_call(&mut self, function_name: &str, n_args: u32) {
+        let n_args = self
+            .call_args
+            .get(function_name)
+            .map_or(n_args, |n| n_args.max(*n));
+        self.call_args.insert(function_name.to_string(), n_args);
+        if let Some(function) = self.functions.last_mut() {
+            let n = function.return_labels.len() as u32;
+            function
+                .return_labels
+                .push(return_symbol(&function.name, n));
+        }
+    }
+
+    /// Argument count passed by the call sites, `None` when the function is never called.
+    pub fn n_args(&self, function_name: &str) -> Option<u32> {
+        self.call_args.get(function_name).copied()
+    }
+
+    pub fn add_static(&mut self, file: &str, index: i32) {
+        self.statics
+            .entry(file.to_string())
+            .or_default()
+            .insert(static_symbol(file, index));
+    }
+
+    pub fn to_json(&self) -> Json {
+        let strings = |items: &mut dyn Iterator<Item = &String>| {
+            Json::Array(items.map(|s| Json::string(s)).collect())
+        };
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                let n_args = self.n_args(&function.name);
+                Json::object(vec![
+                    ("name", Json::string(&function.name)),
+                    ("entry_label", Json::string(&function.entry_label)),
+                    ("file", Json::string(&function.file)),
+                    ("line", Json::Number(function.line as i64)),
+                    ("locals", Json::Number(function.n_locals as i64)),
+                    (
+                        "arguments",
+                        n_args.map_or(Json::Null, |n| Json::Number(n as i64)),
+                    ),
+                    ("labels", strings(&mut function.labels.iter())),
+                    ("return_labels", strings(&mut function.return_labels.iter())),
+                ])
+            })
+            .collect();
+        let statics = self
+            .statics
+            .iter()
+            .map(|(file, symbols)| (file.clone(), strings(&mut symbols.iter())))
+            .collect();
+        Json::object(vec![
+            ("version", Json::Number(VERSION)),
+            ("functions", Json::Array(functions)),
+            ("statics", Json::Object(statics)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        let mut info = DebugInfo::new();
+        info.add_function("Main.main", 2, "Main.vm", 1);
+        info.add_label("LOOP");
+        info.add_call("Main.f", 1);
+        info.add_call("Main.f", 1);
+        info.add_static("Main.vm", 3);
+        info.add_static("Main.vm", 0);
+        info.add_static("Main.vm", 3);
+        info.add_function("Main.f", 0, "Main.vm", 9);
+
+        assert_eq!(info.functions[0].labels, vec!["Main.main$LOOP"]);
+        assert_eq!(
+            info.functions[0].return_labels,
+            vec!["Main.main$ret.0", "Main.main$ret.1"]
+        );
+        assert_eq!(info.statics["Main.vm"].len(), 2);
+        assert_eq!(info.n_args("Main.f"), Some(1));
+        assert_eq!(info.n_args("Main.main"), None);
+
+        let json = info.to_json().to_string();
+        assert!(json.starts_with("{\n  \"version\": 1,\n"));
+        // Main.main is never called, Main.f is called with one argument
+        assert!(json.contains("\"locals\": 2,\n      \"arguments\": null,"));
+        assert!(json.contains("\"locals\": 0,\n      \"arguments\": 1,"));
+        assert!(json.contains("\"Main.vm\": [\n      \"Main.vm.0\",\n      \"Main.vm.3\"\n    ]"));
+    }
+}
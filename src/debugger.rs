@@ -0,0 +1,769 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    emulator::{self, Emulator},
+    hack_asm::assemble,
+    interpreter::{self, Interpreter, Location, STACK_BASE},
+    ir::{Command, Program},
+    source_map::{SourceMap, SourceMapEntry},
+    translate::translate_to_hack,
+};
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+/// Words of `this` and `that` shown by `frame`.
+const POINTED_WORDS: usize = 4;
+
+const HELP: &str = "\
+break <function> | <function>:<label> | <file>:<line>   set a breakpoint (b)
+delete <n>        delete a breakpoint
+breakpoints       list the breakpoints
+continue          run to a breakpoint or the end (c)
+step              execute a VM command, into calls (s)
+stepi             execute a Hack instruction, a VM command on the interpreter (si)
+next              execute a VM command, over calls (n)
+finish            run until the current function returns
+frame             show the segments of the current function and its operand stack (f)
+backtrace         show the call stack, rebuilt from the saved frames (bt)
+ram <a>[..<b>]    show RAM words (x)
+help              show this help (h)
+quit              leave the debugger (q)
+An empty line repeats the last command.";
+
+/// A VM command the program executes.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Position {
+    pub file: String,
+    pub line: usize,
+    /// Empty before the first `function` command of a file.
+    pub function: String,
+    pub command: String,
+}
+
+impl From<&Location> for Position {
+    fn from(location: &Location) -> Self {
+        Self {
+            file: location.file.clone(),
+            line: location.line,
+            function: location.function.clone(),
+            command: location.command.to_string(),
+        }
+    }
+}
+
+impl From<&SourceMapEntry> for Position {
+    fn from(entry: &SourceMapEntry) -> Self {
+        Self {
+            file: entry.location.file.clone(),
+            line: entry.location.line,
+            function: entry.function.clone(),
+            command: entry.location.command.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} {}: {}",
+            self.file, self.line, self.function, self.command
+        )
+    }
+}
+
+/// A machine running a VM program, one VM command or instruction at a time.
+pub trait Target {
+    fn ram(&self) -> &[i16];
+    /// The VM command executed next, `None` when the program left its commands.
+    fn position(&self) -> Option<Position> {
+        self.position_at(self.address())
+    }
+    /// The VM command of the instruction at an address.
+    fn position_at(&self, address: usize) -> Option<Position>;
+    /// The address of the next instruction, compared with the breakpoint addresses.
+    fn address(&self) -> usize;
+    /// The address the program is at before executing the command `file:line`, or the next
+    /// command of the file with instructions.
+    fn resolve(&self, file: &str, line: usize) -> Option<usize>;
+    /// Executes the next VM command, and tells why the program stopped.
+    fn step(&mut self) -> Result<Option<String>, Box<dyn Error>>;
+    /// Executes the next machine instruction, and tells why the program stopped.
+    fn step_instruction(&mut self) -> Result<Option<String>, Box<dyn Error>>;
+    /// The next machine instruction, when it isn't a VM command.
+    fn instruction(&self) -> Option<String>;
+    /// Steps or cycles executed.
+    fn time(&self) -> String;
+}
+
+/// The VM interpreter.
+pub struct VmTarget {
+    vm: Interpreter,
+}
+
+impl VmTarget {
+    pub fn new(program: &Program) -> Result<Self, Box<dyn Error>> {
+        let mut vm = Interpreter::new(program)?;
+        vm.bootstrap()?;
+        Ok(Self { vm })
+    }
+}
+
+impl Target for VmTarget {
+    fn ram(&self) -> &[i16] {
+        &self.vm.ram
+    }
+
+    fn position_at(&self, address: usize) -> Option<Position> {
+        self.vm.location(address).map(Position::from)
+    }
+
+    fn address(&self) -> usize {
+        self.vm.pc
+    }
+
+    fn resolve(&self, file: &str, line: usize) -> Option<usize> {
+        (0..)
+            .map_while(|pc| self.vm.location(pc).map(|location| (pc, location)))
+            .filter(|(_, location)| location.file == file && location.line >= line)
+            .min_by_key(|(_, location)| location.line)
+            .map(|(pc, _)| pc)
+    }
+
+    fn step(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        if self.vm.steps >= interpreter::DEFAULT_MAX_STEPS {
+            return Ok(Some(interpreter::Stop::StepLimit.to_string()));
+        }
+        Ok(self.vm.step()?.map(|stop| stop.to_string()))
+    }
+
+    fn step_instruction(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        self.step()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        None
+    }
+
+    fn time(&self) -> String {
+        format!("{} steps", self.vm.steps)
+    }
+}
+
+/// The CPU emulator running the Hack translation of the program, followed through its source
+/// map.
+pub struct HackTarget {
+    emulator: Emulator,
+    source_map: SourceMap,
+    /// Source map entry of every ROM address.
+    entries: Vec<Option<usize>>,
+    /// Assembly of every ROM address.
+    instructions: Vec<String>,
+}
+
+impl HackTarget {
+    /// Runs the bootstrap code to the first VM command.
+    pub fn new(files: &[PathBuf], program: &Program) -> Result<Self, Box<dyn Error>> {
+        let bootstrap = program.defines("Sys.init");
+        let (asm, source_map) = translate_to_hack(files, bootstrap)?;
+        let assembly = assemble(&asm)?;
+        let asm_lines = asm.lines().collect::<Vec<_>>();
+        let instructions = assembly
+            .lines
+            .iter()
+            .map(|&line| asm_lines[line - 1].trim().to_string())
+            .collect();
+        let mut target = Self {
+            emulator: Emulator::new(assembly.words),
+            entries: source_map.entry_indexes(&assembly.lines),
+            source_map,
+            instructions,
+        };
+        if !bootstrap {
+            target.emulator.ram[SP] = STACK_BASE;
+        }
+        if !target.is_start(target.emulator.pc) {
+            target.step()?;
+        }
+        Ok(target)
+    }
+
+    fn entry(&self, pc: usize) -> Option<usize> {
+        self.entries.get(pc).copied().flatten()
+    }
+
+    /// Whether `pc` is the first instruction of a VM command.
+    fn is_start(&self, pc: u16) -> bool {
+        let pc = pc as usize;
+        self.entry(pc)
+            .is_some_and(|entry| pc == 0 || self.entry(pc - 1) != Some(entry))
+    }
+}
+
+impl Target for HackTarget {
+    fn ram(&self) -> &[i16] {
+        &self.emulator.ram
+    }
+
+    fn position_at(&self, address: usize) -> Option<Position> {
+        self.entry(address)
+            .map(|entry| Position::from(&self.source_map.entries[entry]))
+    }
+
+    fn address(&self) -> usize {
+        self.emulator.pc as usize
+    }
+
+    fn resolve(&self, file: &str, line: usize) -> Option<usize> {
+        (0..self.entries.len())
+            .filter(|&pc| self.is_start(pc as u16))
+            .filter_map(|pc| self.position_at(pc).map(|position| (pc, position)))
+            .filter(|(_, position)| position.file == file && position.line >= line)
+            .min_by_key(|(_, position)| position.line)
+            .map(|(pc, _)| pc)
+    }
+
+    fn step(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        loop {
+            if let Some(stop) = self.step_instruction()? {
+                return Ok(Some(stop));
+            }
+            if self.is_start(self.emulator.pc) {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn step_instruction(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        if self.emulator.cycles >= emulator::DEFAULT_MAX_CYCLES {
+            return Ok(Some(emulator::Stop::CycleLimit.to_string()));
+        }
+        Ok(self.emulator.step()?.map(|stop| stop.to_string()))
+    }
+
+    fn instruction(&self) -> Option<String> {
+        let pc = self.emulator.pc;
+        let instruction = self.instructions.get(pc as usize)?;
+        (!self.is_start(pc)).then(|| format!("ROM[{pc}]: {instruction}"))
+    }
+
+    fn time(&self) -> String {
+        format!("{} cycles", self.emulator.cycles)
+    }
+}
+
+/// A frame of the call stack.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Frame {
+    pub function: String,
+    /// Where the function is, the next command for the innermost frame, the `call` for the
+    /// others.
+    pub position: Option<Position>,
+    pub lcl: i16,
+    pub arg: i16,
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)?;
+        if let Some(position) = &self.position {
+            write!(
+                f,
+                " at {}:{}: {}",
+                position.file, position.line, position.command
+            )?;
+        }
+        write!(f, " (LCL={}, ARG={})", self.lcl, self.arg)
+    }
+}
+
+/// The call stack of a target, innermost frame first.
+pub fn backtrace(target: &dyn Target) -> Vec<Frame> {
+    call_stack(target.ram(), target.position(), |address| {
+        target.position_at(address)
+    })
+}
+
+/// The call stack, innermost frame first, rebuilt from the frames `call` saves under `LCL`:
+/// the return address, then the `LCL`, `ARG`, `THIS` and `THAT` of the caller.
+///
+/// `position` is where the innermost function is, and `position_at` gives the VM command of
+/// an instruction address. A return address is right after the instruction of its `call`,
+/// the jump of the call code on the emulator.
+pub fn call_stack(
+    ram: &[i16],
+    position: Option<Position>,
+    position_at: impl Fn(usize) -> Option<Position>,
+) -> Vec<Frame> {
+    let caller = |return_address: i16| {
+        position_at((return_address as u16 as usize).checked_sub(1)?)
+            .filter(|position| position.command.starts_with("call "))
+    };
+    let mut frames = vec![Frame {
+        function: position
+            .as_ref()
+            .map(|position| position.function.clone())
+            .unwrap_or_default(),
+        position,
+        lcl: ram[LCL],
+        arg: ram[ARG],
+    }];
+    let mut lcl = ram[LCL] as u16 as usize;
+    while lcl >= STACK_BASE as usize + 5 && lcl <= ram.len() && frames.len() < ram.len() {
+        let Some(call) = caller(ram[lcl - 5]) else {
+            break;
+        };
+        frames.push(Frame {
+            function: call.function.clone(),
+            position: Some(call),
+            lcl: ram[lcl - 4],
+            arg: ram[lcl - 3],
+        });
+        lcl = ram[lcl - 4] as u16 as usize;
+    }
+    frames
+}
+
+/// A breakpoint as given and where it stops.
+struct Breakpoint {
+    spec: String,
+    file: String,
+    line: usize,
+    address: usize,
+}
+
+/// The debugger of a program running on a target.
+pub struct Debugger<'a> {
+    program: &'a Program,
+    target: Box<dyn Target + 'a>,
+    breakpoints: Vec<Option<Breakpoint>>,
+    /// Why the program stopped, once it did.
+    stop: Option<String>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a Program, target: Box<dyn Target + 'a>) -> Self {
+        Self {
+            program,
+            target,
+            breakpoints: vec![],
+            stop: None,
+        }
+    }
+
+    /// Reads commands until `quit` or the end of the input.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> Result<(), Box<dyn Error>> {
+        self.show_position(&mut out)?;
+        write!(out, "(debug) ")?;
+        out.flush()?;
+        let mut last = String::new();
+        for line in input.lines() {
+            let line = line?;
+            let line = if line.trim().is_empty() {
+                last.clone()
+            } else {
+                line.trim().to_string()
+            };
+            match self.command(&line, &mut out) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => writeln!(out, "error: {e}")?,
+            }
+            last = line;
+            write!(out, "(debug) ")?;
+            out.flush()?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    /// Executes a command, `true` for `quit`.
+    fn command(&mut self, line: &str, out: &mut impl Write) -> Result<bool, Box<dyn Error>> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let arg = |i: usize| {
+            words
+                .get(i)
+                .copied()
+                .ok_or(format!("`{}` need an argument", words[0]))
+        };
+        match words.first().copied().unwrap_or_default() {
+            "" => {}
+            "break" | "b" => {
+                let spec = arg(1)?;
+                let (file, line) = self.find(spec)?;
+                let address = self
+                    .target
+                    .resolve(&file, line)
+                    .ok_or(format!("{file}:{line} executes no instruction"))?;
+                self.breakpoints.push(Some(Breakpoint {
+                    spec: spec.to_string(),
+                    file,
+                    line,
+                    address,
+                }));
+                writeln!(out, "breakpoint {} at {spec}", self.breakpoints.len())?;
+            }
+            "delete" | "d" => {
+                let n = arg(1)?;
+                let breakpoint = n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.breakpoints.get_mut(n.checked_sub(1)?))
+                    .filter(|breakpoint| breakpoint.is_some())
+                    .ok_or(format!("no breakpoint {n}"))?;
+                *breakpoint = None;
+            }
+            "breakpoints" => {
+                for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+                    if let Some(breakpoint) = breakpoint {
+                        writeln!(
+                            out,
+                            "{}: {} ({}:{})",
+                            i + 1,
+                            breakpoint.spec,
+                            breakpoint.file,
+                            breakpoint.line
+                        )?;
+                    }
+                }
+            }
+            "continue" | "c" => self.run(out, |_| false)?,
+            "step" | "s" => self.run(out, |_| true)?,
+            "stepi" | "si" => {
+                if self.stop.is_none() {
+                    self.stop = self.target.step_instruction()?;
+                }
+                self.show_position(out)?;
+            }
+            "next" | "n" => {
+                let depth = backtrace(&*self.target).len();
+                self.run(out, |target| backtrace(target).len() <= depth)?;
+            }
+            "finish" => {
+                let depth = backtrace(&*self.target).len();
+                self.run(out, |target| backtrace(target).len() < depth)?;
+            }
+            "frame" | "f" => self.show_frame(out)?,
+            "backtrace" | "bt" => {
+                for (i, frame) in backtrace(&*self.target).iter().enumerate() {
+                    writeln!(out, "#{i} {frame}")?;
+                }
+            }
+            "ram" | "x" => {
+                let range = arg(1)?;
+                let error = || format!("`{range}` is not a RAM address or a range like `256..260`");
+                let address = |n: &str| n.parse::<usize>().map_err(|_| error());
+                let range = match range.split_once("..") {
+                    Some((start, end)) => address(start)?..address(end)?,
+                    None => address(range)?..address(range)? + 1,
+                };
+                if range.end > self.target.ram().len() {
+                    return Err(error().into());
+                }
+                for address in range {
+                    writeln!(out, "RAM[{address}] = {}", self.target.ram()[address])?;
+                }
+            }
+            "help" | "h" => writeln!(out, "{HELP}")?,
+            "quit" | "q" => return Ok(true),
+            command => return Err(format!("unknown command `{command}`, see `help`").into()),
+        }
+        Ok(false)
+    }
+
+    /// Executes VM commands until `done` holds, a breakpoint is hit or the program stops.
+    fn run(
+        &mut self,
+        out: &mut impl Write,
+        done: impl Fn(&dyn Target) -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        while self.stop.is_none() {
+            self.stop = self.target.step()?;
+            if self.stop.is_some() || done(&*self.target) {
+                break;
+            }
+            let address = self.target.address();
+            if let Some(i) = self.breakpoints.iter().position(|breakpoint| {
+                breakpoint
+                    .as_ref()
+                    .is_some_and(|breakpoint| breakpoint.address == address)
+            }) {
+                writeln!(out, "breakpoint {}", i + 1)?;
+                break;
+            }
+        }
+        self.show_position(out)
+    }
+
+    fn show_position(&self, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        if let Some(stop) = &self.stop {
+            writeln!(out, "stopped after {}: {stop}", self.target.time())?;
+            return Ok(());
+        }
+        match (self.target.instruction(), self.target.position()) {
+            (Some(instruction), Some(position)) => writeln!(out, "{instruction} in {position}")?,
+            (Some(instruction), None) => writeln!(out, "{instruction}")?,
+            (None, Some(position)) => writeln!(out, "{position}")?,
+            (None, None) => writeln!(out, "outside of the VM commands")?,
+        }
+        Ok(())
+    }
+
+    /// The segments of the current function and its operand stack.
+    fn show_frame(&self, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let ram = self.target.ram();
+        let word = |address: i16| {
+            ram.get(address as u16 as usize)
+                .copied()
+                .unwrap_or_default()
+        };
+        let words = |start: i16, count: i16| {
+            (0..count.max(0))
+                .map(|i| word(start.wrapping_add(i)).to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let function = self
+            .target
+            .position()
+            .map(|position| position.function)
+            .unwrap_or_default();
+        let n_locals = self.n_locals(&function);
+        writeln!(
+            out,
+            "{} SP={} LCL={} ARG={} THIS={} THAT={}",
+            if function.is_empty() {
+                "(no function)"
+            } else {
+                &function
+            },
+            ram[SP],
+            ram[LCL],
+            ram[ARG],
+            ram[THIS],
+            ram[THAT]
+        )?;
+        let stack_base = match n_locals {
+            Some(n_locals) => {
+                // the frame saved by `call` is between the arguments and the locals
+                writeln!(
+                    out,
+                    "argument: {}",
+                    words(ram[ARG], ram[LCL].wrapping_sub(5).wrapping_sub(ram[ARG]))
+                )?;
+                writeln!(out, "local: {}", words(ram[LCL], n_locals as i16))?;
+                ram[LCL].wrapping_add(n_locals as i16)
+            }
+            None => STACK_BASE,
+        };
+        for (name, pointer) in [("this", THIS), ("that", THAT)] {
+            match ram[pointer] {
+                0 => writeln!(out, "{name}: not set")?,
+                base => writeln!(out, "{name}: {} ...", words(base, POINTED_WORDS as i16))?,
+            }
+        }
+        writeln!(
+            out,
+            "stack: {}",
+            words(stack_base, ram[SP].wrapping_sub(stack_base))
+        )?;
+        Ok(())
+    }
+
+    fn n_locals(&self, function: &str) -> Option<u16> {
+        self.program
+            .files
+            .iter()
+            .flat_map(|file| &file.instructions)
+            .find_map(|instruction| match &instruction.command {
+                Command::Function(name, n_locals) if name == function => Some(*n_locals),
+                _ => None,
+            })
+    }
+
+    /// The VM command of a breakpoint, `<function>`, `<function>:<label>` or `<file>:<line>`.
+    /// A bare name which isn't a function is a label of any function.
+    fn find(&self, spec: &str) -> Result<(String, usize), String> {
+        let (name, label) = match spec.rsplit_once(':') {
+            Some((file, line)) if line.parse::<usize>().is_ok() => {
+                let file = if file.ends_with(".vm") {
+                    file.to_string()
+                } else {
+                    format!("{file}.vm")
+                };
+                return Ok((file, line.parse().unwrap_or_default()));
+            }
+            Some((function, label)) => (Some(function), Some(label)),
+            None => (Some(spec), None),
+        };
+        let mut functions = BTreeSet::new();
+        for file in &self.program.files {
+            let mut function = String::new();
+            for instruction in &file.instructions {
+                let found = match &instruction.command {
+                    Command::Function(f, _) => {
+                        function = f.clone();
+                        functions.insert(f.clone());
+                        label.is_none() && name == Some(f.as_str())
+                    }
+                    Command::Label(l) => match label {
+                        Some(label) => l == label && name == Some(function.as_str()),
+                        None => false,
+                    },
+                    _ => false,
+                };
+                if found {
+                    return Ok((file.name.clone(), instruction.line));
+                }
+            }
+        }
+        if label.is_none() {
+            // a bare label
+            for file in &self.program.files {
+                for instruction in &file.instructions {
+                    if instruction.command == Command::Label(spec.to_string()) {
+                        return Ok((file.name.clone(), instruction.line));
+                    }
+                }
+            }
+        }
+        Err(format!("no function or label `{spec}`"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    const FIBONACCI: &str = "\
+function Sys.init 0
+push constant 4
+call Main.fibonacci 1
+pop static 0
+label END
+goto END
+function Main.fibonacci 0
+push argument 0
+push constant 2
+lt
+if-goto BASE
+push argument 0
+push constant 2
+sub
+call Main.fibonacci 1
+push argument 0
+push constant 1
+sub
+call Main.fibonacci 1
+add
+return
+label BASE
+push argument 0
+return
+";
+
+    /// The output of a debugging session on both targets.
+    fn debug(name: &str, commands: &str) -> [String; 2] {
+        let path = PathBuf::from(format!("./test_debugger_{name}.vm"));
+        fs::write(&path, FIBONACCI).unwrap();
+        let program = Program::load(&[&path]).unwrap();
+        let targets: [Box<dyn Target>; 2] = [
+            Box::new(VmTarget::new(&program).unwrap()),
+            Box::new(HackTarget::new(std::slice::from_ref(&path), &program).unwrap()),
+        ];
+        fs::remove_file(Path::new(&path)).unwrap();
+        targets.map(|target| {
+            let mut out = vec![];
+            Debugger::new(&program, target)
+                .repl(commands.as_bytes(), &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        })
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        for out in debug("breakpoints", "b Main.fibonacci:BASE\nc\nbt\nf\nq\n") {
+            assert!(out.contains("breakpoint 1 at Main.fibonacci:BASE"), "{out}");
+            // the label on the interpreter, the next command on the emulator
+            let after = &out[out.find("breakpoint 1\n").unwrap()..];
+            assert!(
+                after.contains("Main.fibonacci: label BASE\n")
+                    || after.contains(":23 Main.fibonacci: push argument 0\n"),
+                "{out}"
+            );
+            // fib(4) -> fib(2) -> fib(0)
+            assert!(
+                after.contains(
+                    "#2 Main.fibonacci at test_debugger_breakpoints.vm:15: call Main.fibonacci 1"
+                ),
+                "{out}"
+            );
+            assert!(
+                after.contains(
+                    "#3 Sys.init at test_debugger_breakpoints.vm:3: call Main.fibonacci 1"
+                ),
+                "{out}"
+            );
+            assert!(!after.contains("#4"), "{out}");
+            assert!(after.contains("argument: 0\n"), "{out}");
+        }
+    }
+
+    #[test]
+    fn test_frame_of_far_pointers() {
+        // LCL = 32767 + 1, written through `that`
+        let path = PathBuf::from("./test_debugger_far_pointers.vm");
+        fs::write(
+            &path,
+            "function Sys.init 0\npush constant 1\npop pointer 1\n\
+             push constant 32767\npush constant 1\nadd\npop that 0\n\
+             label END\ngoto END\n",
+        )
+        .unwrap();
+        let program = Program::load(&[&path]).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut out = vec![];
+        Debugger::new(&program, Box::new(VmTarget::new(&program).unwrap()))
+            .repl("b END\nc\nf\nq\n".as_bytes(), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("LCL=-32768"), "{out}");
+        assert!(out.contains("stack: "), "{out}");
+    }
+
+    #[test]
+    fn test_next_and_finish() {
+        for out in debug("next", "b test_debugger_next:3\nc\nn\ns\nbt\nfinish\n\nq\n") {
+            let after = &out[out.find("breakpoint 1\n").unwrap()..];
+            assert!(
+                after.contains(":3 Sys.init: call Main.fibonacci 1\n"),
+                "{out}"
+            );
+            assert!(after.contains(":4 Sys.init: pop static 0\n"), "{out}");
+            assert!(after.contains("#0 Sys.init"), "{out}");
+            assert!(
+                after.ends_with("the program reached its end loop\n(debug) ")
+                    || after.ends_with("the program loops forever on a goto\n(debug) "),
+                "{out}"
+            );
+        }
+        // stepi executes Hack instructions inside a VM command
+        let [vm, hack] = debug("stepi", "si\nsi\nq\n");
+        assert!(
+            vm.ends_with("Sys.init: call Main.fibonacci 1\n(debug) "),
+            "{vm}"
+        );
+        assert!(hack.contains("ROM["), "{hack}");
+    }
+}
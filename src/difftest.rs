@@ -0,0 +1,522 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    error::Error,
+    fmt,
+    path::PathBuf,
+};
+
+use crate::{
+    backend::static_symbol,
+    emulator::Emulator,
+    hack_asm::{assemble, Assembly},
+    interpreter::{Interpreter, Location, Stop, STACK_BASE},
+    ir::{Command, Program, Segment},
+    source_map::SourceMap,
+    translate::translate_to_hack,
+};
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const TEMP_BASE: usize = 5;
+const HEAP: std::ops::Range<usize> = 2048..16384;
+
+/// A RAM word with different values on the interpreter and the emulator.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Difference {
+    /// What the word is, like `SP`, `stack RAM[258]` or `static Main.vm.0`.
+    pub name: String,
+    pub vm: i16,
+    pub hack: i16,
+}
+
+/// Where the two runs diverged.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Divergence {
+    /// The last VM instruction the interpreter executed, `None` before the first one.
+    pub location: Option<Location>,
+    pub message: String,
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(
+                f,
+                "{}:{}: {} ({})",
+                location.file, location.line, self.message, location.function
+            )?,
+            None => write!(f, "{}", self.message)?,
+        }
+        for difference in &self.differences {
+            write!(
+                f,
+                "\n  {}: interpreter {}, hack {}",
+                difference.name, difference.vm, difference.hack
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of a differential test.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Outcome {
+    Same {
+        steps: u64,
+        cycles: u64,
+        /// Return boundaries the states were compared at.
+        returns: u64,
+        stop: Stop,
+    },
+    Diverged(Divergence),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Same {
+                steps,
+                cycles,
+                returns,
+                stop,
+            } => write!(
+                f,
+                "no divergence, {steps} VM steps and {cycles} Hack cycles, \
+                 states compared at {returns} returns and at the end: {stop}"
+            ),
+            Outcome::Diverged(divergence) => write!(f, "divergence after {divergence}"),
+        }
+    }
+}
+
+/// The Hack translation of a program, with every ROM address mapped to its VM command.
+struct Translation {
+    rom: Vec<u16>,
+    assembly: Assembly,
+    source_map: SourceMap,
+    /// Source map entry of every ROM address, `None` for the bootstrap code.
+    entries: Vec<Option<usize>>,
+    /// `file` and `line` of the VM commands with instructions.
+    code_lines: HashSet<(String, usize)>,
+    bootstrap: bool,
+}
+
+impl Translation {
+    fn new(files: &[PathBuf], bootstrap: bool) -> Result<Self, Box<dyn Error>> {
+        let (asm, source_map) = translate_to_hack(files, bootstrap)?;
+        let assembly = assemble(&asm)?;
+        let entries = source_map.entry_indexes(&assembly.lines);
+        let code_lines = entries
+            .iter()
+            .flatten()
+            .map(|&entry| {
+                let location = &source_map.entries[entry].location;
+                (location.file.clone(), location.line)
+            })
+            .collect();
+        Ok(Self {
+            rom: assembly.words.clone(),
+            assembly,
+            source_map,
+            entries,
+            code_lines,
+            bootstrap,
+        })
+    }
+
+    fn entry(&self, pc: u16) -> Option<usize> {
+        self.entries.get(pc as usize).copied().flatten()
+    }
+
+    /// Whether `pc` is the first instruction of a VM command.
+    fn is_start(&self, pc: u16) -> bool {
+        self.entry(pc)
+            .is_some_and(|entry| pc == 0 || self.entry(pc - 1) != Some(entry))
+    }
+
+    /// `file` and `line` of the VM command at `pc`.
+    fn source(&self, pc: u16) -> Option<(&str, usize)> {
+        self.entry(pc).map(|entry| {
+            let location = &self.source_map.entries[entry].location;
+            (location.file.as_str(), location.line)
+        })
+    }
+
+    fn is_return(&self, pc: u16) -> bool {
+        self.entry(pc).is_some_and(|entry| {
+            Command::parse(&self.source_map.entries[entry].location.command) == Ok(Command::Return)
+        })
+    }
+
+    /// Whether a VM command has Hack instructions, labels and functions without locals don't.
+    fn has_code(&self, location: &Location) -> bool {
+        self.code_lines
+            .contains(&(location.file.clone(), location.line))
+    }
+}
+
+/// The interpreter and the emulator running the same program.
+struct Runs<'a> {
+    program: &'a Program,
+    translation: &'a Translation,
+    vm: Interpreter,
+    hack: Emulator,
+    max_steps: u64,
+    max_cycles: u64,
+    /// Instruction the interpreter executed last.
+    last: Option<usize>,
+}
+
+impl<'a> Runs<'a> {
+    fn new(
+        program: &'a Program,
+        translation: &'a Translation,
+        max_steps: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut vm = Interpreter::new(program)?;
+        vm.bootstrap()?;
+        let mut hack = Emulator::new(translation.rom.clone());
+        if !translation.bootstrap {
+            hack.ram[SP] = STACK_BASE;
+        }
+        Ok(Self {
+            program,
+            translation,
+            vm,
+            hack,
+            max_steps,
+            // a VM command is translated to less than 100 Hack instructions
+            max_cycles: max_steps.saturating_mul(100),
+            last: None,
+        })
+    }
+
+    fn divergence(&self, message: String, differences: Vec<Difference>) -> Outcome {
+        Outcome::Diverged(Divergence {
+            location: self.last.and_then(|pc| self.vm.location(pc)).cloned(),
+            message,
+            differences,
+        })
+    }
+
+    /// Executes an interpreter instruction, `Some` when the program stopped.
+    fn step_vm(&mut self) -> Result<Option<Stop>, Box<dyn Error>> {
+        if self.vm.steps >= self.max_steps {
+            return Ok(Some(Stop::StepLimit));
+        }
+        let pc = self.vm.pc;
+        let stop = self.vm.step()?;
+        if stop.is_none() {
+            self.last = Some(pc);
+        }
+        Ok(stop)
+    }
+
+    /// Executes emulator instructions until `done` holds for the program counter, `false`
+    /// when the emulator stops before.
+    fn run_hack_until(
+        &mut self,
+        mut done: impl FnMut(&Translation, u16) -> bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        loop {
+            if self.hack.cycles >= self.max_cycles || self.hack.step()?.is_some() {
+                return Ok(done(self.translation, self.hack.pc));
+            }
+            if done(self.translation, self.hack.pc) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Compares what the VM program can see: the pointers, the stack without the saved return
+    /// addresses, the statics and temps it uses, and the heap when `heap` is set.
+    fn compare(&self, heap: bool) -> Vec<Difference> {
+        let (vm, hack) = (&self.vm.ram, &self.hack.ram);
+        let mut words = vec![];
+        for (i, name) in ["SP", "LCL", "ARG", "THIS", "THAT"].iter().enumerate() {
+            words.push((name.to_string(), i, i));
+        }
+
+        // return addresses are instruction indexes on one side and ROM addresses on the other
+        let mut return_addresses = BTreeSet::new();
+        let mut frame = vm[LCL] as usize;
+        while frame >= STACK_BASE as usize + 5 && frame <= vm.len() {
+            return_addresses.insert(frame - 5);
+            frame = vm[frame - 4] as u16 as usize;
+        }
+        let sp = (vm[SP] as u16 as usize).min(vm.len());
+        for address in STACK_BASE as usize..sp {
+            if !return_addresses.contains(&address) {
+                words.push((format!("stack RAM[{address}]"), address, address));
+            }
+        }
+
+        let mut statics = BTreeSet::new();
+        let mut temps = BTreeSet::new();
+        for file in &self.program.files {
+            for instruction in &file.instructions {
+                match instruction.command {
+                    Command::Push(Segment::Static, i) | Command::Pop(Segment::Static, i) => {
+                        statics.insert((file.name.as_str(), i));
+                    }
+                    Command::Push(Segment::Temp, i) | Command::Pop(Segment::Temp, i) => {
+                        temps.insert(i as usize);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (file, i) in statics {
+            let symbol = static_symbol(file, i as i32);
+            if let (Some(vm_address), Some(hack_address)) = (
+                self.vm.static_address(file, i),
+                self.translation.assembly.symbol(&symbol),
+            ) {
+                words.push((
+                    format!("static {symbol}"),
+                    vm_address as usize,
+                    hack_address as usize,
+                ));
+            }
+        }
+        for i in temps {
+            words.push((format!("temp {i}"), TEMP_BASE + i, TEMP_BASE + i));
+        }
+        if heap {
+            for address in HEAP {
+                words.push((format!("heap RAM[{address}]"), address, address));
+            }
+        }
+
+        words
+            .into_iter()
+            .filter(|(_, vm_address, hack_address)| vm[*vm_address] != hack[*hack_address])
+            .map(|(name, vm_address, hack_address)| Difference {
+                name,
+                vm: vm[vm_address],
+                hack: hack[hack_address],
+            })
+            .collect()
+    }
+
+    /// Runs both sides from return to return, and compares them at every return and at the
+    /// end. `None` when they diverged.
+    fn compare_at_returns(&mut self) -> Result<Outcome, Box<dyn Error>> {
+        let mut returns = 0;
+        loop {
+            // interpreter to its next return, counting the commands with instructions
+            let mut executed = 0;
+            let stop = loop {
+                let location = self.vm.location(self.vm.pc);
+                let is_return =
+                    location.is_some_and(|location| location.command == Command::Return);
+                let has_code = location.is_some_and(|location| self.translation.has_code(location));
+                if let Some(stop) = self.step_vm()? {
+                    break Some(stop);
+                }
+                if has_code {
+                    executed += 1;
+                }
+                if is_return {
+                    break None;
+                }
+            };
+
+            let Some(stop) = stop else {
+                // emulator out of the code of its next return
+                // the return address can be the code of another `return`
+                let reached = self.run_hack_until(|t, pc| t.is_return(pc))?
+                    && self.run_hack_until(|t, pc| t.is_start(pc) || t.entry(pc).is_none())?;
+                if !reached {
+                    return Ok(self.divergence(
+                        "the Hack program stopped before returning".to_string(),
+                        vec![],
+                    ));
+                }
+                returns += 1;
+                let differences = self.compare(false);
+                if !differences.is_empty() {
+                    return Ok(
+                        self.divergence("the states differ after return".to_string(), differences)
+                    );
+                }
+                continue;
+            };
+
+            // emulator to where the interpreter stopped, in a loop the step limit can stop it
+            // at any pass over a command, which the count of commands since the return gives
+            if stop == Stop::StepLimit {
+                let mut starts = executed + !self.translation.is_start(self.hack.pc) as u64;
+                if starts > 0 {
+                    self.run_hack_until(|t, pc| {
+                        starts -= t.is_start(pc) as u64;
+                        starts == 0
+                    })?;
+                }
+            } else if let Some(location) = self.vm.location(self.vm.pc).cloned() {
+                let target = (location.file.as_str(), location.line);
+                self.run_hack_until(|t, pc| t.is_start(pc) && t.source(pc) == Some(target))?;
+            }
+            let differences = self.compare(stop != Stop::StepLimit);
+            if !differences.is_empty() {
+                return Ok(self.divergence("the states differ at the end".to_string(), differences));
+            }
+            return Ok(Outcome::Same {
+                steps: self.vm.steps,
+                cycles: self.hack.cycles,
+                returns,
+                stop,
+            });
+        }
+    }
+
+    /// Runs both sides in lockstep, one VM command at a time, and compares them after every
+    /// command. `None` when no divergence was found.
+    fn compare_every_command(&mut self) -> Result<Option<Outcome>, Box<dyn Error>> {
+        // the bootstrap code
+        if !self.run_hack_until(|t, pc| t.is_start(pc))? {
+            return Ok(None);
+        }
+        loop {
+            // commands without code, like labels, have nothing to compare
+            while self
+                .vm
+                .location(self.vm.pc)
+                .is_some_and(|location| !self.translation.has_code(location))
+            {
+                if self.step_vm()?.is_some() {
+                    return Ok(None);
+                }
+            }
+            let Some(location) = self.vm.location(self.vm.pc).cloned() else {
+                return Ok(None);
+            };
+            if self.translation.source(self.hack.pc) != Some((&location.file, location.line)) {
+                let message = match self.translation.entry(self.hack.pc) {
+                    Some(entry) => {
+                        let hack = &self.translation.source_map.entries[entry].location;
+                        format!(
+                            "the Hack program went to {}:{} instead of {}:{}",
+                            hack.file, hack.line, location.file, location.line
+                        )
+                    }
+                    None => "the Hack program left the translated commands".to_string(),
+                };
+                return Ok(Some(self.divergence(message, vec![])));
+            }
+            let differences = self.compare(false);
+            if !differences.is_empty() {
+                return Ok(Some(
+                    self.divergence("the states differ".to_string(), differences),
+                ));
+            }
+
+            if self.step_vm()?.is_some() || !self.run_hack_until(|t, pc| t.is_start(pc))? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Runs a program on the interpreter and on the emulator executing its Hack translation, and
+/// finds where they diverge.
+///
+/// The states are compared at every return and at the end, so the translation is free to
+/// keep them differently inside functions. After a divergence both sides run again in
+/// lockstep to find the first VM command after which the states differ.
+pub fn difftest(files: &[PathBuf], max_steps: u64) -> Result<Outcome, Box<dyn Error>> {
+    let program = Program::load(files)?;
+    let translation = Translation::new(files, program.defines("Sys.init"))?;
+
+    let outcome = Runs::new(&program, &translation, max_steps)?.compare_at_returns()?;
+    if let Outcome::Diverged(_) = outcome {
+        if let Some(first) =
+            Runs::new(&program, &translation, max_steps)?.compare_every_command()?
+        {
+            return Ok(first);
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    fn difftest_program(name: &str, lines: &[&str]) -> Result<Outcome, Box<dyn Error>> {
+        let path = PathBuf::from(format!("./test_difftest_{name}.vm"));
+        fs::write(&path, lines.join("\n"))?;
+        let outcome = difftest(std::slice::from_ref(&path), 10_000);
+        fs::remove_file(Path::new(&path))?;
+        outcome
+    }
+
+    #[test]
+    fn test_same() -> Result<(), Box<dyn Error>> {
+        let outcome = difftest_program(
+            "same",
+            &[
+                "function Sys.init 0",
+                "push constant 6",
+                "call Main.double 1",
+                "pop static 0",
+                "label END",
+                "goto END",
+                "function Main.double 1",
+                "push argument 0",
+                "pop local 0",
+                "push local 0",
+                "push local 0",
+                "add",
+                "return",
+            ],
+        )?;
+        assert!(
+            matches!(
+                outcome,
+                Outcome::Same {
+                    returns: 1,
+                    stop: Stop::Loop,
+                    ..
+                }
+            ),
+            "{outcome}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diverged() -> Result<(), Box<dyn Error>> {
+        // `that` reads R13, which the Hack translation reserves for the `y` of comparisons
+        let outcome = difftest_program(
+            "diverged",
+            &[
+                "function Sys.init 0",
+                "push constant 13",
+                "pop pointer 1",
+                "push constant 2",
+                "push constant 3",
+                "lt",
+                "pop static 0",
+                "push that 0",
+                "pop static 1",
+                "return",
+            ],
+        )?;
+        let Outcome::Diverged(divergence) = outcome else {
+            panic!("{outcome}");
+        };
+        let location = divergence.location.unwrap();
+        assert_eq!(location.line, 8);
+        assert_eq!(location.command, Command::Push(crate::ir::Segment::That, 0));
+        assert_eq!(divergence.differences[0].name, "stack RAM[261]");
+        assert_eq!(
+            (divergence.differences[0].vm, divergence.differences[0].hack),
+            (0, 3)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use std::{error::Error, fmt};
+
+/// Words of RAM.
+pub const RAM_SIZE: usize = 32768;
+/// Cycles `run` executes when no limit is given.
+pub const DEFAULT_MAX_CYCLES: u64 = 100_000_000;
+
+/// Why the emulator stopped.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop {
+    /// The program jumped to an `@LOOP` instruction right before the jump, like the
+    /// `(END)` loop of the translated programs.
+    Loop,
+    /// The program counter went past the last instruction.
+    End,
+    CycleLimit,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Stop::Loop => "the program reached its end loop",
+            Stop::End => "the program ran past its last instruction",
+            Stop::CycleLimit => "the cycle limit was reached",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// An error at the ROM address `pc`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EmulatorError {
+    pub pc: u16,
+    pub message: String,
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ROM[{}]: {}", self.pc, self.message)
+    }
+}
+
+impl Error for EmulatorError {}
+
+/// The Hack CPU with its ROM and RAM.
+pub struct Emulator {
+    pub a: i16,
+    pub d: i16,
+    pub pc: u16,
+    pub ram: Vec<i16>,
+    pub rom: Vec<u16>,
+    /// Instructions executed so far.
+    pub cycles: u64,
+}
+
+impl Emulator {
+    /// Loads machine code, the registers and the RAM are zeroed.
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            a: 0,
+            d: 0,
+            pc: 0,
+            ram: vec![0; RAM_SIZE],
+            rom,
+            cycles: 0,
+        }
+    }
+
+    /// Executes instructions until the program stops or `max_cycles` instructions were
+    /// executed by this call.
+    pub fn run(&mut self, max_cycles: u64) -> Result<Stop, EmulatorError> {
+        for _ in 0..max_cycles {
+            if let Some(stop) = self.step()? {
+                return Ok(stop);
+            }
+        }
+        Ok(Stop::CycleLimit)
+    }
+
+    /// Executes the next instruction, and tells whether the program stopped.
+    pub fn step(&mut self) -> Result<Option<Stop>, EmulatorError> {
+        let Some(&instruction) = self.rom.get(self.pc as usize) else {
+            return Ok(Some(Stop::End));
+        };
+        self.cycles += 1;
+
+        // A-instruction
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+            return Ok(None);
+        }
+
+        let y = if instruction & 0x1000 == 0 {
+            self.a
+        } else {
+            self.read(self.a)?
+        };
+        let out = alu(self.d, y, (instruction >> 6) as u8 & 0b111111);
+        let jump = (instruction & 0b111) as u8;
+        let taken = match out {
+            out if out < 0 => jump & 0b100 != 0,
+            0 => jump & 0b010 != 0,
+            _ => jump & 0b001 != 0,
+        };
+        let address = self.a;
+        if instruction & 0b001_000 != 0 {
+            self.write(address, out)?;
+        }
+        if instruction & 0b100_000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b010_000 != 0 {
+            self.d = out;
+        }
+
+        if !taken {
+            self.pc += 1;
+            return Ok(None);
+        }
+        let target = address as u16;
+        // `(LOOP) @LOOP 0;JMP`
+        if target.wrapping_add(1) == self.pc && self.rom.get(target as usize) == Some(&target) {
+            self.pc = target;
+            return Ok(Some(Stop::Loop));
+        }
+        self.pc = target;
+        Ok(None)
+    }
+
+    fn read(&self, address: i16) -> Result<i16, EmulatorError> {
+        self.ram
+            .get(address as u16 as usize)
+            .copied()
+            .ok_or_else(|| self.error(address))
+    }
+
+    fn write(&mut self, address: i16, value: i16) -> Result<(), EmulatorError> {
+        let error = self.error(address);
+        let word = self.ram.get_mut(address as u16 as usize).ok_or(error)?;
+        *word = value;
+        Ok(())
+    }
+
+    fn error(&self, address: i16) -> EmulatorError {
+        EmulatorError {
+            pc: self.pc,
+            message: format!("RAM[{}] is out of the {RAM_SIZE} words RAM", address as u16),
+        }
+    }
+}
+
+/// The Hack ALU, `control` is the `zx nx zy ny f no` bits.
+fn alu(x: i16, y: i16, control: u8) -> i16 {
+    let x = if control & 0b100000 != 0 { 0 } else { x };
+    let x = if control & 0b010000 != 0 { !x } else { x };
+    let y = if control & 0b001000 != 0 { 0 } else { y };
+    let y = if control & 0b000100 != 0 { !y } else { y };
+    let out = if control & 0b000010 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if control & 0b000001 != 0 {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hack_asm::assemble;
+
+    use super::*;
+
+    fn load(asm: &str) -> Emulator {
+        Emulator::new(assemble(asm).unwrap().words)
+    }
+
+    #[test]
+    fn test_alu() {
+        // computations of `comp_bits` in `hack_asm`
+        assert_eq!(alu(5, 3, 0b101010), 0);
+        assert_eq!(alu(5, 3, 0b111010), -1);
+        assert_eq!(alu(5, 3, 0b001111), -5);
+        assert_eq!(alu(5, 3, 0b110010), 2);
+        assert_eq!(alu(5, 3, 0b010011), 2);
+        assert_eq!(alu(5, 3, 0b000111), -2);
+        assert_eq!(alu(5, 3, 0b010101), 7);
+        assert_eq!(alu(i16::MAX, 1, 0b000010), i16::MIN);
+    }
+
+    #[test]
+    fn test_run() {
+        // RAM[2] = RAM[0] * RAM[1]
+        let mut emulator = load(
+            "@2\nM=0\n\
+             (LOOP)\n@1\nD=M\n@END\nD;JEQ\n\
+             @0\nD=M\n@2\nM=D+M\n@1\nM=M-1\n@LOOP\n0;JMP\n\
+             (END)\n@END\n0;JMP\n",
+        );
+        emulator.ram[0] = 6;
+        emulator.ram[1] = 7;
+        assert_eq!(emulator.run(1000), Ok(Stop::Loop));
+        assert_eq!(emulator.ram[2], 42);
+        assert_eq!(emulator.pc, 14);
+
+        let mut emulator = load("@0\nD=A\n");
+        assert_eq!(emulator.run(1000), Ok(Stop::End));
+        assert_eq!(emulator.cycles, 2);
+
+        let mut emulator = load("(LOOP)\n@LOOP\nD;JEQ\n");
+        emulator.d = 1;
+        assert_eq!(emulator.run(10), Ok(Stop::End));
+        emulator.pc = 0;
+        emulator.d = 0;
+        assert_eq!(emulator.run(10), Ok(Stop::Loop));
+    }
+
+    #[test]
+    fn test_ram_error() {
+        let mut emulator = load("@32767\nA=A+1\nM=1\n");
+        assert_eq!(
+            emulator.run(10).unwrap_err().to_string(),
+            "ROM[2]: RAM[32768] is out of the 32768 words RAM"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::ir::{Command, IrError};
+
+/// Formats the source of a `.vm` file: every command with single spaces and no indentation,
+/// comments kept after one space, no trailing whitespace, at most one blank line in a row and
+/// none at the start or the end.
+pub fn format_vm(name: &str, source: &str) -> Result<String, IrError> {
+    let mut lines: Vec<String> = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let (code, comment) = match line.find("//") {
+            Some(start) => (&line[..start], Some(line[start..].trim_end())),
+            None => (line, None),
+        };
+        let code = code.trim();
+        let mut formatted = if code.is_empty() {
+            String::new()
+        } else {
+            Command::parse(code)
+                .map_err(|message| IrError {
+                    file: name.to_string(),
+                    line: i + 1,
+                    message,
+                })?
+                .to_string()
+        };
+        if let Some(comment) = comment {
+            if !formatted.is_empty() {
+                formatted.push(' ');
+            }
+            formatted.push_str(comment);
+        }
+        if formatted.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(formatted);
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_vm() {
+        let source = "\n// Main.vm  \n\
+                      function  Main.main   0\n\
+                      \t push constant 7// seven\n\n\n\
+                      \tadd   \n\
+                      return\n\n";
+        let formatted = "// Main.vm\n\
+                         function Main.main 0\n\
+                         push constant 7 // seven\n\n\
+                         add\n\
+                         return\n";
+        assert_eq!(format_vm("Main.vm", source).unwrap(), formatted);
+        assert_eq!(format_vm("Main.vm", formatted).unwrap(), formatted);
+        assert_eq!(
+            format_vm("Main.vm", "add\npush stack 1\n")
+                .unwrap_err()
+                .to_string(),
+            "Main.vm:2: unknow segment `stack`"
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    difftest::{difftest, Outcome},
+    ir::{ArithmeticOp, Command, Segment},
+};
+
+/// Classes the generated functions are in, besides `Sys`.
+const CLASSES: [&str; 2] = ["Main", "Util"];
+/// Functions of a program, besides `Sys.init`.
+const MAX_FUNCTIONS: usize = 4;
+const MAX_ARGS: u16 = 3;
+/// Locals of a function besides the loop counters.
+const MAX_LOCALS: u16 = 3;
+/// Loops nested in a function, every level has its counter in a local.
+const MAX_LOOP_DEPTH: u16 = 2;
+const MAX_LOOP_COUNT: u16 = 3;
+/// Nested operations of an expression.
+const MAX_EXPRESSION_DEPTH: usize = 3;
+/// Statements of a block.
+const MAX_STATEMENTS: usize = 5;
+/// Commands of a function after which the blocks get no more statements and the
+/// expressions no more operations, so the translation fits in the ROM.
+const MAX_FUNCTION_COMMANDS: usize = 150;
+/// Words of a class `static` segment, of `this` and `that`.
+const SEGMENT_WORDS: u16 = 4;
+/// `this` and `that` point to the heap, at one of these blocks.
+const HEAP_BASE: u16 = 2048;
+const HEAP_BLOCKS: u16 = 64;
+/// Constants at the edges of the arithmetic, pushed as often as random ones.
+const EDGE_CONSTANTS: [u16; 6] = [0, 1, 2, 16384, 32766, 32767];
+
+const BINARY_OPS: [ArithmeticOp; 7] = [
+    ArithmeticOp::Add,
+    ArithmeticOp::Sub,
+    ArithmeticOp::Eq,
+    ArithmeticOp::Gt,
+    ArithmeticOp::Lt,
+    ArithmeticOp::And,
+    ArithmeticOp::Or,
+];
+
+/// Pseudorandom numbers of a seed, a splitmix64 generator, so a seed gives the same program
+/// on every platform.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Whether an event of `percent` chances in 100 happens.
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+struct Function {
+    name: String,
+    args: u16,
+    /// The loop counters, then the other locals.
+    locals: u16,
+}
+
+/// Generates the commands of a function at a time.
+struct Generator<'a> {
+    rng: &'a mut Rng,
+    functions: &'a [Function],
+    /// Index of the function generated, it calls the functions after it only, so the
+    /// programs end.
+    function: usize,
+    commands: Vec<Command>,
+    labels: usize,
+    loop_depth: u16,
+}
+
+impl Generator<'_> {
+    fn current(&self) -> &Function {
+        &self.functions[self.function]
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    fn push(&mut self, segment: Segment, index: u16) {
+        self.commands.push(Command::Push(segment, index));
+    }
+
+    fn pop(&mut self, segment: Segment, index: u16) {
+        self.commands.push(Command::Pop(segment, index));
+    }
+
+    /// Statements which leave the stack as they found it.
+    fn block(&mut self) {
+        for _ in 0..=self.rng.below(MAX_STATEMENTS) {
+            if self.commands.len() >= MAX_FUNCTION_COMMANDS {
+                break;
+            }
+            self.statement();
+        }
+    }
+
+    fn statement(&mut self) {
+        match self.rng.below(10) {
+            0 | 1 if self.loop_depth < MAX_LOOP_DEPTH => self.counted_loop(),
+            2 | 3 => {
+                // if-goto over a block, and sometimes an else block
+                let skip = self.label();
+                self.expression(0);
+                self.commands.push(Command::IfGoto(skip.clone()));
+                self.block();
+                if self.rng.chance(50) {
+                    let end = self.label();
+                    self.commands.push(Command::Goto(end.clone()));
+                    self.commands.push(Command::Label(skip));
+                    self.block();
+                    self.commands.push(Command::Label(end));
+                } else {
+                    self.commands.push(Command::Label(skip));
+                }
+            }
+            4 => {
+                // points this or that to another heap block
+                let base = HEAP_BASE + self.rng.below(HEAP_BLOCKS as usize) as u16 * SEGMENT_WORDS;
+                let pointer = self.rng.below(2) as u16;
+                self.push(Segment::Constant, base);
+                self.pop(Segment::Pointer, pointer);
+            }
+            _ => {
+                self.expression(0);
+                self.pop_anywhere();
+            }
+        }
+    }
+
+    /// Runs a block 1 to `MAX_LOOP_COUNT` times, with the local of its depth as counter.
+    fn counted_loop(&mut self) {
+        let counter = self.loop_depth;
+        let top = self.label();
+        let count = 1 + self.rng.below(MAX_LOOP_COUNT as usize) as u16;
+        self.push(Segment::Constant, count);
+        self.pop(Segment::Local, counter);
+        self.commands.push(Command::Label(top.clone()));
+        self.loop_depth += 1;
+        self.block();
+        self.loop_depth -= 1;
+        self.push(Segment::Local, counter);
+        self.push(Segment::Constant, 1);
+        self.commands.push(Command::Arithmetic(ArithmeticOp::Sub));
+        self.pop(Segment::Local, counter);
+        self.push(Segment::Local, counter);
+        self.commands.push(Command::IfGoto(top));
+    }
+
+    /// Commands pushing one value.
+    fn expression(&mut self, depth: usize) {
+        let leaf = depth >= MAX_EXPRESSION_DEPTH || self.commands.len() >= MAX_FUNCTION_COMMANDS;
+        match self.rng.below(if leaf { 2 } else { 6 }) {
+            0 => {
+                let value = if self.rng.chance(50) {
+                    *self.rng.pick(&EDGE_CONSTANTS)
+                } else {
+                    self.rng.below(32768) as u16
+                };
+                self.push(Segment::Constant, value);
+            }
+            1 => self.push_anywhere(),
+            2 => {
+                self.expression(depth + 1);
+                let op = *self.rng.pick(&[ArithmeticOp::Neg, ArithmeticOp::Not]);
+                self.commands.push(Command::Arithmetic(op));
+            }
+            3 if self.function + 1 < self.functions.len() => {
+                let callee =
+                    self.function + 1 + self.rng.below(self.functions.len() - self.function - 1);
+                for _ in 0..self.functions[callee].args {
+                    self.expression(depth + 1);
+                }
+                let callee = &self.functions[callee];
+                self.commands
+                    .push(Command::Call(callee.name.clone(), callee.args));
+            }
+            _ => {
+                self.expression(depth + 1);
+                self.expression(depth + 1);
+                let op = *self.rng.pick(&BINARY_OPS);
+                self.commands.push(Command::Arithmetic(op));
+            }
+        }
+    }
+
+    fn push_anywhere(&mut self) {
+        let function = self.current();
+        let (args, locals) = (function.args, function.locals);
+        let (segment, words) = match self.rng.below(7) {
+            0 if args > 0 => (Segment::Argument, args),
+            1 if locals > 0 => (Segment::Local, locals),
+            2 => (Segment::Static, SEGMENT_WORDS),
+            3 => (Segment::This, SEGMENT_WORDS),
+            4 => (Segment::That, SEGMENT_WORDS),
+            5 => (Segment::Pointer, 2),
+            _ => (Segment::Temp, 8),
+        };
+        let index = self.rng.below(words as usize) as u16;
+        self.push(segment, index);
+    }
+
+    /// Pops to a segment, not to the pointers or the loop counters.
+    fn pop_anywhere(&mut self) {
+        let function = self.current();
+        let (args, locals) = (function.args, function.locals);
+        let counters = MAX_LOOP_DEPTH;
+        let (segment, first, words) = match self.rng.below(6) {
+            0 if args > 0 => (Segment::Argument, 0, args),
+            1 if locals > counters => (Segment::Local, counters, locals),
+            2 => (Segment::Static, 0, SEGMENT_WORDS),
+            3 => (Segment::This, 0, SEGMENT_WORDS),
+            4 => (Segment::That, 0, SEGMENT_WORDS),
+            _ => (Segment::Temp, 0, 8),
+        };
+        let index = first + self.rng.below((words - first) as usize) as u16;
+        self.pop(segment, index);
+    }
+}
+
+/// A random well-formed VM program, its files and their sources.
+///
+/// `Sys.init` points `this` and `that` to the heap, calls the first functions, keeps their
+/// results in `static` and loops at its end. The functions only call the functions
+/// generated after them, and their loops are counted, so every program ends. They use all
+/// the segments and arithmetic commands.
+pub fn generate(seed: u64) -> Vec<(String, String)> {
+    let mut rng = Rng::new(seed);
+    let mut functions = vec![Function {
+        name: "Sys.init".to_string(),
+        args: 0,
+        locals: MAX_LOOP_DEPTH,
+    }];
+    for i in 0..=rng.below(MAX_FUNCTIONS) {
+        functions.push(Function {
+            name: format!("{}.f{i}", rng.pick(&CLASSES)),
+            args: rng.below(MAX_ARGS as usize + 1) as u16,
+            locals: MAX_LOOP_DEPTH + rng.below(MAX_LOCALS as usize + 1) as u16,
+        });
+    }
+
+    let mut bodies = vec![];
+    for function in 0..functions.len() {
+        let mut generator = Generator {
+            rng: &mut rng,
+            functions: &functions,
+            function,
+            commands: vec![],
+            labels: 0,
+            loop_depth: 0,
+        };
+        if function == 0 {
+            for pointer in 0..2 {
+                let base = HEAP_BASE + pointer * SEGMENT_WORDS;
+                generator.push(Segment::Constant, base);
+                generator.pop(Segment::Pointer, pointer);
+            }
+            generator.block();
+            generator.expression(0);
+            generator.pop(Segment::Static, 0);
+            generator.commands.push(Command::Label("END".to_string()));
+            generator.commands.push(Command::Goto("END".to_string()));
+        } else {
+            generator.block();
+            generator.expression(0);
+            generator.commands.push(Command::Return);
+        }
+        bodies.push(generator.commands);
+    }
+
+    let mut files = vec![];
+    for class in ["Sys"].iter().chain(&CLASSES) {
+        let mut source = format!("// generated by vmtranslator fuzz, seed {seed}\n");
+        for (function, body) in functions.iter().zip(&bodies) {
+            if function.name.split('.').next() != Some(class) {
+                continue;
+            }
+            source += &format!(
+                "{}\n",
+                Command::Function(function.name.clone(), function.locals)
+            );
+            for command in body {
+                source += &format!("{command}\n");
+            }
+        }
+        if source.lines().count() > 1 {
+            files.push((format!("{class}.vm"), source));
+        }
+    }
+    files
+}
+
+/// Writes the files of a program to `dir`, and returns their paths.
+pub fn write_program(
+    dir: &Path,
+    files: &[(String, String)],
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let mut paths = vec![];
+    for (name, source) in files {
+        let path = dir.join(name);
+        fs::write(&path, source)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Generates the program of `seed` in `dir` and compares its runs on the interpreter and on
+/// the emulator. The program is removed unless they diverge.
+pub fn fuzz(seed: u64, dir: &Path, max_steps: u64) -> Result<Outcome, Box<dyn Error>> {
+    let paths = write_program(dir, &generate(seed))?;
+    let outcome = difftest(&paths, max_steps)?;
+    if let Outcome::Same { .. } = outcome {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+        let sources = (0..50)
+            .flat_map(generate)
+            .map(|(_, source)| source)
+            .collect::<String>();
+        for op in BINARY_OPS
+            .iter()
+            .chain(&[ArithmeticOp::Neg, ArithmeticOp::Not])
+        {
+            assert!(sources.contains(&format!("\n{}\n", op.name())), "{op:?}");
+        }
+        for segment in [
+            "argument", "local", "static", "constant", "this", "that", "pointer", "temp",
+        ] {
+            assert!(sources.contains(&format!("push {segment} ")), "{segment}");
+        }
+        assert!(sources.contains("call Util.") && sources.contains("if-goto L"));
+    }
+
+    #[test]
+    fn test_fuzz() -> Result<(), Box<dyn Error>> {
+        for seed in 0..10 {
+            let dir = PathBuf::from(format!("./test_fuzz_{seed}"));
+            let outcome = fuzz(seed, &dir, 100_000)?;
+            assert!(
+                matches!(outcome, Outcome::Same { .. }),
+                "seed {seed}: {outcome}"
+            );
+        }
+        Ok(())
+    }
+}
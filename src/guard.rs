@@ -0,0 +1,257 @@
+use std::{error::Error, fmt, ops::Range};
+
+use crate::{
+    debugger::{call_stack, Frame, Position},
+    emulator::Emulator,
+    interpreter::{Interpreter, STACK_BASE},
+    ir::{Command, Segment},
+    keyboard::KBD,
+    screen::SCREEN,
+    source_map::SourceMap,
+};
+
+const SP: usize = 0;
+const THIS: usize = 3;
+const THAT: usize = 4;
+/// Innermost frames of the backtrace of a violation.
+const BACKTRACE_FRAMES: usize = 10;
+/// First word of the heap, the stack ends before it.
+pub const HEAP_BASE: usize = 2048;
+
+/// Memory bounds a running program must stay in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guard {
+    /// Highest value of `SP`, the stack is from 256 to the word before it.
+    pub stack_limit: usize,
+    /// Regions `pop this` and `pop that` may write to.
+    pub pointer_writes: Vec<Range<usize>>,
+}
+
+impl Default for Guard {
+    /// The stack stays out of the heap, and the pointers write to the RAM and the screen,
+    /// not to the keyboard or past it.
+    fn default() -> Self {
+        Self {
+            stack_limit: HEAP_BASE,
+            pointer_writes: vec![0..SCREEN, SCREEN..KBD],
+        }
+    }
+}
+
+impl Guard {
+    /// Checks where `command` writes before it is executed, a `pop this` or `pop that` must
+    /// write to one of the `pointer_writes` regions.
+    pub fn check_command(&self, command: &Command, ram: &[i16]) -> Result<(), String> {
+        let (name, pointer, index) = match command {
+            Command::Pop(Segment::This, index) => ("THIS", THIS, index),
+            Command::Pop(Segment::That, index) => ("THAT", THAT, index),
+            _ => return Ok(()),
+        };
+        let address = ram[pointer] as u16 as usize + *index as usize;
+        if self
+            .pointer_writes
+            .iter()
+            .any(|region| region.contains(&address))
+        {
+            return Ok(());
+        }
+        Err(format!(
+            "write to RAM[{address}] through {name}={}, {}",
+            ram[pointer],
+            region_name(address)
+        ))
+    }
+
+    /// Checks `SP` after an instruction.
+    pub fn check_stack(&self, ram: &[i16]) -> Result<(), String> {
+        let sp = ram[SP] as u16 as usize;
+        if sp < STACK_BASE as usize {
+            Err(format!("stack underflow, SP={sp} is below {STACK_BASE}"))
+        } else if sp > self.stack_limit {
+            Err(format!(
+                "stack overflow, SP={sp} is past the stack limit {}, {}",
+                self.stack_limit,
+                region_name(sp - 1)
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks the VM interpreter before every step.
+    pub fn before_vm_step(&self, vm: &Interpreter) -> Result<(), Box<Violation>> {
+        let Some(location) = vm.location(vm.pc) else {
+            return Ok(());
+        };
+        self.check_command(&location.command, &vm.ram)
+            .map_err(|message| Box::new(vm_violation(vm, vm.pc, message)))
+    }
+
+    /// Checks the VM interpreter after every step, `pc` is the address of the instruction
+    /// executed.
+    pub fn after_vm_step(&self, vm: &Interpreter, pc: usize) -> Result<(), Box<Violation>> {
+        self.check_stack(&vm.ram)
+            .map_err(|message| Box::new(vm_violation(vm, pc, message)))
+    }
+}
+
+fn vm_violation(vm: &Interpreter, pc: usize, message: String) -> Violation {
+    let position_at = |pc| vm.location(pc).map(Position::from);
+    Violation {
+        message,
+        position: position_at(pc),
+        backtrace: call_stack(&vm.ram, position_at(vm.pc), position_at),
+    }
+}
+
+/// A guard of the Hack translation of a program running on the CPU emulator, followed
+/// through its source map.
+pub struct HackGuard<'a> {
+    guard: Guard,
+    source_map: &'a SourceMap,
+    /// Source map entry of every ROM address.
+    entries: Vec<Option<usize>>,
+    /// Command of every source map entry.
+    commands: Vec<Option<Command>>,
+}
+
+impl<'a> HackGuard<'a> {
+    /// `source_map` comes with the assembly lines of the ROM addresses.
+    pub fn new(guard: Guard, source_map: &'a SourceMap, lines: &[usize]) -> Self {
+        Self {
+            guard,
+            entries: source_map.entry_indexes(lines),
+            commands: source_map
+                .entries
+                .iter()
+                .map(|entry| Command::parse(&entry.location.command).ok())
+                .collect(),
+            source_map,
+        }
+    }
+
+    fn entry(&self, pc: usize) -> Option<usize> {
+        self.entries.get(pc).copied().flatten()
+    }
+
+    /// To call before every instruction, the writes of a command are checked before its
+    /// first instruction.
+    pub fn before_step(&self, emulator: &Emulator) -> Result<(), Box<Violation>> {
+        let pc = emulator.pc as usize;
+        let Some(entry) = self.entry(pc) else {
+            return Ok(());
+        };
+        if pc > 0 && self.entry(pc - 1) == Some(entry) {
+            return Ok(());
+        }
+        let Some(command) = &self.commands[entry] else {
+            return Ok(());
+        };
+        self.guard
+            .check_command(command, &emulator.ram)
+            .map_err(|message| Box::new(self.violation(emulator, pc, message)))
+    }
+
+    /// To call after every instruction, `pc` is the address of the instruction executed. The
+    /// stack is checked after the instructions of VM commands, not the bootstrap code.
+    pub fn after_step(&self, emulator: &Emulator, pc: u16) -> Result<(), Box<Violation>> {
+        if self.entry(pc as usize).is_none() {
+            return Ok(());
+        }
+        self.guard
+            .check_stack(&emulator.ram)
+            .map_err(|message| Box::new(self.violation(emulator, pc as usize, message)))
+    }
+
+    fn violation(&self, emulator: &Emulator, pc: usize, message: String) -> Violation {
+        let position_at = |pc| {
+            self.entry(pc)
+                .map(|entry| Position::from(&self.source_map.entries[entry]))
+        };
+        Violation {
+            message,
+            position: position_at(pc),
+            backtrace: call_stack(
+                &emulator.ram,
+                position_at(emulator.pc as usize),
+                position_at,
+            ),
+        }
+    }
+}
+
+/// What the Hack platform maps at an address.
+fn region_name(address: usize) -> &'static str {
+    match address {
+        0..=15 => "a register",
+        16..=255 => "the static variables",
+        256..=2047 => "the stack",
+        2048..=16383 => "the heap",
+        16384..=24575 => "the screen",
+        KBD => "the keyboard",
+        _ => "no memory",
+    }
+}
+
+/// A program which left the bounds of its guard, where it did and its call stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub message: String,
+    /// The VM command which left the bounds.
+    pub position: Option<Position>,
+    pub backtrace: Vec<Frame>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.position {
+            Some(position) => write!(f, "{position}: {}", self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+        for (i, frame) in self.backtrace.iter().enumerate().take(BACKTRACE_FRAMES) {
+            write!(f, "\n  #{i} {frame}")?;
+        }
+        if let Some(more) = self.backtrace.len().checked_sub(BACKTRACE_FRAMES + 1) {
+            write!(f, "\n  ... {} more frames", more + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Violation {}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::RAM_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let guard = Guard::default();
+        let mut ram = vec![0; RAM_SIZE];
+        ram[SP] = 2048;
+        assert_eq!(guard.check_stack(&ram), Ok(()));
+        ram[SP] = 2049;
+        assert_eq!(
+            guard.check_stack(&ram),
+            Err("stack overflow, SP=2049 is past the stack limit 2048, the heap".to_string())
+        );
+        ram[SP] = 255;
+        assert!(guard.check_stack(&ram).is_err());
+
+        ram[THAT] = 24575;
+        let pop = |segment, index| Command::Pop(segment, index);
+        assert_eq!(guard.check_command(&pop(Segment::That, 0), &ram), Ok(()));
+        assert_eq!(
+            guard.check_command(&pop(Segment::That, 1), &ram),
+            Err("write to RAM[24576] through THAT=24575, the keyboard".to_string())
+        );
+        assert_eq!(guard.check_command(&pop(Segment::This, 1), &ram), Ok(()));
+        ram[THIS] = -1;
+        assert!(guard
+            .check_command(&pop(Segment::This, 0), &ram)
+            .unwrap_err()
+            .ends_with("no memory"));
+    }
+}
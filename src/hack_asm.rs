@@ -0,0 +1,300 @@
+use std::{collections::BTreeMap, error::Error, fmt};
+
+/// First RAM address of the variables the assembler allocates.
+const VARIABLE_BASE: u16 = 16;
+
+const PREDEFINED_SYMBOLS: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// A Hack assembly error, `line` is 1-based.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// An assembled Hack program.
+#[derive(Debug, Default, Clone)]
+pub struct Assembly {
+    /// Machine code, the index is the ROM address.
+    pub words: Vec<u16>,
+    /// 1-based assembly line of every ROM address.
+    pub lines: Vec<usize>,
+    /// Label symbols and their ROM addresses.
+    pub labels: BTreeMap<String, u16>,
+    /// Variable symbols and the RAM addresses allocated to them.
+    pub variables: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    /// The value of a symbol, `None` when it is not defined.
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        predefined_symbol(name)
+            .or_else(|| self.labels.get(name).copied())
+            .or_else(|| self.variables.get(name).copied())
+    }
+}
+
+/// Assembles Hack assembly source to machine code.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut assembly = Assembly::default();
+
+    // first pass, labels
+    let mut rom_address = 0;
+    for (i, line) in source.lines().enumerate() {
+        let error = |message| AsmError {
+            line: i + 1,
+            message,
+        };
+        let line = strip_comment(line);
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = line.strip_prefix('(') {
+            let label = label
+                .strip_suffix(')')
+                .ok_or(error(format!("`)` is missing in `{line}`")))?;
+            if !is_symbol(label) {
+                return Err(error(format!("invalid label `{label}`")));
+            }
+            if predefined_symbol(label).is_some() || assembly.labels.contains_key(label) {
+                return Err(error(format!("label `{label}` is already defined")));
+            }
+            assembly.labels.insert(label.to_string(), rom_address);
+        } else {
+            rom_address = rom_address
+                .checked_add(1)
+                .filter(|n| *n <= 32768)
+                .ok_or(error("the program is larger than the 32K ROM".to_string()))?;
+        }
+    }
+
+    // second pass, instructions
+    let mut next_variable = VARIABLE_BASE;
+    for (i, line) in source.lines().enumerate() {
+        let error = |message| AsmError {
+            line: i + 1,
+            message,
+        };
+        let line = strip_comment(line);
+        if line.is_empty() || line.starts_with('(') {
+            continue;
+        }
+        let word = if let Some(value) = line.strip_prefix('@') {
+            if let Ok(n) = value.parse::<u16>() {
+                if n > 32767 {
+                    return Err(error(format!("`{value}` is larger than 32767")));
+                }
+                n
+            } else if !is_symbol(value) {
+                return Err(error(format!("invalid symbol `{value}`")));
+            } else if let Some(n) = assembly.symbol(value) {
+                n
+            } else {
+                assembly.variables.insert(value.to_string(), next_variable);
+                next_variable += 1;
+                next_variable - 1
+            }
+        } else {
+            c_instruction(line).ok_or(error(format!("invalid instruction `{line}`")))?
+        };
+        assembly.words.push(word);
+        assembly.lines.push(i + 1);
+    }
+
+    Ok(assembly)
+}
+
+/// Parses a `.hack` file, one 16 digits binary word per line.
+pub fn parse_hack(source: &str) -> Result<Vec<u16>, AsmError> {
+    let mut words = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match u16::from_str_radix(line, 2) {
+            Ok(word) if line.len() == 16 => words.push(word),
+            _ => {
+                return Err(AsmError {
+                    line: i + 1,
+                    message: format!("`{line}` is not a 16 bits binary word"),
+                })
+            }
+        }
+    }
+    Ok(words)
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find("//") {
+        Some(index) => line[..index].trim(),
+        None => line.trim(),
+    }
+}
+
+fn is_symbol(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+fn predefined_symbol(name: &str) -> Option<u16> {
+    if let Some(n) = (0..16).find(|n| format!("R{n}") == name) {
+        return Some(n);
+    }
+    PREDEFINED_SYMBOLS
+        .iter()
+        .find(|(symbol, _)| *symbol == name)
+        .map(|(_, n)| *n)
+}
+
+/// Encodes `dest=comp;jump`.
+fn c_instruction(line: &str) -> Option<u16> {
+    let (dest, rest) = match line.split_once('=') {
+        Some((dest, rest)) => (dest.trim(), rest),
+        None => ("", line),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp.trim(), jump.trim()),
+        None => (rest.trim(), ""),
+    };
+
+    let mut dest_bits = 0;
+    for c in dest.chars() {
+        let bit = match c {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return None,
+        };
+        if dest_bits & bit != 0 {
+            return None;
+        }
+        dest_bits |= bit;
+    }
+    let jump_bits = match jump {
+        "" => 0,
+        "JGT" => 1,
+        "JEQ" => 2,
+        "JGE" => 3,
+        "JLT" => 4,
+        "JNE" => 5,
+        "JLE" => 6,
+        "JMP" => 7,
+        _ => return None,
+    };
+    let comp_bits = comp_bits(comp)?;
+    Some(0xE000 | comp_bits << 6 | dest_bits << 3 | jump_bits)
+}
+
+/// The `a` bit and the 6 `c` bits of a computation.
+fn comp_bits(comp: &str) -> Option<u16> {
+    let comp = comp.replace(' ', "");
+    // `M` is the same computation as `A` with the `a` bit set
+    let (a, normalized) = if comp.contains('M') {
+        (0b100_0000, comp.replace('M', "A"))
+    } else {
+        (0, comp.clone())
+    };
+    let c = match normalized.as_str() {
+        "0" => 0b101010,
+        "1" => 0b111111,
+        "-1" => 0b111010,
+        "D" => 0b001100,
+        "A" => 0b110000,
+        "!D" => 0b001101,
+        "!A" => 0b110001,
+        "-D" => 0b001111,
+        "-A" => 0b110011,
+        "D+1" | "1+D" => 0b011111,
+        "A+1" | "1+A" => 0b110111,
+        "D-1" => 0b001110,
+        "A-1" => 0b110010,
+        "D+A" | "A+D" => 0b000010,
+        "D-A" => 0b010011,
+        "A-D" => 0b000111,
+        "D&A" | "A&D" => 0b000000,
+        "D|A" | "A|D" => 0b010101,
+        _ => return None,
+    };
+    if a != 0 && comp.contains('A') {
+        return None;
+    }
+    Some(a | c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let assembly = assemble(
+            "// comment\n\
+             @256\n\
+             D=A\n\
+             (LOOP)\n\
+             @counter // variable\n\
+             AM=M-1\n\
+             @LOOP\n\
+             D;JGT\n\
+             @R15\n\
+             M=D+M\n",
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.words,
+            vec![
+                0b0000000100000000,
+                0b1110110000010000,
+                0b0000000000010000,
+                0b1111110010101000,
+                0b0000000000000010,
+                0b1110001100000001,
+                0b0000000000001111,
+                0b1111000010001000,
+            ]
+        );
+        assert_eq!(assembly.lines, vec![2, 3, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(assembly.symbol("LOOP"), Some(2));
+        assert_eq!(assembly.symbol("counter"), Some(16));
+        assert_eq!(assembly.symbol("KBD"), Some(24576));
+        assert_eq!(assembly.symbol("R16"), None);
+    }
+
+    #[test]
+    fn test_assemble_error() {
+        let e = assemble("@1\nD=X\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: invalid instruction `D=X`");
+        let e = assemble("(A)\n(A)\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: label `A` is already defined");
+        assert!(assemble("@32768\n").is_err());
+        assert!(assemble("D=A+M\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_hack() {
+        assert_eq!(
+            parse_hack("0000000000000010\n1110110000010000\n"),
+            Ok(vec![2, 0xEC10])
+        );
+        assert!(parse_hack("0101\n").is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    backend::StaticAllocator,
+    ir::{ArithmeticOp, Command, IrError, Program, Segment},
+};
+
+/// Words of RAM.
+pub const RAM_SIZE: usize = 32768;
+/// Initial stack pointer set by the bootstrap code.
+pub const STACK_BASE: i16 = 256;
+/// Steps `run` executes when no limit is given.
+pub const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP_BASE: u16 = 5;
+
+/// Why the interpreter stopped.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop {
+    /// `Sys.halt` was called.
+    Halt,
+    /// `Sys.init` returned to the bootstrap code.
+    Return,
+    /// A `goto` jumped to itself, the usual end of a program.
+    Loop,
+    /// The program counter went past the last instruction.
+    End,
+    StepLimit,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Stop::Halt => "Sys.halt was called",
+            Stop::Return => "Sys.init returned",
+            Stop::Loop => "the program loops forever on a goto",
+            Stop::End => "the program ran past its last instruction",
+            Stop::StepLimit => "the step limit was reached",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// Where an operand of `push` and `pop` is.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Constant(i16),
+    /// A fixed RAM address, `static`, `temp` and `pointer`.
+    Fixed(u16),
+    /// `RAM[RAM[base] + index]`, `local`, `argument`, `this` and `that`.
+    Based(usize, u16),
+}
+
+/// An instruction with its labels and functions resolved to instruction indexes.
+#[derive(Debug, Clone)]
+enum Op {
+    Push(Operand),
+    Pop(Operand),
+    Arithmetic(ArithmeticOp),
+    Label,
+    Goto(usize),
+    IfGoto(usize),
+    Function(u16),
+    /// `None` is a call of a function the program doesn't define.
+    Call(Option<usize>, u16),
+    Return,
+}
+
+/// Where an instruction comes from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    /// Empty before the first `function` command of a file.
+    pub function: String,
+    pub command: Command,
+}
+
+/// Executes VM programs with the memory layout of the Hack platform.
+pub struct Interpreter {
+    pub ram: Vec<i16>,
+    /// Index of the next instruction.
+    pub pc: usize,
+    /// Instructions executed so far.
+    pub steps: u64,
+    ops: Vec<Op>,
+    locations: Vec<Location>,
+    functions: HashMap<String, usize>,
+    statics: StaticAllocator,
+    /// Return address of the bootstrap call of `Sys.init`.
+    bootstrap_return: Option<usize>,
+}
+
+impl Interpreter {
+    /// Loads a program, the RAM is zeroed and the first instruction is the next one.
+    pub fn new(program: &Program) -> Result<Self, IrError> {
+        let mut locations = vec![];
+        for file in &program.files {
+            let mut function = String::new();
+            for instruction in &file.instructions {
+                if let Command::Function(name, _) = &instruction.command {
+                    function = name.clone();
+                }
+                locations.push(Location {
+                    file: file.name.clone(),
+                    line: instruction.line,
+                    function: function.clone(),
+                    command: instruction.command.clone(),
+                });
+            }
+        }
+
+        // labels are scoped by function, or by file before the first function
+        let scope = |location: &Location| (location.file.clone(), location.function.clone());
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        for (i, location) in locations.iter().enumerate() {
+            match &location.command {
+                Command::Function(name, _) => {
+                    functions.insert(name.clone(), i);
+                }
+                Command::Label(label) => {
+                    labels.insert((scope(location), label.clone()), i);
+                }
+                _ => {}
+            }
+        }
+
+        let mut statics = StaticAllocator::default();
+        let mut ops = vec![];
+        for location in &locations {
+            let error = |message: String| IrError {
+                file: location.file.clone(),
+                line: location.line,
+                message,
+            };
+            let label = |label: &String| {
+                labels
+                    .get(&(scope(location), label.clone()))
+                    .copied()
+                    .ok_or(error(format!("label `{label}` is not defined")))
+            };
+            let mut operand = |segment, index: u16| {
+                Ok(match segment {
+                    Segment::Constant => Operand::Constant(index as i16),
+                    Segment::Static => {
+                        Operand::Fixed(statics.address(&location.file, index as i32) as u16)
+                    }
+                    Segment::Temp if index < 8 => Operand::Fixed(TEMP_BASE + index),
+                    Segment::Pointer if index < 2 => Operand::Fixed(THIS as u16 + index),
+                    Segment::Local => Operand::Based(LCL, index),
+                    Segment::Argument => Operand::Based(ARG, index),
+                    Segment::This => Operand::Based(THIS, index),
+                    Segment::That => Operand::Based(THAT, index),
+                    _ => return Err(error(format!("{} {index} is out of range", segment.name()))),
+                })
+            };
+            let op = match &location.command {
+                Command::Push(segment, index) => Op::Push(operand(*segment, *index)?),
+                Command::Pop(Segment::Constant, _) => {
+                    return Err(error("can't pop to constant".to_string()))
+                }
+                Command::Pop(segment, index) => Op::Pop(operand(*segment, *index)?),
+                Command::Arithmetic(op) => Op::Arithmetic(*op),
+                Command::Label(_) => Op::Label,
+                Command::Goto(l) => Op::Goto(label(l)?),
+                Command::IfGoto(l) => Op::IfGoto(label(l)?),
+                Command::Function(_, n_locals) => Op::Function(*n_locals),
+                Command::Call(name, n_args) => Op::Call(functions.get(name).copied(), *n_args),
+                Command::Return => Op::Return,
+            };
+            ops.push(op);
+        }
+
+        Ok(Self {
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            steps: 0,
+            ops,
+            locations,
+            functions,
+            statics,
+            bootstrap_return: None,
+        })
+    }
+
+    /// Does what the bootstrap code of the translators does, `SP=256` and `call Sys.init 0`.
+    ///
+    /// Without `Sys.init` only the stack pointer is set, the program starts at its first
+    /// instruction.
+    pub fn bootstrap(&mut self) -> Result<(), IrError> {
+        self.ram[SP] = STACK_BASE;
+        if let Some(&entry) = self.functions.get("Sys.init") {
+            // past the last instruction, it's never executed
+            let return_address = self.ops.len();
+            self.bootstrap_return = Some(return_address);
+            self.call(entry, 0, return_address)?;
+        }
+        Ok(())
+    }
+
+    /// Index of the `function` command of a function.
+    pub fn function_entry(&self, name: &str) -> Option<usize> {
+        self.functions.get(name).copied()
+    }
+
+    /// RAM address of a static variable of a file, `None` when the program never uses it.
+    pub fn static_address(&self, file: &str, index: u16) -> Option<u16> {
+        self.statics
+            .get(file, index as i32)
+            .map(|address| address as u16)
+    }
+
+    /// Where the instruction `pc` comes from, `None` past the last instruction.
+    pub fn location(&self, pc: usize) -> Option<&Location> {
+        self.locations.get(pc)
+    }
+
+    /// Executes instructions until the program stops or `max_steps` instructions were
+    /// executed by this call.
+    pub fn run(&mut self, max_steps: u64) -> Result<Stop, IrError> {
+        for _ in 0..max_steps {
+            if let Some(stop) = self.step()? {
+                return Ok(stop);
+            }
+        }
+        Ok(Stop::StepLimit)
+    }
+
+    /// Executes the next instruction, and tells whether the program stopped.
+    pub fn step(&mut self) -> Result<Option<Stop>, IrError> {
+        if self.bootstrap_return == Some(self.pc) {
+            return Ok(Some(Stop::Return));
+        }
+        let Some(op) = self.ops.get(self.pc).cloned() else {
+            return Ok(Some(Stop::End));
+        };
+        self.steps += 1;
+        let mut next = self.pc + 1;
+        match op {
+            Op::Push(operand) => {
+                let value = match operand {
+                    Operand::Constant(n) => n,
+                    operand => {
+                        let address = self.address(operand)?;
+                        self.read(address)?
+                    }
+                };
+                self.push(value)?;
+            }
+            Op::Pop(operand) => {
+                let value = self.pop()?;
+                let address = self.address(operand)?;
+                self.write(address, value)?;
+            }
+            Op::Arithmetic(op) => self.arithmetic(op)?,
+            Op::Label => {}
+            Op::Goto(target) if target + 1 == self.pc => return Ok(Some(Stop::Loop)),
+            Op::Goto(target) => next = target,
+            Op::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    next = target;
+                }
+            }
+            Op::Function(n_locals) => {
+                for _ in 0..n_locals {
+                    self.push(0)?;
+                }
+            }
+            Op::Call(entry, n_args) => {
+                let Command::Call(name, _) = &self.locations[self.pc].command else {
+                    unreachable!()
+                };
+                // `Sys.halt` stops the program even if it's defined, it loops forever
+                if name == "Sys.halt" {
+                    return Ok(Some(Stop::Halt));
+                }
+                let entry =
+                    entry.ok_or_else(|| self.error(format!("function `{name}` is not defined")))?;
+                self.call(entry, n_args, next)?;
+                return Ok(None);
+            }
+            Op::Return => {
+                let frame = self.ram[LCL] as u16 as usize;
+                let return_address = self.read(frame.wrapping_sub(5))? as u16 as usize;
+                let value = self.pop()?;
+                self.write(self.ram[ARG] as u16 as usize, value)?;
+                self.ram[SP] = self.ram[ARG].wrapping_add(1);
+                for (i, pointer) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+                    self.ram[pointer] = self.read(frame.wrapping_sub(i + 1))?;
+                }
+                next = return_address;
+            }
+        }
+        self.pc = next;
+        Ok(None)
+    }
+
+    fn call(&mut self, entry: usize, n_args: u16, return_address: usize) -> Result<(), IrError> {
+        self.push(return_address as i16)?;
+        for pointer in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[pointer])?;
+        }
+        // the pointers wrap around like on the Hack CPU
+        self.ram[ARG] = self.ram[SP].wrapping_sub(5).wrapping_sub(n_args as i16);
+        self.ram[LCL] = self.ram[SP];
+        self.pc = entry;
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, op: ArithmeticOp) -> Result<(), IrError> {
+        use ArithmeticOp::*;
+        let bool = |b: bool| if b { -1 } else { 0 };
+        let y = self.pop()?;
+        let value = match op {
+            Neg => y.wrapping_neg(),
+            Not => !y,
+            _ => {
+                let x = self.pop()?;
+                match op {
+                    Add => x.wrapping_add(y),
+                    Sub => x.wrapping_sub(y),
+                    Eq => bool(x == y),
+                    Gt => bool(x > y),
+                    Lt => bool(x < y),
+                    And => x & y,
+                    Or => x | y,
+                    Neg | Not => unreachable!(),
+                }
+            }
+        };
+        self.push(value)
+    }
+
+    fn address(&self, operand: Operand) -> Result<usize, IrError> {
+        match operand {
+            Operand::Constant(_) => unreachable!(),
+            Operand::Fixed(address) => Ok(address as usize),
+            Operand::Based(base, index) => Ok((self.ram[base] as u16 as usize) + index as usize),
+        }
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), IrError> {
+        let sp = self.ram[SP] as u16 as usize;
+        self.write(sp, value)?;
+        self.ram[SP] = self.ram[SP].wrapping_add(1);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, IrError> {
+        self.ram[SP] = self.ram[SP].wrapping_sub(1);
+        self.read(self.ram[SP] as u16 as usize)
+    }
+
+    fn read(&self, address: usize) -> Result<i16, IrError> {
+        self.ram
+            .get(address)
+            .copied()
+            .ok_or_else(|| self.error(format!("RAM[{address}] is out of the {RAM_SIZE} words RAM")))
+    }
+
+    fn write(&mut self, address: usize, value: i16) -> Result<(), IrError> {
+        match self.ram.get_mut(address) {
+            Some(word) => {
+                *word = value;
+                Ok(())
+            }
+            None => Err(self.error(format!("RAM[{address}] is out of the {RAM_SIZE} words RAM"))),
+        }
+    }
+
+    /// An error at the current instruction.
+    fn error(&self, message: String) -> IrError {
+        let (file, line) = self
+            .location(self.pc)
+            .map_or((String::new(), 0), |l| (l.file.clone(), l.line));
+        IrError {
+            file,
+            line,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::test_file::TestFile;
+
+    use super::*;
+
+    fn load(lines: &[&str]) -> Result<Interpreter, Box<dyn Error>> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        for line in lines {
+            test_file.add_line(line)?;
+        }
+        Ok(Interpreter::new(&Program::load(&[&test_file.path])?)?)
+    }
+
+    #[test]
+    fn test_arithmetic() -> Result<(), Box<dyn Error>> {
+        let mut vm = load(&[
+            "push constant 32767",
+            "push constant 1",
+            "add",
+            "push constant 7",
+            "push constant 8",
+            "lt",
+            "push constant 0",
+            "not",
+            "push constant 0",
+            "gt",
+        ])?;
+        vm.ram[SP] = STACK_BASE;
+        assert_eq!(vm.run(100)?, Stop::End);
+        assert_eq!(vm.ram[SP], 259);
+        assert_eq!(&vm.ram[256..259], &[i16::MIN, -1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_call_and_return() -> Result<(), Box<dyn Error>> {
+        let mut vm = load(&[
+            "function Sys.init 0",
+            "push constant 7",
+            "push constant 3",
+            "call Main.sub 2",
+            "pop static 0",
+            "call Sys.halt 0",
+            "function Main.sub 1",
+            "push argument 0",
+            "push argument 1",
+            "sub",
+            "pop local 0",
+            "push local 0",
+            "return",
+        ])?;
+        vm.bootstrap()?;
+        assert_eq!(vm.run(100)?, Stop::Halt);
+        assert_eq!(vm.ram[16], 4);
+        assert_eq!(vm.ram[SP], 261);
+        assert_eq!(vm.ram[LCL], 261);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sys_init_return_and_loop() -> Result<(), Box<dyn Error>> {
+        let mut vm = load(&["function Sys.init 0", "push constant 5", "return"])?;
+        vm.bootstrap()?;
+        assert_eq!(vm.run(100)?, Stop::Return);
+        assert_eq!(vm.ram[256], 5);
+
+        let mut vm = load(&["function Sys.init 0", "label END", "goto END"])?;
+        vm.bootstrap()?;
+        assert_eq!(vm.run(100)?, Stop::Loop);
+
+        let mut vm = load(&[
+            "function Sys.init 0",
+            "label L",
+            "push constant 1",
+            "if-goto L",
+        ])?;
+        vm.bootstrap()?;
+        assert_eq!(vm.run(100)?, Stop::StepLimit);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pointers_wrap_around() -> Result<(), Box<dyn Error>> {
+        let mut vm = load(&["call Main.f 0", "function Main.f 0", "return"])?;
+        vm.ram[SP] = 32763;
+        vm.step()?;
+        assert_eq!(
+            (vm.ram[SP], vm.ram[LCL], vm.ram[ARG]),
+            (i16::MIN, i16::MIN, 32763)
+        );
+
+        let mut vm = load(&["push constant 7", "return"])?;
+        (vm.ram[SP], vm.ram[LCL], vm.ram[ARG]) = (STACK_BASE, 300, 32767);
+        vm.run(2)?;
+        assert_eq!((vm.ram[32767], vm.ram[SP]), (7, i16::MIN));
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_error() -> Result<(), Box<dyn Error>> {
+        let mut vm = load(&["push constant 1", "pop pointer 1", "push that 32767"])?;
+        vm.ram[SP] = STACK_BASE;
+        let e = vm.run(100).unwrap_err();
+        assert!(e
+            .to_string()
+            .ends_with(":3: RAM[32768] is out of the 32768 words RAM"));
+        Ok(())
+    }
+}
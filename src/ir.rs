@@ -0,0 +1,709 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    path::Path,
+};
+
+use crate::{json::Json, parser::Parser};
+
+/// Version of the JSON schema written by `Program::to_json`.
+pub const IR_JSON_VERSION: i64 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Segment {
+    Argument,
+    Local,
+    Static,
+    Constant,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+impl Segment {
+    pub fn parse(s: &str) -> Option<Self> {
+        use Segment::*;
+        Some(match s {
+            "argument" => Argument,
+            "local" => Local,
+            "static" => Static,
+            "constant" => Constant,
+            "this" => This,
+            "that" => That,
+            "pointer" => Pointer,
+            "temp" => Temp,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        use Segment::*;
+        match self {
+            Argument => "argument",
+            Local => "local",
+            Static => "static",
+            Constant => "constant",
+            This => "this",
+            That => "that",
+            Pointer => "pointer",
+            Temp => "temp",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+impl ArithmeticOp {
+    pub fn parse(s: &str) -> Option<Self> {
+        use ArithmeticOp::*;
+        Some(match s {
+            "add" => Add,
+            "sub" => Sub,
+            "neg" => Neg,
+            "eq" => Eq,
+            "gt" => Gt,
+            "lt" => Lt,
+            "and" => And,
+            "or" => Or,
+            "not" => Not,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        use ArithmeticOp::*;
+        match self {
+            Add => "add",
+            Sub => "sub",
+            Neg => "neg",
+            Eq => "eq",
+            Gt => "gt",
+            Lt => "lt",
+            And => "and",
+            Or => "or",
+            Not => "not",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Command {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Arithmetic(ArithmeticOp),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function(String, u16),
+    Call(String, u16),
+    Return,
+}
+
+impl Command {
+    /// Parses one VM command, without comment.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let arity = |n: usize| {
+            if words.len() == n + 1 {
+                Ok(())
+            } else {
+                Err(format!("`{}` need {n} args: `{s}`", words[0]))
+            }
+        };
+        let number = |word: &str| {
+            word.parse::<u16>()
+                .ok()
+                .filter(|n| *n <= i16::MAX as u16)
+                .ok_or(format!("`{word}` is not a number between 0 and 32767"))
+        };
+        let segment = |word: &str| Segment::parse(word).ok_or(format!("unknow segment `{word}`"));
+
+        match words.first().copied() {
+            None => Err("empty command".to_string()),
+            Some("push") => {
+                arity(2)?;
+                Ok(Command::Push(segment(words[1])?, number(words[2])?))
+            }
+            Some("pop") => {
+                arity(2)?;
+                Ok(Command::Pop(segment(words[1])?, number(words[2])?))
+            }
+            Some("label") => {
+                arity(1)?;
+                Ok(Command::Label(words[1].to_string()))
+            }
+            Some("goto") => {
+                arity(1)?;
+                Ok(Command::Goto(words[1].to_string()))
+            }
+            Some("if-goto") => {
+                arity(1)?;
+                Ok(Command::IfGoto(words[1].to_string()))
+            }
+            Some("function") => {
+                arity(2)?;
+                Ok(Command::Function(words[1].to_string(), number(words[2])?))
+            }
+            Some("call") => {
+                arity(2)?;
+                Ok(Command::Call(words[1].to_string(), number(words[2])?))
+            }
+            Some("return") => {
+                arity(0)?;
+                Ok(Command::Return)
+            }
+            Some(word) => match ArithmeticOp::parse(word) {
+                Some(op) => {
+                    arity(0)?;
+                    Ok(Command::Arithmetic(op))
+                }
+                None => Err(format!("unknow command `{s}`")),
+            },
+        }
+    }
+}
+
+/// The command in the VM language, with single spaces.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Push(segment, index) => write!(f, "push {} {index}", segment.name()),
+            Command::Pop(segment, index) => write!(f, "pop {} {index}", segment.name()),
+            Command::Arithmetic(op) => write!(f, "{}", op.name()),
+            Command::Label(label) => write!(f, "label {label}"),
+            Command::Goto(label) => write!(f, "goto {label}"),
+            Command::IfGoto(label) => write!(f, "if-goto {label}"),
+            Command::Function(name, n_locals) => write!(f, "function {name} {n_locals}"),
+            Command::Call(name, n_args) => write!(f, "call {name} {n_args}"),
+            Command::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// A VM command and the line it is on.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Instruction {
+    /// 1-based line number in the source file.
+    pub line: usize,
+    pub command: Command,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VmFile {
+    /// File name, like `Main.vm`.
+    pub name: String,
+    pub instructions: Vec<Instruction>,
+}
+
+/// A parsed and validated VM program.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Program {
+    pub files: Vec<VmFile>,
+}
+
+/// An error located in a VM file.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IrError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for IrError {}
+
+impl Program {
+    /// Parses and validates the `.vm` files, the first error is returned.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self, Box<dyn Error>> {
+        let mut program = Program::default();
+        for path in paths {
+            program.files.push(VmFile::load(path.as_ref())?);
+        }
+        if let Some(e) = program.validate().into_iter().next() {
+            return Err(e.into());
+        }
+        Ok(program)
+    }
+
+    /// Whether a file has a `function {name}` command.
+    pub fn defines(&self, name: &str) -> bool {
+        self.files.iter().any(|file| {
+            file.instructions
+                .iter()
+                .any(|i| matches!(&i.command, Command::Function(f, _) if f == name))
+        })
+    }
+
+    /// The functions no chain of calls from `Sys.init` reaches, none without `Sys.init`: the
+    /// program starts at its first command then.
+    pub fn unreachable_functions(&self) -> HashSet<String> {
+        let mut calls = HashMap::new();
+        for file in &self.files {
+            for function in file.functions() {
+                if let Some(name) = function.name {
+                    let callees = function.body.iter().filter_map(|i| match &i.command {
+                        Command::Call(callee, _) => Some(callee.as_str()),
+                        _ => None,
+                    });
+                    calls.insert(name, callees.collect::<Vec<_>>());
+                }
+            }
+        }
+        if !calls.contains_key("Sys.init") {
+            return HashSet::new();
+        }
+        let mut reached = HashSet::from(["Sys.init"]);
+        let mut pending = vec!["Sys.init"];
+        while let Some(name) = pending.pop() {
+            for &callee in calls.get(name).into_iter().flatten() {
+                if reached.insert(callee) {
+                    pending.push(callee);
+                }
+            }
+        }
+        calls
+            .into_keys()
+            .filter(|name| !reached.contains(name))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Checks what the parser can't see in a single command.
+    pub fn validate(&self) -> Vec<IrError> {
+        let mut errors = vec![];
+        let mut functions = HashMap::new();
+        for file in &self.files {
+            let error = |line, message| IrError {
+                file: file.name.clone(),
+                line,
+                message,
+            };
+            for function in file.functions() {
+                if let Some(name) = function.name {
+                    if let Some((other_file, other_line)) =
+                        functions.insert(name.to_string(), (&file.name, function.line))
+                    {
+                        errors.push(error(
+                            function.line,
+                            format!(
+                                "function `{name}` is already defined at {other_file}:{other_line}"
+                            ),
+                        ));
+                    }
+                }
+                let mut labels = HashSet::new();
+                for instruction in function.body {
+                    if let Command::Label(label) = &instruction.command {
+                        if !labels.insert(label) {
+                            errors.push(error(
+                                instruction.line,
+                                format!("label `{label}` is already defined"),
+                            ));
+                        }
+                    }
+                }
+                for instruction in function.body {
+                    let line = instruction.line;
+                    match &instruction.command {
+                        Command::Goto(label) | Command::IfGoto(label)
+                            if !labels.contains(label) =>
+                        {
+                            errors.push(error(line, format!("label `{label}` is not defined")));
+                        }
+                        Command::Pop(Segment::Constant, _) => {
+                            errors.push(error(line, "can't pop to constant".to_string()));
+                        }
+                        Command::Push(Segment::Pointer, i) | Command::Pop(Segment::Pointer, i)
+                            if *i > 1 =>
+                        {
+                            errors.push(error(line, format!("pointer {i} is out of 0..=1")));
+                        }
+                        Command::Push(Segment::Temp, i) | Command::Pop(Segment::Temp, i)
+                            if *i > 7 =>
+                        {
+                            errors.push(error(line, format!("temp {i} is out of 0..=7")));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("version", Json::Number(IR_JSON_VERSION)),
+            (
+                "files",
+                Json::Array(self.files.iter().map(VmFile::to_json).collect()),
+            ),
+        ])
+    }
+}
+
+/// The commands of a function, the commands before the first `function` have no name.
+pub struct FunctionBody<'a> {
+    pub name: Option<&'a str>,
+    pub n_locals: u16,
+    /// Line of the `function` command, 0 for the commands before the first function.
+    pub line: usize,
+    pub body: &'a [Instruction],
+}
+
+impl VmFile {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(format!("invalid file name `{}`", path.display()))?
+            .to_string();
+        let mut parser = Parser::new(path)?;
+        let mut instructions = vec![];
+        while parser.has_more_lines() {
+            let error = |line, message| IrError {
+                file: name.clone(),
+                line,
+                message,
+            };
+            let cmd_number = parser.next_cmd_number;
+            parser.advance();
+            // only blank lines and comments were left
+            if parser.next_cmd_number == cmd_number {
+                continue;
+            }
+            let line = parser.line_number();
+            let command = Command::parse(&parser.raw_cmd()).map_err(|e| error(line, e))?;
+            instructions.push(Instruction { line, command });
+        }
+        Ok(Self { name, instructions })
+    }
+
+    /// Splits the instructions at every `function` command.
+    pub fn functions(&self) -> Vec<FunctionBody<'_>> {
+        let mut functions = vec![];
+        let mut start = 0;
+        let mut current: (Option<&str>, u16, usize) = (None, 0, 0);
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if let Command::Function(name, n_locals) = &instruction.command {
+                if i > start || current.0.is_some() {
+                    functions.push(FunctionBody {
+                        name: current.0,
+                        n_locals: current.1,
+                        line: current.2,
+                        body: &self.instructions[start..i],
+                    });
+                }
+                current = (Some(name), *n_locals, instruction.line);
+                start = i + 1;
+            }
+        }
+        if start < self.instructions.len() || current.0.is_some() {
+            functions.push(FunctionBody {
+                name: current.0,
+                n_locals: current.1,
+                line: current.2,
+                body: &self.instructions[start..],
+            });
+        }
+        functions
+    }
+
+    fn to_json(&self) -> Json {
+        let functions = self
+            .functions()
+            .iter()
+            .map(|function| {
+                let optional =
+                    |present: bool, value: Json| if present { value } else { Json::Null };
+                Json::object(vec![
+                    ("name", function.name.map_or(Json::Null, Json::string)),
+                    (
+                        "line",
+                        optional(function.name.is_some(), Json::Number(function.line as i64)),
+                    ),
+                    ("locals", Json::Number(function.n_locals as i64)),
+                    (
+                        "instructions",
+                        Json::Array(function.body.iter().map(instruction_to_json).collect()),
+                    ),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            ("name", Json::string(&self.name)),
+            ("functions", Json::Array(functions)),
+        ])
+    }
+}
+
+fn instruction_to_json(instruction: &Instruction) -> Json {
+    let line = ("line", Json::Number(instruction.line as i64));
+    let op = |name: &str| ("op", Json::string(name));
+    match &instruction.command {
+        Command::Push(segment, index) | Command::Pop(segment, index) => Json::object(vec![
+            line,
+            op(if matches!(instruction.command, Command::Push(..)) {
+                "push"
+            } else {
+                "pop"
+            }),
+            ("segment", Json::string(segment.name())),
+            ("index", Json::Number(*index as i64)),
+        ]),
+        Command::Arithmetic(arithmetic) => Json::object(vec![line, op(arithmetic.name())]),
+        Command::Label(label) => {
+            Json::object(vec![line, op("label"), ("label", Json::string(label))])
+        }
+        Command::Goto(label) => {
+            Json::object(vec![line, op("goto"), ("label", Json::string(label))])
+        }
+        Command::IfGoto(label) => {
+            Json::object(vec![line, op("if-goto"), ("label", Json::string(label))])
+        }
+        Command::Call(function, n_args) => Json::object(vec![
+            line,
+            op("call"),
+            ("function", Json::string(function)),
+            ("arguments", Json::Number(*n_args as i64)),
+        ]),
+        Command::Return => Json::object(vec![line, op("return")]),
+        // `functions` splits the instructions at `function` commands
+        Command::Function(..) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_file::TestFile;
+
+    use super::*;
+
+    fn load(lines: &[&str]) -> Result<Program, Box<dyn Error>> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        for line in lines {
+            test_file.add_line(line)?;
+        }
+        Program::load(&[&test_file.path])
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::parse("push  constant 7"),
+            Ok(Command::Push(Segment::Constant, 7))
+        );
+        assert_eq!(
+            Command::parse("lt"),
+            Ok(Command::Arithmetic(ArithmeticOp::Lt))
+        );
+        assert!(Command::parse("push stack 1").is_err());
+        assert!(Command::parse("pushx constant 1").is_err());
+        for command in [
+            "pop that 3",
+            "if-goto LOOP",
+            "call Main.f 2",
+            "neg",
+            "return",
+        ] {
+            assert_eq!(Command::parse(command).unwrap().to_string(), command);
+        }
+        assert!(Command::parse("push constant 32768").is_err());
+        assert!(Command::parse("call f").is_err());
+        assert!(Command::parse("return 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_arithmetic() {
+        use ArithmeticOp::*;
+        for op in [Add, Sub, Neg, Eq, Gt, Lt, And, Or, Not] {
+            assert_eq!(Command::parse(op.name()), Ok(Command::Arithmetic(op)));
+        }
+    }
+
+    #[test]
+    fn test_parse_push_and_pop() {
+        assert_eq!(
+            Command::parse("push local 1"),
+            Ok(Command::Push(Segment::Local, 1))
+        );
+        assert_eq!(
+            Command::parse("push static 2"),
+            Ok(Command::Push(Segment::Static, 2))
+        );
+        assert_eq!(
+            Command::parse("pop local 1"),
+            Ok(Command::Pop(Segment::Local, 1))
+        );
+        assert_eq!(
+            Command::parse("pop static 2"),
+            Ok(Command::Pop(Segment::Static, 2))
+        );
+    }
+
+    #[test]
+    fn test_parse_branching() {
+        let label = || "LABEL1".to_string();
+        assert_eq!(Command::parse("label LABEL1"), Ok(Command::Label(label())));
+        assert_eq!(Command::parse("goto LABEL1"), Ok(Command::Goto(label())));
+        assert_eq!(
+            Command::parse("if-goto LABEL1"),
+            Ok(Command::IfGoto(label()))
+        );
+    }
+
+    #[test]
+    fn test_parse_function_call_and_return() {
+        assert_eq!(
+            Command::parse("function f1 0"),
+            Ok(Command::Function("f1".to_string(), 0))
+        );
+        assert_eq!(
+            Command::parse("function functionName 3"),
+            Ok(Command::Function("functionName".to_string(), 3))
+        );
+        assert_eq!(
+            Command::parse("call functionName 3"),
+            Ok(Command::Call("functionName".to_string(), 3))
+        );
+        assert_eq!(Command::parse("return"), Ok(Command::Return));
+    }
+
+    #[test]
+    fn test_parse_unknow() {
+        assert_eq!(
+            Command::parse("???"),
+            Err("unknow command `???`".to_string())
+        );
+        assert_eq!(Command::parse(""), Err("empty command".to_string()));
+    }
+
+    #[test]
+    fn test_parse_arity() {
+        assert_eq!(
+            Command::parse("label"),
+            Err("`label` need 1 args: `label`".to_string())
+        );
+        assert_eq!(
+            Command::parse("function f"),
+            Err("`function` need 2 args: `function f`".to_string())
+        );
+        for command in [
+            "goto",
+            "if-goto",
+            "push local",
+            "pop",
+            "call",
+            "add 1",
+            "not x",
+            "label A B",
+            "push local 1 2",
+        ] {
+            assert!(Command::parse(command).is_err(), "{command}");
+        }
+        assert_eq!(
+            Command::parse("push local x"),
+            Err("`x` is not a number between 0 and 32767".to_string())
+        );
+    }
+
+    #[test]
+    fn test_load() -> Result<(), Box<dyn Error>> {
+        let program = load(&[
+            "push constant 1",
+            "function Main.main 1 // main",
+            "",
+            "label LOOP",
+            "if-goto LOOP",
+            "return",
+        ])?;
+        let file = &program.files[0];
+        assert_eq!(file.instructions.len(), 5);
+        assert_eq!(file.instructions[1].line, 2);
+        assert_eq!(file.instructions[2].line, 4);
+
+        let functions = file.functions();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].name, None);
+        assert_eq!(functions[1].name, Some("Main.main"));
+        assert_eq!(functions[1].n_locals, 1);
+        assert_eq!(functions[1].body.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let e = load(&["function Main.main 0", "goto END", "return"]).unwrap_err();
+        assert!(e.to_string().ends_with(":2: label `END` is not defined"));
+
+        let e = load(&["pop constant 1"]).unwrap_err();
+        assert!(e.to_string().ends_with(":1: can't pop to constant"));
+
+        let e = load(&["function f 0", "push temp 8"]).unwrap_err();
+        assert!(e.to_string().ends_with(":2: temp 8 is out of 0..=7"));
+
+        let e = load(&["function f 0", "function f 0"]).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains(":2: function `f` is already defined at "));
+
+        let e = load(&["add", "jump"]).unwrap_err();
+        assert!(e.to_string().ends_with(":2: unknow command `jump`"));
+    }
+
+    #[test]
+    fn test_unreachable_functions() -> Result<(), Box<dyn Error>> {
+        let program = load(&[
+            "function Sys.init 0",
+            "call Main.main 0",
+            "function Main.main 0",
+            "call Main.main 0",
+            "call Math.abs 1",
+            "return",
+            "function Math.abs 0",
+            "return",
+            "function Main.unused 0",
+            "call Math.abs 1",
+            "return",
+        ])?;
+        assert_eq!(
+            program.unreachable_functions(),
+            HashSet::from(["Main.unused".to_string()])
+        );
+        let program = load(&["function Main.main 0", "return"])?;
+        assert!(program.unreachable_functions().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_json() -> Result<(), Box<dyn Error>> {
+        let program = load(&["function Main.main 2", "push local 1", "call Main.f 1"])?;
+        let json = program.to_json().to_string();
+        assert!(json.starts_with("{\n  \"version\": 1,\n  \"files\": [\n"));
+        assert!(json.contains(
+            "\"name\": \"Main.main\",\n          \"line\": 1,\n          \"locals\": 2,\n"
+        ));
+        assert!(json.contains("\"op\": \"push\",\n              \"segment\": \"local\",\n              \"index\": 1\n"));
+        assert!(json.contains("\"function\": \"Main.f\",\n              \"arguments\": 1\n"));
+        Ok(())
+    }
+}
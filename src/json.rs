@@ -0,0 +1,98 @@
+use std::fmt;
+
+/// A JSON value, object keys keep their insertion order.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn string(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Number(n) => write!(f, "{n}"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) if items.is_empty() => write!(f, "[]"),
+            Json::Array(items) => {
+                writeln!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{pad}")?;
+                    item.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < items.len() { "," } else { "" })?;
+                }
+                write!(f, "{}]", "  ".repeat(indent))
+            }
+            Json::Object(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Json::Object(fields) => {
+                writeln!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    write!(f, "{pad}")?;
+                    write_string(f, key)?;
+                    write!(f, ": ")?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < fields.len() { "," } else { "" })?;
+                }
+                write!(f, "{}}}", "  ".repeat(indent))
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let json = Json::object(vec![
+            ("name", Json::string("a \"b\"\n")),
+            ("n", Json::Number(-3)),
+            ("list", Json::Array(vec![Json::Number(1), Json::Null])),
+            ("empty", Json::Array(vec![])),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            "{\n  \"name\": \"a \\\"b\\\"\\n\",\n  \"n\": -3,\n  \"list\": [\n    1,\n    null\n  ],\n  \"empty\": []\n}"
+        );
+    }
+}
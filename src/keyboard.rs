@@ -0,0 +1,244 @@
+/// RAM word of the memory mapped keyboard.
+pub const KBD: usize = 24576;
+/// Steps or cycles between the keys of a `type` command without `every`.
+const DEFAULT_TYPE_DELAY: u64 = 10000;
+
+/// Codes of the Hack keys which are not characters.
+const KEY_NAMES: [(&str, i16); 13] = [
+    ("newline", 128),
+    ("backspace", 129),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+];
+
+/// Key presses replayed on the keyboard word during a run.
+///
+/// A script has one command per line, lines starting with `#` are comments. The times are steps on the
+/// interpreter and cycles on the emulator:
+///
+/// ```text
+/// at 100000 press up for 5000
+/// at 200000 type "hello\n" every 2000
+/// ```
+///
+/// `press` holds a key, given by its code, its name like `up` or `f1`, or a quoted
+/// character like `'a'`. `type` presses the characters of a string one after the other,
+/// every `every` steps or cycles (10000 by default), each for the first half of it. `\n`
+/// and `\b` stand for the newline and backspace keys.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct KeyboardScript {
+    /// The keyboard word from a time on, sorted by time.
+    changes: Vec<(u64, i16)>,
+    next: usize,
+}
+
+impl KeyboardScript {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut presses = vec![];
+        for (i, line) in source.lines().enumerate() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {message}", i + 1);
+            let mut words = line.split_whitespace().peekable();
+            if words.peek().is_none() {
+                continue;
+            }
+            if words.next() != Some("at") {
+                return Err(error("a command starts with `at <time>`".to_string()));
+            }
+            words.next_if(|&word| word == "cycle" || word == "step");
+            let at = time(words.next()).map_err(error)?;
+            match words.next() {
+                Some("press") => {
+                    let key = key(words
+                        .next()
+                        .ok_or(error("`press` need a key".to_string()))?)
+                    .map_err(error)?;
+                    if words.next() != Some("for") {
+                        return Err(error("`press <key>` need `for <time>`".to_string()));
+                    }
+                    let duration = time(words.next()).map_err(error)?;
+                    let end = at
+                        .checked_add(duration)
+                        .ok_or(error("the key is released too late".to_string()))?;
+                    presses.push((at, end, key));
+                }
+                Some("type") => {
+                    let rest = line[line.find("type").unwrap_or_default() + 4..].trim();
+                    let (text, rest) = quoted(rest).map_err(error)?;
+                    let mut words = rest.split_whitespace();
+                    let every = match words.next() {
+                        None => DEFAULT_TYPE_DELAY,
+                        Some("every") => time(words.next()).map_err(error)?,
+                        Some(word) => return Err(error(format!("unexpected `{word}`"))),
+                    };
+                    if every < 2 {
+                        return Err(error("`every` need 2 or more".to_string()));
+                    }
+                    for (i, key) in text.into_iter().enumerate() {
+                        let start = (i as u64)
+                            .checked_mul(every)
+                            .and_then(|delay| delay.checked_add(at))
+                            .filter(|start| start.checked_add(every / 2).is_some())
+                            .ok_or(error("the text is typed too late".to_string()))?;
+                        presses.push((start, start + every / 2, key));
+                    }
+                    continue;
+                }
+                _ => return Err(error("expected `press` or `type`".to_string())),
+            }
+            words.next_if(|&word| word == "cycles" || word == "steps");
+            if let Some(word) = words.next() {
+                return Err(error(format!("unexpected `{word}`")));
+            }
+        }
+
+        // the last key pressed wins, releasing it releases the keyboard
+        let mut times = presses
+            .iter()
+            .flat_map(|&(start, end, _)| [start, end])
+            .collect::<Vec<_>>();
+        times.sort();
+        times.dedup();
+        let mut changes = vec![];
+        for time in times {
+            let key = presses
+                .iter()
+                .filter(|&&(start, end, _)| start <= time && time < end)
+                .max_by_key(|&&(start, ..)| start)
+                .map_or(0, |&(.., key)| key);
+            if changes.last().map(|&(_, last)| last) != Some(key) {
+                changes.push((time, key));
+            }
+        }
+        Ok(Self { changes, next: 0 })
+    }
+
+    /// Writes the keyboard word of `time` to the RAM, times must not decrease between calls.
+    pub fn update(&mut self, time: u64, ram: &mut [i16]) {
+        while let Some(&(_, key)) = self.changes.get(self.next).filter(|&&(at, _)| at <= time) {
+            ram[KBD] = key;
+            self.next += 1;
+        }
+    }
+}
+
+fn time(word: Option<&str>) -> Result<u64, String> {
+    let word = word.ok_or("missing time")?;
+    word.parse()
+        .map_err(|_| format!("`{word}` is not a step or cycle count"))
+}
+
+fn key(word: &str) -> Result<i16, String> {
+    if let Some(c) = word
+        .strip_prefix('\'')
+        .and_then(|word| word.strip_suffix('\''))
+    {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) if (' '..='~').contains(&c) => Ok(c as i16),
+            _ => Err(format!("`{word}` is not a printable character")),
+        };
+    }
+    if let Some(n) = word.strip_prefix('f').and_then(|n| n.parse::<i16>().ok()) {
+        if (1..=12).contains(&n) {
+            return Ok(140 + n);
+        }
+    }
+    KEY_NAMES
+        .iter()
+        .find(|(name, _)| *name == word)
+        .map(|&(_, key)| key)
+        .or_else(|| word.parse().ok().filter(|key| *key > 0))
+        .ok_or(format!("unknown key `{word}`"))
+}
+
+/// The keys of a `"..."` string, and what follows it.
+fn quoted(s: &str) -> Result<(Vec<i16>, &str), String> {
+    let rest = s.strip_prefix('"').ok_or("`type` need a quoted string")?;
+    let mut keys = vec![];
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        let key = match c {
+            '"' => return Ok((keys, &rest[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => 128,
+                Some('b') => 129,
+                Some(c @ ('\\' | '"')) => c as i16,
+                _ => return Err("unknown escape, `\\n`, `\\b`, `\\\\` or `\\\"`".to_string()),
+            },
+            ' '..='~' => c as i16,
+            _ => return Err(format!("`{c}` is not a Hack key")),
+        };
+        keys.push(key);
+    }
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = KeyboardScript::parse(
+            "# arrows\n\
+             at cycle 100 press up for 50 cycles\n\
+             at 120 press 'x' for 10\n\
+             \n\
+             at 1000 type \"a\\n\" every 20\n",
+        )
+        .unwrap();
+        assert_eq!(
+            script.changes,
+            [
+                (100, 131),
+                (120, 120),
+                (130, 131),
+                (150, 0),
+                (1000, 97),
+                (1010, 0),
+                (1020, 128),
+                (1030, 0)
+            ]
+        );
+        assert_eq!(key("f12"), Ok(152));
+        assert_eq!(
+            KeyboardScript::parse("at 1 press up for\n"),
+            Err("line 1: missing time".to_string())
+        );
+        assert!(KeyboardScript::parse("at 1 type \"a").is_err());
+        assert_eq!(
+            KeyboardScript::parse("\nat 1 press up for 18446744073709551615\n"),
+            Err("line 2: the key is released too late".to_string())
+        );
+        assert_eq!(
+            KeyboardScript::parse("at 18446744073709551615 type \"ab\" every 2\n"),
+            Err("line 1: the text is typed too late".to_string())
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let mut script = KeyboardScript::parse("at 10 press 65 for 5").unwrap();
+        let mut ram = vec![0; KBD + 1];
+        let mut pressed = vec![];
+        for time in 0..20 {
+            script.update(time, &mut ram);
+            pressed.push(ram[KBD]);
+        }
+        assert_eq!(pressed.iter().filter(|&&key| key == 65).count(), 5);
+        assert_eq!(pressed[10], 65);
+        assert_eq!(pressed[15], 0);
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::Write;
+
+use crate::{
+    hack_asm::{assemble, AsmError},
+    source_map::{SourceMap, SourceMapEntry},
+};
+
+const HEADER: &str = "// vmtranslator listing v1";
+
+/// Renders Hack assembly as a listing, every instruction with its ROM address and
+/// machine code, labels and symbols resolved to their values, and the VM command
+/// above the instructions it was translated to.
+///
+/// `source_map` maps the lines of `asm` back to the VM commands.
+pub fn listing(asm: &str, source_map: &SourceMap) -> Result<String, AsmError> {
+    let assembly = assemble(asm)?;
+    let mut entries = source_map.entries.iter().peekable();
+    let mut current_entry: Option<&SourceMapEntry> = None;
+    let mut rom_address = 0;
+    let pad = " ".repeat(5 + 2 + 16 + 2);
+
+    let mut buf = String::new();
+    writeln!(buf, "{HEADER}").unwrap();
+    writeln!(buf, "//  ROM  machine code      instruction").unwrap();
+    for (i, line) in asm.lines().enumerate() {
+        let line_number = i + 1;
+        let line = match line.find("//") {
+            Some(index) => line[..index].trim(),
+            None => line.trim(),
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        while entries.next_if(|e| e.last_line < line_number).is_some() {}
+        let entry = entries
+            .peek()
+            .filter(|e| e.first_line <= line_number)
+            .copied();
+        if let (Some(entry), true) = (entry, entry != current_entry) {
+            let location = &entry.location;
+            let function = match entry.function.as_str() {
+                "" => String::new(),
+                function => format!(" {function}:"),
+            };
+            writeln!(
+                buf,
+                "{pad}// {}:{}{function} {}",
+                location.file, location.line, location.command
+            )
+            .unwrap();
+        }
+        current_entry = entry;
+
+        if let Some(label) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            writeln!(buf, "{pad}({label}) = {rom_address}").unwrap();
+            continue;
+        }
+        let word = assembly.words[rom_address];
+        let instruction = match line.strip_prefix('@') {
+            Some(symbol) if symbol.parse::<u16>().is_err() => format!("{line} = {word}"),
+            _ => line.to_string(),
+        };
+        writeln!(buf, "{rom_address:>5}  {word:016b}  {instruction}").unwrap();
+        rom_address += 1;
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source_map::SourceLocation;
+
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let asm = "@256\nD=A\n(Main.main)\n// push constant 1\n@1\nD=A\n@Main.main\n0;JMP\n";
+        let mut source_map = SourceMap::new();
+        for (first_line, last_line, line, command) in [
+            (3, 3, 1, "function Main.main 0"),
+            (4, 6, 2, "push constant 1"),
+            (7, 8, 3, "goto LOOP"),
+        ] {
+            source_map.add(SourceMapEntry {
+                first_line,
+                last_line,
+                function: "Main.main".to_string(),
+                location: SourceLocation {
+                    file: "Main.vm".to_string(),
+                    line,
+                    command: command.to_string(),
+                },
+            });
+        }
+        let listing = listing(asm, &source_map).unwrap();
+        let lines = listing.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], HEADER);
+        assert_eq!(lines[2], "    0  0000000100000000  @256");
+        assert_eq!(
+            lines[4].trim(),
+            "// Main.vm:1 Main.main: function Main.main 0"
+        );
+        assert_eq!(lines[5].trim(), "(Main.main) = 2");
+        assert_eq!(lines[6].trim(), "// Main.vm:2 Main.main: push constant 1");
+        assert_eq!(lines[7], "    2  0000000000000001  @1");
+        assert_eq!(lines[10], "    4  0000000000000010  @Main.main = 2");
+        assert_eq!(lines.len(), 12);
+    }
+}
@@ -1,91 +1,1157 @@
+mod atomic_file;
+mod backend;
+mod cli;
 mod code_writer;
+mod coverage;
+mod debug_info;
+mod debugger;
+mod difftest;
+mod emulator;
+mod formatter;
+mod fuzz;
+mod guard;
+mod hack_asm;
+mod interpreter;
+mod ir;
+mod json;
+mod keyboard;
+mod listing;
+mod minimize;
 mod parser;
+mod profiler;
+mod screen;
+mod source_map;
 mod test_file;
+mod test_script;
+mod trace;
+mod translate;
 
-use code_writer::CodeWriter;
-use parser::*;
-use std::{env::args, error::Error, ffi::OsString, fs, path::Path, result};
+use backend::new_backend;
+use cli::usage;
+use code_writer::{CodeWriter, Comments};
+use guard::Guard;
+use screen::ScreenOptions;
+use source_map::SourceMap;
+use std::{
+    collections::BTreeMap,
+    env::args_os,
+    error::Error,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    result,
+};
+use trace::TraceFilter;
+use translate::{input_files, translate, translate_to_hack, OptLevel};
 
-fn main() -> result::Result<(), Box<dyn Error>> {
-    assert_eq!(
-        args().len(),
-        2,
-        "VM Translator need a input file or folder arg"
-    );
+/// Where the output is written.
+enum Output {
+    /// Next to the input, named after it.
+    Default,
+    File(PathBuf),
+    Dir(PathBuf),
+    Stdout,
+}
+
+fn main() -> ExitCode {
+    match command(args_os().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            if e.is::<cli::UsageError>() {
+                eprintln!("See `vmtranslator --help`.");
+            }
+            cli::exit_code(&*e)
+        }
+    }
+}
+
+/// Runs the command of the command line. An input without a command is translated, like
+/// `vmtranslator Main.vm`.
+fn command(args: Vec<OsString>) -> result::Result<(), Box<dyn Error>> {
+    let Some(first) = args.first() else {
+        return Err(usage("need a command or an input file or folder"));
+    };
+    if first == "-h" || first == "--help" {
+        print!("{}", cli::HELP);
+        return Ok(());
+    }
+    if first == "-V" || first == "--version" {
+        println!("{}", cli::VERSION);
+        return Ok(());
+    }
+    let name = first.to_string_lossy();
+    let (name, args) = if cli::command_help(&name).is_some() {
+        (name.as_ref(), &args[1..])
+    } else if name.starts_with('-') || name.contains(['.', '/']) || Path::new(first).exists() {
+        ("translate", &args[..])
+    } else {
+        return Err(usage(format!("unknown command `{name}`")));
+    };
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", cli::command_help(name).unwrap_or(cli::HELP));
+        return Ok(());
+    }
+    let args = args.iter().cloned();
+    match name {
+        "check" => check_command(args),
+        "fmt" => fmt_command(args),
+        "run" => run_command(args),
+        "emulate" => emulate_command(args),
+        "debug" => debug_command(args),
+        "test" => test_command(args),
+        "difftest" => difftest_command(args),
+        "fuzz" => fuzz_command(args),
+        "minimize" => minimize_command(args),
+        _ => translate_command(args),
+    }
+}
 
-    let input_arg = args().nth(1).unwrap();
-    let input_path = Path::new(&input_arg);
+/// `vmtranslator [translate] [<options>] <input>`, translates a `.vm` file or folder with a
+/// backend. The options are `--emit`, `-O`, the output options, `--comments`, `--source-map`
+/// and `--debug-info`.
+fn translate_command(
+    mut args: impl Iterator<Item = OsString>,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut backend_name = "hack".to_string();
+    let mut opt_level = OptLevel::default();
+    let mut write_source_map = false;
+    let mut write_debug_info = false;
+    let mut comments = None;
+    let mut output = Output::Default;
+    let mut input_arg = None;
+    while let Some(arg) = args.next() {
+        let mut value = |message: &str| -> result::Result<OsString, Box<dyn Error>> {
+            args.next().ok_or_else(|| usage(message))
+        };
+        if arg == "--emit" {
+            backend_name = utf8(value("`--emit` need a backend name")?)?;
+        } else if arg == "-O" {
+            opt_level = utf8(value("`-O` need an optimization level, 0 or 1")?)?
+                .parse()
+                .map_err(usage)?;
+        } else if let Some(level) = arg.to_str().and_then(|arg| arg.strip_prefix("-O")) {
+            opt_level = level.parse().map_err(usage)?;
+        } else if arg == "--comments" {
+            comments = Some(
+                utf8(value("`--comments` need none, vm or verbose")?)?
+                    .parse()
+                    .map_err(usage)?,
+            );
+        } else if arg == "-o" {
+            let path = value("`-o` need a file path, or `-` for stdout")?;
+            output = if path == "-" {
+                Output::Stdout
+            } else {
+                Output::File(path.into())
+            };
+        } else if arg == "--out-dir" {
+            output = Output::Dir(value("`--out-dir` need a directory")?.into());
+        } else if arg == "--source-map" {
+            write_source_map = true;
+        } else if arg == "--debug-info" {
+            write_debug_info = true;
+        } else {
+            if input_arg.is_some() {
+                return Err(usage("`translate` need only one input file or folder arg"));
+            }
+            input_arg = Some(positional("translate", arg)?);
+        }
+    }
+    let input_path =
+        input_arg.ok_or_else(|| usage("`translate` need a input file or folder arg"))?;
 
-    if input_path.is_file() {
-        let input_file_name = input_path.file_name().unwrap();
-        let input_file_dir = input_path.parent().unwrap();
+    let (backend, extension) = match backend_name.as_str() {
+        "ir-json" | "listing" => {
+            // the listing shows the VM commands next to the Hack code instead of comments, the
+            // IR is the program before optimization
+            let ignored = [
+                ("--comments", comments.is_some()),
+                ("--source-map", write_source_map),
+                ("--debug-info", write_debug_info),
+                ("-O1", backend_name == "ir-json" && opt_level > OptLevel::O0),
+            ];
+            if let Some((option, _)) = ignored.iter().find(|(_, given)| *given) {
+                return Err(usage(format!(
+                    "`--emit {backend_name}` can't be used with `{option}`"
+                )));
+            }
+            let extension = if backend_name == "listing" {
+                "lst"
+            } else {
+                "ir.json"
+            };
+            (None, extension)
+        }
+        _ => {
+            let backend = new_backend(&backend_name).ok_or_else(|| {
+                usage(format!(
+                    "unknow backend `{backend_name}`, expect one of: ir-json, listing, {}",
+                    backend::BACKENDS.join(", ")
+                ))
+            })?;
+            if !backend.writes_per_command()
+                && (write_source_map || write_debug_info || comments == Some(Comments::Vm))
+            {
+                return Err(usage(format!(
+                    "`--emit {backend_name}` generates the program at the end, it can't write \
+                     `--source-map`, `--debug-info` or `--comments vm`"
+                )));
+            }
+            let extension = backend.extension();
+            (Some(backend), extension)
+        }
+    };
 
-        let input_file_name_str = input_file_name.to_str().unwrap();
-        let output_file_name = OsString::from(input_file_name_str.replace(".vm", ".asm"));
-        let output_file_path = input_file_dir.join(output_file_name);
+    let comments = comments.unwrap_or(Comments::Verbose);
+    let input_files = input_files(&input_path)?;
+    // `None` is stdout
+    let output_file_path = match output {
+        Output::Stdout => None,
+        Output::File(path) => Some(path),
+        Output::Default => {
+            Some(default_output_dir(&input_path)?.join(output_file_name(&input_path, extension)?))
+        }
+        Output::Dir(dir) => Some(dir.join(output_file_name(&input_path, extension)?)),
+    };
 
-        let mut code_writer = CodeWriter::new(&output_file_path)?;
+    let Some(backend) = backend else {
+        if backend_name == "listing" {
+            let backend = Box::new(backend::HackBackend::new());
+            let mut code_writer = CodeWriter::from_writer(vec![], backend, Comments::None)?;
+            translate(&mut code_writer, &input_files, opt_level)?;
+            let source_map = code_writer.source_map().clone();
+            let asm = String::from_utf8(code_writer.into_inner())?;
+            return write_output(&output_file_path, listing::listing(&asm, &source_map)?);
+        }
+        let program = ir::Program::load(&input_files)?;
+        return write_output(&output_file_path, program.to_json().to_string() + "\n");
+    };
 
-        translate_file(&mut code_writer, input_path)?;
+    let Some(output_file_path) = output_file_path else {
+        if write_source_map || write_debug_info {
+            return Err(usage(
+                "`--source-map` and `--debug-info` need an output file, not stdout",
+            ));
+        }
+        let mut code_writer = CodeWriter::from_writer(io::stdout().lock(), backend, comments)?;
+        return translate(&mut code_writer, &input_files, opt_level);
+    };
+
+    let mut code_writer = CodeWriter::new(&output_file_path, backend, comments)?;
+    translate(&mut code_writer, &input_files, opt_level)?;
+
+    if write_source_map {
+        let mut source_map_path = output_file_path.clone().into_os_string();
+        source_map_path.push(".map");
+        atomic_file::write(
+            source_map_path.as_ref(),
+            code_writer.source_map().to_string(),
+        )?;
+    }
+    if write_debug_info {
+        let mut debug_info_path = output_file_path.clone().into_os_string();
+        debug_info_path.push(".dbg.json");
+        atomic_file::write(
+            debug_info_path.as_ref(),
+            code_writer.debug_info().to_json().to_string() + "\n",
+        )?;
+    }
+    code_writer.commit()?;
+
+    Ok(())
+}
+
+/// `vmtranslator check <input>...`, parses and validates every input, a `.vm` file or a
+/// folder, as a program without translating it, and prints all the errors.
+fn check_command(args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut inputs = vec![];
+    for arg in args {
+        inputs.push(input_files(&positional("check", arg)?)?);
+    }
+    if inputs.is_empty() {
+        return Err(usage("`check` need `.vm` files or folders"));
+    }
+
+    let (mut files, mut errors) = (0, 0);
+    for paths in inputs {
+        let mut program = ir::Program::default();
+        for path in &paths {
+            files += 1;
+            match ir::VmFile::load(path) {
+                Ok(file) => program.files.push(file),
+                Err(e) if e.is::<ir::IrError>() => {
+                    errors += 1;
+                    println!("{e}");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        for e in program.validate() {
+            errors += 1;
+            println!("{e}");
+        }
+    }
+    println!("checked {files} files, {errors} errors");
+    if errors > 0 {
+        return Err(format!("{errors} errors").into());
+    }
+    Ok(())
+}
+
+/// `vmtranslator fmt [--check] <input>...`, formats `.vm` files and folders in place, or
+/// with `--check` prints the files which aren't formatted.
+fn fmt_command(args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut check = false;
+    let mut paths = vec![];
+    for arg in args {
+        if arg == "--check" {
+            check = true;
+        } else {
+            paths.append(&mut input_files(&positional("fmt", arg)?)?);
+        }
+    }
+    if paths.is_empty() {
+        return Err(usage("`fmt` need `.vm` files or folders"));
+    }
 
-        code_writer.close()?;
-    } else if input_path.is_dir() {
-        let output_file_name = input_path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string()
-            + ".asm";
-        let output_file_path = input_path.join(output_file_name);
+    let mut unformatted = 0;
+    for path in &paths {
+        let source = fs::read_to_string(path)?;
+        let formatted = formatter::format_vm(&path.display().to_string(), &source)?;
+        if formatted == source {
+            continue;
+        }
+        unformatted += 1;
+        if check {
+            println!("{}", path.display());
+        } else {
+            atomic_file::write(path, formatted)?;
+            println!("formatted {}", path.display());
+        }
+    }
+    if check && unformatted > 0 {
+        return Err(format!("{unformatted} files are not formatted").into());
+    }
+    Ok(())
+}
 
-        let mut code_writer = CodeWriter::new(&output_file_path)?;
+/// `vmtranslator run [<options>] <input>`, executes the program on the VM interpreter and
+/// prints RAM ranges. The options are `--steps`, `--ram`, the trace options, the screen
+/// options, `--keyboard`, the guard options and `--coverage`.
+fn run_command(args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut options = RunOptions::parse(
+        "run",
+        "step",
+        interpreter::DEFAULT_MAX_STEPS,
+        args,
+        |_, _| Ok(false),
+    )?;
 
-        for entry in fs::read_dir(input_path)? {
-            let entry = entry?;
+    let paths = input_files(&options.input_path)?;
+    let program = ir::Program::load(&paths)?;
+    let mut coverage = options
+        .coverage_path
+        .is_some()
+        .then(|| coverage::Coverage::new(&program, &paths));
+    let mut vm = interpreter::Interpreter::new(&program)?;
+    vm.bootstrap()?;
+    let stop = if !options.instrumented() {
+        vm.run(options.limit)?
+    } else {
+        let (guard, trace) = (options.guard.take(), options.trace.take());
+        step_loop(&mut vm, &mut options, |vm| {
+            let pc = vm.pc;
+            if let Some(guard) = &guard {
+                guard.before_vm_step(vm)?;
+            }
+            if let (Some(coverage), Some(location)) = (&mut coverage, vm.location(pc)) {
+                coverage.hit(&location.file, location.line, &vm.ram);
+            }
+            if let Some(stop) = vm.step()? {
+                return Ok(ControlFlow::Break(stop));
+            }
+            if let Some(guard) = &guard {
+                guard.after_vm_step(vm, pc)?;
+            }
+            let location = vm.location(pc).ok_or("no instruction was executed")?;
+            let traced = trace
+                .as_ref()
+                .is_some_and(|filter| filter.matches(&location.function));
+            Ok(ControlFlow::Continue(traced.then(|| {
+                trace::trace_line(
+                    &location.file,
+                    location.line,
+                    &location.function,
+                    &location.command,
+                    &vm.ram,
+                )
+            })))
+        })?
+    };
+    options.report(stop, vm.steps, &vm.ram, coverage.as_ref())
+}
 
-            if entry.path().is_file() && entry.path().extension().unwrap() == "vm" {
-                translate_file(&mut code_writer, &entry.path())?;
+/// `vmtranslator emulate [<options>] <input>`, executes a `.asm` or `.hack` file on the Hack
+/// CPU emulator and prints RAM ranges. `.vm` inputs are translated to Hack first, only they
+/// can be traced, guarded and covered. The options are `--cycles`, `--ram`, the trace
+/// options, the profile options, the screen options, `--keyboard`, the guard options and
+/// `--coverage`.
+fn emulate_command(args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut profile = false;
+    let mut folded_path = None;
+    let mut options = RunOptions::parse(
+        "emulate",
+        "cycle",
+        emulator::DEFAULT_MAX_CYCLES,
+        args,
+        |arg, args| {
+            if arg == "--profile" {
+                profile = true;
+            } else if arg == "--profile-folded" {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage("`--profile-folded` need a file path"))?;
+                folded_path = Some(PathBuf::from(utf8(path)?));
+            } else {
+                return Ok(false);
             }
+            Ok(true)
+        },
+    )?;
+
+    let rom = load_rom(&options.input_path)?;
+    let source_map = rom
+        .source_map
+        .as_ref()
+        .map(|(source_map, lines)| (source_map, lines.as_slice()));
+    let mut tracer = match (&options.trace, source_map) {
+        (None, _) => None,
+        (Some(_), Some((source_map, lines))) => Some(trace::HackTracer::new(source_map, lines)),
+        (Some(_), None) => {
+            return Err(usage(
+                "`--trace` need `.vm` files, the source map of a translation",
+            ))
         }
-        code_writer.close()?;
+    };
+    let guard = match (options.guard.take(), source_map) {
+        (None, _) => None,
+        (Some(guard), Some((source_map, lines))) => {
+            Some(guard::HackGuard::new(guard, source_map, lines))
+        }
+        (Some(_), None) => {
+            return Err(usage(
+                "`--guard` need `.vm` files, the source map of a translation",
+            ))
+        }
+    };
+    let mut coverage = match (&options.coverage_path, source_map) {
+        (None, _) => None,
+        (Some(_), Some((source_map, lines))) => {
+            let paths = input_files(&options.input_path)?;
+            let program = ir::Program::load(&paths)?;
+            let coverage = coverage::Coverage::new(&program, &paths);
+            Some(coverage::HackCoverage::new(coverage, source_map, lines))
+        }
+        (Some(_), None) => {
+            return Err(usage(
+                "`--coverage` need `.vm` files, the source map of a translation",
+            ))
+        }
+    };
+    let mut profiler = None;
+    if profile || folded_path.is_some() {
+        if rom.labels.is_empty() {
+            return Err(usage(
+                "`--profile` need `.vm` or `.asm` files, the function labels",
+            ));
+        }
+        profiler = Some(profiler::Profiler::new(
+            rom.words.len(),
+            &rom.labels,
+            source_map,
+        ));
     }
 
+    let mut emulator = emulator::Emulator::new(rom.words.clone());
+    let stop = if !options.instrumented() && guard.is_none() && profiler.is_none() {
+        emulator.run(options.limit)?
+    } else {
+        let trace = options.trace.take();
+        step_loop(&mut emulator, &mut options, |emulator| {
+            let pc = emulator.pc;
+            if let Some(guard) = &guard {
+                guard.before_step(emulator)?;
+            }
+            if let Some(coverage) = &mut coverage {
+                coverage.step(pc, &emulator.ram);
+            }
+            let stop = emulator.step()?;
+            if let Some(guard) = &guard {
+                guard.after_step(emulator, pc)?;
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.step(pc, emulator.pc);
+            }
+            if let Some(stop) = stop {
+                return Ok(ControlFlow::Break(stop));
+            }
+            Ok(ControlFlow::Continue(match (&mut tracer, &trace) {
+                (Some(tracer), Some(filter)) => tracer.step(emulator.pc, &emulator.ram, filter),
+                _ => None,
+            }))
+        })?
+    };
+    let coverage = coverage.as_ref().map(|coverage| &coverage.coverage);
+    options.report(stop, emulator.cycles, &emulator.ram, coverage)?;
+    if let Some(profiler) = profiler {
+        if profile {
+            print!("\n{}\n{}", profiler.report(), profiler.lines_report());
+        }
+        if let Some(path) = folded_path {
+            atomic_file::write(&path, profiler.folded())?;
+        }
+    }
     Ok(())
 }
 
-fn translate_file(
-    code_writer: &mut CodeWriter,
-    file_path: &Path,
+/// `vmtranslator debug [--hack] <input>`, debugs the program on the VM interpreter, or on the
+/// CPU emulator running its Hack translation with `--hack`, with commands read from stdin.
+fn debug_command(args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut hack = false;
+    let mut input_arg = None;
+    for arg in args {
+        if arg == "--hack" {
+            hack = true;
+        } else {
+            if input_arg.is_some() {
+                return Err(usage("`debug` need only one input file or folder arg"));
+            }
+            input_arg = Some(positional("debug", arg)?);
+        }
+    }
+    let input_path = input_arg.ok_or_else(|| usage("`debug` need a input file or folder arg"))?;
+
+    let files = input_files(&input_path)?;
+    let program = ir::Program::load(&files)?;
+    let target: Box<dyn debugger::Target> = if hack {
+        Box::new(debugger::HackTarget::new(&files, &program)?)
+    } else {
+        Box::new(debugger::VmTarget::new(&program)?)
+    };
+    let mut debugger = debugger::Debugger::new(&program, target);
+    debugger.repl(io::stdin().lock(), io::stdout())
+}
+
+/// `vmtranslator difftest [--steps <n>] <input>`, runs the program on the VM interpreter and
+/// its Hack translation on the CPU emulator, and reports the first VM command after which
+/// their states differ.
+fn difftest_command(
+    mut args: impl Iterator<Item = OsString>,
 ) -> result::Result<(), Box<dyn Error>> {
-    let mut parser = Parser::new(file_path)?;
-    let source_file_name = file_path.file_name().unwrap().to_str().unwrap();
-    code_writer.set_source_file(source_file_name);
-
-    loop {
-        if !parser.has_more_lines() {
-        break;
-        }
-        parser.advance();
-        if let Some(cmd) = &parser.get_cmd_type() {
-            use CommandType::*;
-            match cmd {
-                Push | Pop => {
-                    code_writer.write_push_pop(&parser.cmd(), &parser.arg1(), parser.arg2())?
+    let mut max_steps = interpreter::DEFAULT_MAX_STEPS;
+    let mut input_arg = None;
+    while let Some(arg) = args.next() {
+        if arg == "--steps" {
+            let steps = utf8(
+                args.next()
+                    .ok_or_else(|| usage("`--steps` need a step count"))?,
+            )?;
+            max_steps = steps
+                .parse()
+                .map_err(|_| usage(format!("`{steps}` is not a step count")))?;
+        } else {
+            if input_arg.is_some() {
+                return Err(usage("`difftest` need only one input file or folder arg"));
+            }
+            input_arg = Some(positional("difftest", arg)?);
+        }
+    }
+    let input_path =
+        input_arg.ok_or_else(|| usage("`difftest` need a input file or folder arg"))?;
+
+    let outcome = difftest::difftest(&input_files(&input_path)?, max_steps)?;
+    println!("{outcome}");
+    match outcome {
+        difftest::Outcome::Same { .. } => Ok(()),
+        difftest::Outcome::Diverged(_) => {
+            Err("the translation diverged from the interpreter".into())
+        }
+    }
+}
+
+/// `vmtranslator minimize [--steps <n>] [--out <dir>] <input>`, reduces a program the
+/// translation diverges on to a minimal one, written to `<dir>`.
+fn minimize_command(
+    mut args: impl Iterator<Item = OsString>,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut max_steps = interpreter::DEFAULT_MAX_STEPS;
+    let mut out_dir = PathBuf::from("minimized");
+    let mut input_arg = None;
+    while let Some(arg) = args.next() {
+        if arg == "--steps" {
+            let steps = utf8(
+                args.next()
+                    .ok_or_else(|| usage("`--steps` need a step count"))?,
+            )?;
+            max_steps = steps
+                .parse()
+                .map_err(|_| usage(format!("`{steps}` is not a step count")))?;
+        } else if arg == "--out" {
+            out_dir = PathBuf::from(
+                args.next()
+                    .ok_or_else(|| usage("`--out` need a directory"))?,
+            );
+        } else {
+            if input_arg.is_some() {
+                return Err(usage("`minimize` need only one input file or folder arg"));
+            }
+            input_arg = Some(positional("minimize", arg)?);
+        }
+    }
+    let input_path =
+        input_arg.ok_or_else(|| usage("`minimize` need a input file or folder arg"))?;
+    minimize(&input_files(&input_path)?, max_steps, &out_dir)
+}
+
+/// Minimizes a diverging program into `out_dir` and prints the result.
+fn minimize(
+    files: &[PathBuf],
+    max_steps: u64,
+    out_dir: &Path,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut minimizer = minimize::Minimizer::new(files, max_steps)?;
+    let size = minimizer.size();
+    minimizer.minimize()?;
+    minimizer.write(out_dir)?;
+    println!(
+        "reduced {size} commands to {} in {} difftests, written to {}",
+        minimizer.size(),
+        minimizer.tests,
+        out_dir.display()
+    );
+    if let Some(divergence) = &minimizer.divergence {
+        println!("divergence after {divergence}");
+    }
+    Ok(())
+}
+
+/// `vmtranslator fuzz [--seed <n>] [--count <n>] [--steps <n>] [--out <dir>] [--minimize]`,
+/// generates random VM programs from consecutive seeds and difftests them. The programs which
+/// diverge are kept in `<dir>/seed-<n>`, and minimized to `<dir>/seed-<n>.min` with
+/// `--minimize`.
+fn fuzz_command(mut args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut seed = None;
+    let mut count = 100;
+    let mut max_steps = 100_000;
+    let mut out_dir = PathBuf::from("fuzz");
+    let mut minimize_programs = false;
+    while let Some(arg) = args.next() {
+        let mut value = |message: &str| -> result::Result<String, Box<dyn Error>> {
+            utf8(args.next().ok_or_else(|| usage(message))?)
+        };
+        let number = |s: String| {
+            s.parse::<u64>()
+                .map_err(|_| usage(format!("`{s}` is not a number")))
+        };
+        if arg == "--seed" {
+            seed = Some(number(value("`--seed` need a number")?)?);
+        } else if arg == "--count" {
+            count = number(value("`--count` need a program count")?)?;
+        } else if arg == "--steps" {
+            max_steps = number(value("`--steps` need a step count")?)?;
+        } else if arg == "--out" {
+            out_dir = PathBuf::from(value("`--out` need a directory")?);
+        } else if arg == "--minimize" {
+            minimize_programs = true;
+        } else {
+            return Err(usage(format!(
+                "unknown `fuzz` option `{}`",
+                arg.to_string_lossy()
+            )));
+        }
+    }
+    // without a seed, one from the clock, printed to reproduce the run
+    let first = match seed {
+        Some(seed) => seed,
+        None => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    };
+    println!("fuzzing {count} programs from seed {first}");
+
+    let mut diverged = 0;
+    for seed in first..first.saturating_add(count) {
+        let dir = out_dir.join(format!("seed-{seed}"));
+        if let difftest::Outcome::Diverged(divergence) = fuzz::fuzz(seed, &dir, max_steps)? {
+            diverged += 1;
+            println!("seed {seed}: divergence after {divergence}");
+            println!("  program kept in {}", dir.display());
+            if minimize_programs {
+                minimize(
+                    &input_files(&dir)?,
+                    max_steps,
+                    &out_dir.join(format!("seed-{seed}.min")),
+                )?;
+            }
+        }
+    }
+    println!("{count} programs, {diverged} diverged");
+    if diverged > 0 {
+        return Err(format!("{diverged} programs diverged").into());
+    }
+    Ok(())
+}
+
+/// `vmtranslator test [--vm] <script or folder>...`, runs nand2tetris `.tst` scripts on the
+/// CPU emulator and compares their output with their `.cmp` files. Scripts loading `.vm`
+/// files run on the VM interpreter with `--vm`, they are skipped otherwise.
+fn test_command(args: impl Iterator<Item = OsString>) -> result::Result<(), Box<dyn Error>> {
+    let mut include_vm = false;
+    let mut scripts = vec![];
+    for arg in args {
+        if arg == "--vm" {
+            include_vm = true;
+            continue;
+        }
+        let path = positional("test", arg)?;
+        if path.is_dir() {
+            scripts.append(&mut test_script::discover(&path)?);
+        } else {
+            scripts.push(path);
+        }
+    }
+    if scripts.is_empty() {
+        return Err(usage(
+            "`test` need `.tst` scripts or folders containing them",
+        ));
+    }
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in &scripts {
+        let outcome = test_script::TestScript::load(path).and_then(|script| {
+            if script.is_vm() && !include_vm {
+                return Ok(test_script::Outcome::Skip(
+                    "it runs on the VM emulator, use `--vm`".to_string(),
+                ));
+            }
+            script.run()
+        });
+        match outcome {
+            Ok(test_script::Outcome::Pass) => passed += 1,
+            Ok(test_script::Outcome::Skip(_)) => skipped += 1,
+            _ => failed += 1,
+        }
+        match outcome {
+            Ok(outcome) => println!("{}: {outcome}", path.display()),
+            Err(e) => println!("{}: error, {e}", path.display()),
+        }
+    }
+    println!("{passed} passed, {failed} failed, {skipped} skipped");
+    if failed > 0 {
+        return Err(format!("{failed} test scripts failed").into());
+    }
+    Ok(())
+}
+
+/// The options `run` and `emulate` share, and their input.
+struct RunOptions {
+    /// `step` or `cycle`.
+    unit: &'static str,
+    /// Steps or cycles the program is stopped after.
+    limit: u64,
+    ram_ranges: Vec<std::ops::Range<usize>>,
+    trace: Option<TraceFilter>,
+    screen: ScreenOptions,
+    keyboard: Option<keyboard::KeyboardScript>,
+    guard: Option<Guard>,
+    coverage_path: Option<PathBuf>,
+    input_path: PathBuf,
+}
+
+impl RunOptions {
+    /// Parses the args of `command`, which counts in `unit`s, a step or a cycle. `other`
+    /// parses the options of the command alone, it gets an option and the args after it and
+    /// returns false for the options it doesn't know.
+    fn parse<I: Iterator<Item = OsString>>(
+        command: &str,
+        unit: &'static str,
+        default_limit: u64,
+        mut args: I,
+        mut other: impl FnMut(&OsString, &mut I) -> result::Result<bool, Box<dyn Error>>,
+    ) -> result::Result<Self, Box<dyn Error>> {
+        let mut options = RunOptions {
+            unit,
+            limit: default_limit,
+            ram_ranges: vec![],
+            trace: None,
+            screen: ScreenOptions::default(),
+            keyboard: None,
+            guard: None,
+            coverage_path: None,
+            input_path: PathBuf::new(),
+        };
+        let mut pointer_writes = vec![];
+        let mut input_arg = None;
+        let count = |count: String| {
+            count
+                .parse()
+                .map_err(|_| usage(format!("`{count}` is not a {unit} count")))
+        };
+        while let Some(arg) = args.next() {
+            if other(&arg, &mut args)? {
+                continue;
+            }
+            let mut value = |message: &str| -> result::Result<String, Box<dyn Error>> {
+                utf8(args.next().ok_or_else(|| usage(message))?)
+            };
+            if arg == format!("--{unit}s").as_str() {
+                options.limit = count(value(&format!("`--{unit}s` need a {unit} count"))?)?;
+            } else if arg == "--ram" {
+                options.ram_ranges.push(
+                    parse_ram_range(&value(
+                        "`--ram` need an address or a range like `256..260`",
+                    )?)
+                    .map_err(usage)?,
+                );
+            } else if arg == "--trace" {
+                options.trace.get_or_insert_with(TraceFilter::default);
+            } else if arg == "--trace-function" {
+                let function = value("`--trace-function` need a function name, or `Class.*`")?;
+                options
+                    .trace
+                    .get_or_insert_with(TraceFilter::default)
+                    .functions
+                    .push(function);
+            } else if arg == "--screen" {
+                options.screen.path = Some(PathBuf::from(value(
+                    "`--screen` need a .pbm or .png file path",
+                )?));
+            } else if arg == "--screen-at" {
+                let at = value(&format!("`--screen-at` need a {unit} count"))?;
+                options.screen.at.push(count(at)?);
+            } else if arg == "--show-screen" {
+                options.screen.view = Some(
+                    value("`--show-screen` need blocks or ascii")?
+                        .parse()
+                        .map_err(usage)?,
+                );
+            } else if arg == "--guard" {
+                options.guard.get_or_insert_with(Guard::default);
+            } else if arg == "--stack-limit" {
+                let limit = value("`--stack-limit` need a RAM address")?;
+                options.guard.get_or_insert_with(Guard::default).stack_limit = limit
+                    .parse()
+                    .map_err(|_| usage(format!("`{limit}` is not a RAM address")))?;
+            } else if arg == "--pointer-writes" {
+                pointer_writes.push(
+                    parse_ram_range(&value(
+                        "`--pointer-writes` need an address or a range like `2048..16384`",
+                    )?)
+                    .map_err(usage)?,
+                );
+            } else if arg == "--coverage" {
+                options.coverage_path =
+                    Some(PathBuf::from(value("`--coverage` need a .info file path")?));
+            } else if arg == "--keyboard" {
+                let path = PathBuf::from(value("`--keyboard` need a keyboard script path")?);
+                let script = keyboard::KeyboardScript::parse(&fs::read_to_string(&path)?)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+                options.keyboard = Some(script);
+            } else {
+                if input_arg.is_some() {
+                    return Err(usage(format!(
+                        "`{command}` need only one input file or folder arg"
+                    )));
                 }
-                Arithmetic => code_writer.write_arithmetic(
-                    &parser.cmd(),
-                    &format!("{source_file_name}.{}", parser.next_cmd_number - 1),
-                )?,
-                Label => code_writer.write_label(&parser.arg1())?,
-                Goto => code_writer.write_goto(&parser.arg1())?,
-                If => code_writer.write_if(&parser.arg1())?,
-                Function => code_writer.write_function(&parser.arg1(), parser.arg2() as u32)?,
-                Call => code_writer.write_call(&parser.arg1(), parser.arg2() as u32)?,
-                Return => code_writer.write_return()?,
+                input_arg = Some(positional(command, arg)?);
+            }
+        }
+        options.input_path = input_arg
+            .ok_or_else(|| usage(format!("`{command}` need a input file or folder arg")))?;
+        let screen = &mut options.screen;
+        if screen.during_run() && screen.path.is_none() && screen.view.is_none() {
+            return Err(usage("`--screen-at` need `--screen` or `--show-screen`"));
+        }
+        screen.at.sort();
+        if !pointer_writes.is_empty() {
+            options
+                .guard
+                .get_or_insert_with(Guard::default)
+                .pointer_writes = pointer_writes;
+        }
+        if options.ram_ranges.is_empty() {
+            options.ram_ranges.push(0..16);
+        }
+        Ok(options)
+    }
+
+    /// Whether the program must run a step at a time, with `step_loop`.
+    fn instrumented(&self) -> bool {
+        self.trace.is_some()
+            || self.screen.during_run()
+            || self.keyboard.is_some()
+            || self.guard.is_some()
+            || self.coverage_path.is_some()
+    }
+
+    /// Prints how the program stopped after `time` steps or cycles and the RAM ranges, writes the screen and the coverage.
+    fn report(
+        &self,
+        stop: impl std::fmt::Display,
+        time: u64,
+        ram: &[i16],
+        coverage: Option<&coverage::Coverage>,
+    ) -> result::Result<(), Box<dyn Error>> {
+        println!("stopped after {time} {}s: {stop}", self.unit);
+        for range in self.ram_ranges.clone() {
+            for address in range {
+                println!("RAM[{address}] = {}", ram[address]);
+            }
+        }
+        if let Some(screen) = self.screen.capture(ram, None)? {
+            print!("{screen}");
+        }
+        if let (Some(coverage), Some(path)) = (coverage, &self.coverage_path) {
+            atomic_file::write(path, coverage.lcov())?;
+            println!("{}", coverage.summary());
+        }
+        Ok(())
+    }
+}
+
+/// The VM interpreter or the CPU emulator, for `step_loop`.
+trait Machine {
+    type Stop;
+    /// The stop at the step or cycle limit.
+    const LIMIT: Self::Stop;
+
+    /// Steps or cycles executed.
+    fn time(&self) -> u64;
+
+    fn ram(&mut self) -> &mut [i16];
+}
+
+impl Machine for interpreter::Interpreter {
+    type Stop = interpreter::Stop;
+    const LIMIT: Self::Stop = interpreter::Stop::StepLimit;
+
+    fn time(&self) -> u64 {
+        self.steps
+    }
+
+    fn ram(&mut self) -> &mut [i16] {
+        &mut self.ram
+    }
+}
+
+impl Machine for emulator::Emulator {
+    type Stop = emulator::Stop;
+    const LIMIT: Self::Stop = emulator::Stop::CycleLimit;
+
+    fn time(&self) -> u64 {
+        self.cycles
+    }
+
+    fn ram(&mut self) -> &mut [i16] {
+        &mut self.ram
+    }
+}
+
+/// Runs `machine` a step or a cycle at a time until `step` breaks with a stop or the limit of
+/// `options`, pressing the keys of the keyboard script, capturing the screen and printing the
+/// trace lines `step` continues with.
+fn step_loop<M: Machine>(
+    machine: &mut M,
+    options: &mut RunOptions,
+    mut step: impl FnMut(&mut M) -> result::Result<ControlFlow<M::Stop, Option<String>>, Box<dyn Error>>,
+) -> result::Result<M::Stop, Box<dyn Error>> {
+    let mut out = io::BufWriter::new(io::stdout());
+    let mut captures = options.screen.at.iter().peekable();
+    let stop = loop {
+        let time = machine.time();
+        if time >= options.limit {
+            break M::LIMIT;
+        }
+        if let Some(keyboard) = &mut options.keyboard {
+            keyboard.update(time, machine.ram());
+        }
+        match step(machine)? {
+            ControlFlow::Break(stop) => break stop,
+            ControlFlow::Continue(Some(line)) => writeln!(out, "{line}")?,
+            ControlFlow::Continue(None) => {}
+        }
+        let time = machine.time();
+        while captures.next_if(|&&at| at <= time).is_some() {
+            if let Some(screen) = options.screen.capture(machine.ram(), Some(time))? {
+                write!(out, "{screen}")?;
             }
         }
+    };
+    out.flush()?;
+    Ok(stop)
+}
+
+/// A program for the emulator.
+struct Rom {
+    words: Vec<u16>,
+    /// Label symbols and their ROM addresses, empty for `.hack` files.
+    labels: BTreeMap<String, u16>,
+    /// The source map of the translation of `.vm` files, and the assembly line of every ROM
+    /// address.
+    source_map: Option<(SourceMap, Vec<usize>)>,
+}
+
+/// Machine code of a `.hack` or `.asm` file, or of the Hack translation of `.vm` files.
+fn load_rom(input_path: &Path) -> result::Result<Rom, Box<dyn Error>> {
+    let extension = input_path.extension().filter(|_| input_path.is_file());
+    if extension.is_some_and(|extension| extension == "hack") {
+        Ok(Rom {
+            words: hack_asm::parse_hack(&fs::read_to_string(input_path)?)?,
+            labels: BTreeMap::new(),
+            source_map: None,
+        })
+    } else if extension.is_some_and(|extension| extension == "asm") {
+        let assembly = hack_asm::assemble(&fs::read_to_string(input_path)?)?;
+        Ok(Rom {
+            words: assembly.words,
+            labels: assembly.labels,
+            source_map: None,
+        })
+    } else {
+        let (asm, source_map) = translate_to_hack(&input_files(input_path)?, true)?;
+        let assembly = hack_asm::assemble(&asm)?;
+        Ok(Rom {
+            words: assembly.words,
+            labels: assembly.labels,
+            source_map: Some((source_map, assembly.lines)),
+        })
     }
+}
 
+/// `256` or `256..260`, inside the RAM.
+fn parse_ram_range(s: &str) -> result::Result<std::ops::Range<usize>, String> {
+    let error = || format!("`{s}` is not a RAM address or a range like `256..260`");
+    let address = |n: &str| n.parse::<usize>().map_err(|_| error());
+    let range = match s.split_once("..") {
+        Some((start, end)) => address(start)?..address(end)?,
+        None => address(s)?..address(s)? + 1,
+    };
+    if range.end > interpreter::RAM_SIZE {
+        return Err(format!("`{s}` is out of the RAM"));
+    }
+    Ok(range)
+}
+
+/// An input arg of `command`, the args starting with `-` are unknown options.
+fn positional(command: &str, arg: OsString) -> result::Result<PathBuf, Box<dyn Error>> {
+    if arg.to_string_lossy().starts_with('-') {
+        return Err(usage(format!(
+            "unknown `{command}` option `{}`",
+            arg.to_string_lossy()
+        )));
+    }
+    Ok(PathBuf::from(arg))
+}
+
+fn utf8(s: OsString) -> result::Result<String, Box<dyn Error>> {
+    s.into_string()
+        .map_err(|s| usage(format!("`{}` is not valid UTF-8", s.to_string_lossy())))
+}
+
+/// The folder of an input file, or the input folder itself.
+fn default_output_dir(input_path: &Path) -> result::Result<PathBuf, Box<dyn Error>> {
+    if input_path.is_dir() {
+        return Ok(input_path.to_path_buf());
+    }
+    Ok(input_path.parent().map_or(PathBuf::from("."), |dir| {
+        if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir.to_path_buf()
+        }
+    }))
+}
+
+/// `Main.vm` is translated to `Main.{extension}`, a folder `Prog/` to `Prog.{extension}`.
+fn output_file_name(input_path: &Path, extension: &str) -> result::Result<String, Box<dyn Error>> {
+    let name = if input_path.is_dir() {
+        // `file_name` of `.` or `..` is `None`
+        input_path.canonicalize()?.file_name().map(OsString::from)
+    } else {
+        input_path.file_stem().map(OsString::from)
+    };
+    let name = name.ok_or(format!(
+        "can't name the output of `{}`",
+        input_path.display()
+    ))?;
+    Ok(utf8(name)? + "." + extension)
+}
+
+fn write_output(path: &Option<PathBuf>, contents: String) -> result::Result<(), Box<dyn Error>> {
+    match path {
+        Some(path) => atomic_file::write(path, contents)?,
+        None => io::stdout().lock().write_all(contents.as_bytes())?,
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_file_name() -> result::Result<(), Box<dyn Error>> {
+        let name = output_file_name(Path::new("dir/Main.vm.old.vm"), "asm")?;
+        assert_eq!(name, "Main.vm.old.asm");
+        assert_eq!(output_file_name(Path::new("Main"), "s")?, "Main.s");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ram_range() {
+        assert_eq!(parse_ram_range("256..260"), Ok(256..260));
+        assert_eq!(parse_ram_range("0"), Ok(0..1));
+        assert!(parse_ram_range("1..x").is_err());
+        assert!(parse_ram_range("32768").is_err());
+    }
+
+    #[test]
+    fn test_run_options() -> result::Result<(), Box<dyn Error>> {
+        let args = |args: &str| args.split(' ').map(OsString::from).collect::<Vec<_>>();
+        let mut profile = false;
+        let options = RunOptions::parse(
+            "emulate",
+            "cycle",
+            100,
+            args("--cycles 50 --profile --pointer-writes 2048 Prog").into_iter(),
+            |arg, _| {
+                profile |= arg == "--profile";
+                Ok(arg == "--profile")
+            },
+        )?;
+        assert!(profile);
+        assert_eq!((options.limit, options.ram_ranges.len()), (50, 1));
+        assert_eq!(options.ram_ranges[0], 0..16);
+        assert_eq!(
+            options.guard.unwrap().pointer_writes.pop(),
+            Some(2048..2049)
+        );
+        assert_eq!(options.input_path, PathBuf::from("Prog"));
+
+        let parse = |s: &str| {
+            RunOptions::parse("run", "step", 100, args(s).into_iter(), |_, _| Ok(false))
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert_eq!(parse("--steps x Prog").unwrap(), "`x` is not a step count");
+        assert_eq!(
+            parse("--cycles 5 Prog").unwrap(),
+            "unknown `run` option `--cycles`"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    atomic_file,
+    difftest::{difftest, Divergence, Outcome},
+    ir::{Command, Program},
+};
+
+/// A VM program being reduced, the commands of its files.
+type Files = Vec<(String, Vec<Command>)>;
+
+/// Reduces a program the translation diverges on, by delta debugging: it deletes files,
+/// functions, then runs of commands and single commands, and keeps a deletion when the
+/// program still loads and still diverges the same way, with the same message after the
+/// same command. Any divergence would do otherwise, like the one of a program reduced to
+/// commands running off its end.
+pub struct Minimizer {
+    files: Files,
+    max_steps: u64,
+    /// Where the candidates are written, the file names matter to the statics.
+    scratch: PathBuf,
+    /// Difftests run so far.
+    pub tests: usize,
+    /// The divergence of the smallest program.
+    pub divergence: Option<Divergence>,
+}
+
+/// The message of a divergence and the command it happened after.
+fn signature(divergence: &Divergence) -> (&str, Option<&Command>) {
+    (
+        &divergence.message,
+        divergence
+            .location
+            .as_ref()
+            .map(|location| &location.command),
+    )
+}
+
+impl Minimizer {
+    pub fn new(paths: &[PathBuf], max_steps: u64) -> Result<Self, Box<dyn Error>> {
+        let program = Program::load(paths)?;
+        let files = program
+            .files
+            .into_iter()
+            .map(|file| {
+                let commands = file
+                    .instructions
+                    .into_iter()
+                    .map(|instruction| instruction.command)
+                    .collect();
+                (file.name, commands)
+            })
+            .collect();
+        Ok(Self {
+            files,
+            max_steps,
+            scratch: std::env::temp_dir().join(format!("vmtranslator-minimize-{}", process::id())),
+            tests: 0,
+            divergence: None,
+        })
+    }
+
+    /// Commands of the program.
+    pub fn size(&self) -> usize {
+        self.files.iter().map(|(_, commands)| commands.len()).sum()
+    }
+
+    /// Reduces the program until no deletion keeps it diverging, an error when the program
+    /// doesn't diverge to begin with.
+    pub fn minimize(&mut self) -> Result<(), Box<dyn Error>> {
+        let files = self.files.clone();
+        self.diverges(&files)?;
+        if self.divergence.is_none() {
+            return Err(
+                "the translation doesn't diverge from the interpreter on the program".into(),
+            );
+        }
+        loop {
+            let size = (self.files.len(), self.size());
+            self.delete_files()?;
+            self.delete_functions()?;
+            self.delete_commands()?;
+            if (self.files.len(), self.size()) == size {
+                break;
+            }
+        }
+        let _ = fs::remove_dir_all(&self.scratch);
+        Ok(())
+    }
+
+    /// Keeps `candidate` when it diverges.
+    fn keep_if_diverges(&mut self, candidate: Files) -> Result<bool, Box<dyn Error>> {
+        let diverges = self.diverges(&candidate)?;
+        if diverges {
+            self.files = candidate;
+        }
+        Ok(diverges)
+    }
+
+    fn diverges(&mut self, files: &Files) -> Result<bool, Box<dyn Error>> {
+        self.tests += 1;
+        let _ = fs::remove_dir_all(&self.scratch);
+        fs::create_dir_all(&self.scratch)?;
+        let mut paths = vec![];
+        for (name, commands) in files {
+            let path = self.scratch.join(name);
+            fs::write(&path, source(commands))?;
+            paths.push(path);
+        }
+        // a candidate which doesn't load, or which the interpreter rejects, isn't well formed
+        let Ok(Outcome::Diverged(divergence)) = difftest(&paths, self.max_steps) else {
+            return Ok(false);
+        };
+        if self
+            .divergence
+            .as_ref()
+            .is_some_and(|first| signature(first) != signature(&divergence))
+        {
+            return Ok(false);
+        }
+        self.divergence = Some(divergence);
+        Ok(true)
+    }
+
+    fn delete_files(&mut self) -> Result<(), Box<dyn Error>> {
+        for i in (0..self.files.len()).rev() {
+            if self.files.len() > 1 {
+                let mut candidate = self.files.clone();
+                candidate.remove(i);
+                self.keep_if_diverges(candidate)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes a function at a time, from its `function` command to the next one.
+    fn delete_functions(&mut self) -> Result<(), Box<dyn Error>> {
+        for file in 0..self.files.len() {
+            let starts = self.files[file]
+                .1
+                .iter()
+                .enumerate()
+                .filter(|(_, command)| matches!(command, Command::Function(..)))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            // the last function first, so the starts before it stay right
+            for (n, &start) in starts.iter().enumerate().rev() {
+                let end = starts
+                    .get(n + 1)
+                    .copied()
+                    .unwrap_or(self.files[file].1.len());
+                let mut candidate = self.files.clone();
+                candidate[file].1.drain(start..end);
+                self.keep_if_diverges(candidate)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes runs of commands, halving their length down to single commands.
+    fn delete_commands(&mut self) -> Result<(), Box<dyn Error>> {
+        for file in 0..self.files.len() {
+            let mut run = self.files[file].1.len().div_ceil(2);
+            while run > 0 {
+                let mut start = 0;
+                while start < self.files[file].1.len() {
+                    let end = (start + run).min(self.files[file].1.len());
+                    let mut candidate = self.files.clone();
+                    candidate[file].1.drain(start..end);
+                    if !self.keep_if_diverges(candidate)? {
+                        start = end;
+                    }
+                }
+                run /= 2;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the files of the program to `dir`, and returns their paths.
+    pub fn write(&self, dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+        let mut paths = vec![];
+        for (name, commands) in &self.files {
+            let path = dir.join(name);
+            atomic_file::write(&path, source(commands))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+fn source(commands: &[Command]) -> String {
+    commands
+        .iter()
+        .map(|command| format!("{command}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimize() -> Result<(), Box<dyn Error>> {
+        // `that` reads R13, which the Hack translation reserves for the `y` of comparisons
+        let dir = PathBuf::from("./test_minimize");
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("Sys.vm"),
+            "function Sys.init 0\n\
+             push constant 13\npop pointer 1\n\
+             push constant 5\ncall Main.double 1\npop static 0\n\
+             push constant 2\npush constant 3\nlt\npop static 1\n\
+             push that 0\npop static 2\n\
+             label END\ngoto END\n",
+        )?;
+        fs::write(
+            dir.join("Main.vm"),
+            "function Main.double 0\npush argument 0\npush argument 0\nadd\nreturn\n",
+        )?;
+        let paths = [dir.join("Main.vm"), dir.join("Sys.vm")];
+        let mut minimizer = Minimizer::new(&paths, 10_000)?;
+        minimizer.minimize()?;
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(minimizer.files.len(), 1);
+        let (name, commands) = &minimizer.files[0];
+        assert_eq!(name, "Sys.vm");
+        assert!(commands.len() <= 7, "{}", source(commands));
+        let divergence = minimizer.divergence.unwrap();
+        assert_eq!(
+            divergence.location.unwrap().command,
+            Command::Push(crate::ir::Segment::That, 0)
+        );
+        Ok(())
+    }
+}
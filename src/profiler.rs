@@ -0,0 +1,326 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::source_map::SourceMap;
+
+/// Name of the code before the first function label, like the bootstrap code.
+const NO_FUNCTION: &str = "(no function)";
+/// Prefixes of the labels of the comparison code, which have a `.` like the function labels
+/// of an assembly file.
+const COMPARISON_LABELS: [&str; 4] = ["X_NEGATIVE_", "SUBTRACT_", "COMPARE_", "CONTINUE_"];
+/// VM commands listed by `lines_report`.
+const HOT_LINES: usize = 20;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    /// Cycles of the instructions of the function.
+    pub self_cycles: u64,
+    /// Cycles spent between the calls of the function and their returns, callees included.
+    pub inclusive_cycles: u64,
+    pub calls: u64,
+}
+
+/// Attributes every instruction executed by the emulator to a VM function, and to a VM
+/// command when the source map of the translation is known.
+///
+/// The function of an instruction is the last function label before it, the labels like
+/// `(Main.main)` which `_write_function` emits. With a source map they are the labels of its
+/// functions, without they are guessed from their names: a `.`, no `$`, and not the labels
+/// of the comparisons. A jump to a function label is a call, which returns when the program
+/// jumps to the instruction after the call jump.
+pub struct Profiler<'a> {
+    functions: Vec<FunctionProfile>,
+    /// Function of every ROM address.
+    rom_functions: Vec<usize>,
+    /// Functions starting at a ROM address.
+    entries: HashMap<u16, usize>,
+    /// Functions called and their return addresses.
+    stack: Vec<(usize, u16)>,
+    /// Cycles of every call stack.
+    folded: HashMap<Vec<usize>, u64>,
+    source_map: Option<&'a SourceMap>,
+    /// Source map entry of every ROM address.
+    rom_entries: Vec<Option<usize>>,
+    /// Cycles of every source map entry.
+    entry_cycles: Vec<u64>,
+    pub cycles: u64,
+}
+
+impl<'a> Profiler<'a> {
+    /// `source_map` comes with the assembly lines of the ROM addresses.
+    pub fn new(
+        rom_size: usize,
+        labels: &BTreeMap<String, u16>,
+        source_map: Option<(&'a SourceMap, &[usize])>,
+    ) -> Self {
+        let mut functions = vec![FunctionProfile {
+            name: NO_FUNCTION.to_string(),
+            ..Default::default()
+        }];
+        let source_functions = source_map.map(|(source_map, _)| {
+            source_map
+                .entries
+                .iter()
+                .map(|entry| entry.function.as_str())
+                .collect::<HashSet<_>>()
+        });
+        let is_function = |name: &str| match &source_functions {
+            Some(source_functions) => source_functions.contains(name),
+            None => {
+                name.contains('.')
+                    && !name.contains('$')
+                    && !COMPARISON_LABELS
+                        .iter()
+                        .any(|prefix| name.starts_with(prefix))
+            }
+        };
+        let mut starts = labels
+            .iter()
+            .filter(|(name, _)| is_function(name))
+            .map(|(name, &address)| (address, name))
+            .collect::<Vec<_>>();
+        starts.sort();
+        let mut entries = HashMap::new();
+        let mut rom_functions = vec![0; rom_size];
+        for (address, name) in starts {
+            functions.push(FunctionProfile {
+                name: name.clone(),
+                ..Default::default()
+            });
+            entries.insert(address, functions.len() - 1);
+            for function in rom_functions.iter_mut().skip(address as usize) {
+                *function = functions.len() - 1;
+            }
+        }
+
+        let (rom_entries, entry_cycles) = match source_map {
+            Some((source_map, lines)) => (
+                source_map.entry_indexes(lines),
+                vec![0; source_map.entries.len()],
+            ),
+            None => (vec![], vec![]),
+        };
+        Self {
+            functions,
+            rom_functions,
+            entries,
+            stack: vec![],
+            folded: HashMap::new(),
+            source_map: source_map.map(|(source_map, _)| source_map),
+            rom_entries,
+            entry_cycles,
+            cycles: 0,
+        }
+    }
+
+    /// To call after every instruction, `pc` is the address of the instruction executed and
+    /// `next` the address of the next one.
+    pub fn step(&mut self, pc: u16, next: u16) {
+        self.cycles += 1;
+        let function = self.rom_functions.get(pc as usize).copied().unwrap_or(0);
+        self.functions[function].self_cycles += 1;
+        // recursive calls count once
+        let mut counted = vec![false; self.functions.len()];
+        for &(caller, _) in &self.stack {
+            if !counted[caller] {
+                counted[caller] = true;
+                self.functions[caller].inclusive_cycles += 1;
+            }
+        }
+        let stack = if self.stack.is_empty() {
+            vec![function]
+        } else {
+            self.stack.iter().map(|&(function, _)| function).collect()
+        };
+        *self.folded.entry(stack).or_default() += 1;
+        if let Some(entry) = self.rom_entries.get(pc as usize).copied().flatten() {
+            self.entry_cycles[entry] += 1;
+        }
+
+        if next == pc.wrapping_add(1) {
+            return;
+        }
+        if let Some(&callee) = self.entries.get(&next) {
+            self.functions[callee].calls += 1;
+            self.stack.push((callee, pc.wrapping_add(1)));
+        } else if let Some(depth) = self.stack.iter().rposition(|&(_, ret)| ret == next) {
+            self.stack.truncate(depth);
+        }
+    }
+
+    /// The functions which executed instructions, the most expensive first.
+    pub fn functions(&self) -> Vec<&FunctionProfile> {
+        let mut functions = self
+            .functions
+            .iter()
+            .filter(|function| function.self_cycles > 0 || function.calls > 0)
+            .collect::<Vec<_>>();
+        let cost = |function: &FunctionProfile| {
+            (
+                function.inclusive_cycles.max(function.self_cycles),
+                function.self_cycles,
+            )
+        };
+        functions.sort_by_key(|function| std::cmp::Reverse(cost(function)));
+        functions
+    }
+
+    /// The flat profile, a table of the functions.
+    pub fn report(&self) -> String {
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.cycles.max(1) as f64;
+        let mut s = format!("profile of {} cycles\n", self.cycles);
+        let _ = writeln!(
+            s,
+            "{:>12} {:>6} {:>12} {:>6} {:>8}  function",
+            "self", "%", "inclusive", "%", "calls"
+        );
+        for function in self.functions() {
+            let _ = writeln!(
+                s,
+                "{:>12} {:>5.1}% {:>12} {:>5.1}% {:>8}  {}",
+                function.self_cycles,
+                percent(function.self_cycles),
+                function.inclusive_cycles,
+                percent(function.inclusive_cycles),
+                function.calls,
+                function.name
+            );
+        }
+        s
+    }
+
+    /// The VM commands which took the most cycles, empty without source map.
+    pub fn lines_report(&self) -> String {
+        let Some(source_map) = self.source_map else {
+            return String::new();
+        };
+        let mut commands = BTreeMap::<_, u64>::new();
+        for (entry, &cycles) in source_map.entries.iter().zip(&self.entry_cycles) {
+            if cycles > 0 {
+                let location = &entry.location;
+                let key = (
+                    &location.file,
+                    location.line,
+                    &entry.function,
+                    &location.command,
+                );
+                *commands.entry(key).or_default() += cycles;
+            }
+        }
+        let mut commands = commands.into_iter().collect::<Vec<_>>();
+        commands.sort_by_key(|&(_, cycles)| std::cmp::Reverse(cycles));
+
+        let mut s = format!("{:>12}  hottest VM commands\n", "cycles");
+        for ((file, line, function, command), cycles) in commands.into_iter().take(HOT_LINES) {
+            let _ = writeln!(s, "{cycles:>12}  {file}:{line} {function}: {command}");
+        }
+        s
+    }
+
+    /// The cycles of every call stack, one `caller;callee cycles` line per stack, the input
+    /// format of the flame graph tools.
+    pub fn folded(&self) -> String {
+        let mut lines = self
+            .folded
+            .iter()
+            .map(|(stack, cycles)| {
+                let names = stack
+                    .iter()
+                    .map(|&function| self.functions[function].name.as_str())
+                    .collect::<Vec<_>>();
+                format!("{} {cycles}\n", names.join(";"))
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fs, path::PathBuf};
+
+    use crate::{emulator::Emulator, hack_asm::assemble, translate::translate_to_hack};
+
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        // the bootstrap calls Sys.init, which calls Main.f twice
+        let assembly = assemble(
+            "@Sys.init\n0;JMP\n\
+             (Main.f)\nD=1\n@R15\nA=M\n0;JMP\n\
+             (Sys.init)\n\
+             @Sys.init$ret.0\nD=A\n@R15\nM=D\n@Main.f\n0;JMP\n(Sys.init$ret.0)\n\
+             @Sys.init$ret.1\nD=A\n@R15\nM=D\n@Main.f\n0;JMP\n(Sys.init$ret.1)\n\
+             (END)\n@END\n0;JMP\n",
+        )
+        .unwrap();
+        let mut profiler = Profiler::new(assembly.words.len(), &assembly.labels, None);
+        let mut emulator = Emulator::new(assembly.words);
+        loop {
+            let pc = emulator.pc;
+            let stop = emulator.step().unwrap();
+            profiler.step(pc, emulator.pc);
+            if stop.is_some() {
+                break;
+            }
+        }
+
+        let functions = profiler.functions();
+        let names = functions
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Sys.init", "Main.f", NO_FUNCTION]);
+        assert_eq!((functions[0].calls, functions[0].self_cycles), (1, 14));
+        assert_eq!(functions[0].inclusive_cycles, 22);
+        assert_eq!((functions[1].calls, functions[1].self_cycles), (2, 8));
+        assert_eq!(functions[1].inclusive_cycles, 8);
+        assert_eq!(functions[2].self_cycles, 2);
+        assert_eq!(
+            profiler.folded(),
+            "(no function) 2\nSys.init 14\nSys.init;Main.f 8\n"
+        );
+    }
+
+    #[test]
+    fn test_source_map_functions() -> Result<(), Box<dyn Error>> {
+        let path = PathBuf::from("./test_source_map_functions.vm");
+        fs::write(
+            &path,
+            "function Sys.init 0\ncall f 0\nlabel END\ngoto END\n\
+             function f 0\npush constant 1\npush constant 2\nlt\nreturn\n",
+        )?;
+        let translation = translate_to_hack(std::slice::from_ref(&path), true);
+        fs::remove_file(&path)?;
+        let (asm, source_map) = translation?;
+        let assembly = assemble(&asm)?;
+        let mut profiler = Profiler::new(
+            assembly.words.len(),
+            &assembly.labels,
+            Some((&source_map, &assembly.lines)),
+        );
+        let mut emulator = Emulator::new(assembly.words);
+        for _ in 0..1000 {
+            let pc = emulator.pc;
+            let stop = emulator.step()?;
+            profiler.step(pc, emulator.pc);
+            if stop.is_some() {
+                break;
+            }
+        }
+
+        let mut names = profiler
+            .functions()
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [NO_FUNCTION, "Sys.init", "f"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    error::Error,
+    fmt::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::atomic_file;
+
+/// First RAM word of the memory mapped screen.
+pub const SCREEN: usize = 16384;
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+/// Pixels a terminal character stands for.
+const CELL_WIDTH: usize = 4;
+const CELL_HEIGHT: usize = 8;
+
+/// Whether the pixel is black, bit `x % 16` of the word `x / 16` of the row.
+pub fn pixel(ram: &[i16], x: usize, y: usize) -> bool {
+    ram[SCREEN + y * WIDTH / 16 + x / 16] as u16 & (1 << (x % 16)) != 0
+}
+
+/// The screen as a binary PBM image, `P4`.
+pub fn pbm(ram: &[i16]) -> Vec<u8> {
+    let mut image = format!("P4\n{WIDTH} {HEIGHT}\n").into_bytes();
+    for y in 0..HEIGHT {
+        // black pixels are 1
+        image.extend(packed_row(ram, y, false));
+    }
+    image
+}
+
+/// The screen as a black and white PNG image, its pixel data is stored without compression.
+pub fn png(ram: &[i16]) -> Vec<u8> {
+    let mut header = vec![];
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    // bit depth 1, grayscale, deflate, filter method 0, no interlace
+    header.extend([1, 0, 0, 0, 0]);
+
+    let mut pixels = vec![];
+    for y in 0..HEIGHT {
+        // no filter, then white pixels are 1
+        pixels.push(0);
+        pixels.extend(packed_row(ram, y, true));
+    }
+    // zlib stream of one stored deflate block, the pixels fit in 65535 bytes
+    let mut data = vec![0x78, 0x01, 0x01];
+    data.extend((pixels.len() as u16).to_le_bytes());
+    data.extend((!(pixels.len() as u16)).to_le_bytes());
+    data.extend(&pixels);
+    data.extend(adler32(&pixels).to_be_bytes());
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, chunk) in [(b"IHDR", header), (b"IDAT", data), (b"IEND", vec![])] {
+        image.extend((chunk.len() as u32).to_be_bytes());
+        let start = image.len();
+        image.extend(kind);
+        image.extend(chunk);
+        let crc = crc32(&image[start..]);
+        image.extend(crc.to_be_bytes());
+    }
+    image
+}
+
+/// The pixels of a row, 8 per byte with the leftmost in the highest bit.
+fn packed_row(ram: &[i16], y: usize, white: bool) -> Vec<u8> {
+    (0..WIDTH / 8)
+        .map(|byte| {
+            (0..8).fold(0, |bits, i| {
+                bits << 1 | (pixel(ram, byte * 8 + i, y) != white) as u8
+            })
+        })
+        .collect()
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+/// How the screen is printed in a terminal.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum View {
+    /// Quadrant block characters, like `▙`.
+    Blocks,
+    /// `#` and `.`.
+    Ascii,
+}
+
+impl FromStr for View {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(View::Blocks),
+            "ascii" => Ok(View::Ascii),
+            _ => Err(format!("unknown screen view `{s}`, blocks or ascii")),
+        }
+    }
+}
+
+/// The screen downscaled for a terminal, a character stands for 4x8 pixels and is drawn when
+/// one of them is black.
+pub fn render(ram: &[i16], view: View) -> String {
+    // quadrants of a character, left to right and top to bottom
+    const BLOCKS: [char; 16] = [
+        ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+    ];
+    let black = |x: usize, y: usize, width: usize, height: usize| {
+        (y..y + height).any(|y| (x..x + width).any(|x| pixel(ram, x, y)))
+    };
+    let mut s = String::new();
+    for row in 0..HEIGHT / CELL_HEIGHT {
+        let y = row * CELL_HEIGHT;
+        for column in 0..WIDTH / CELL_WIDTH {
+            let x = column * CELL_WIDTH;
+            let (w, h) = (CELL_WIDTH / 2, CELL_HEIGHT / 2);
+            let c = match view {
+                View::Ascii if black(x, y, CELL_WIDTH, CELL_HEIGHT) => '#',
+                View::Ascii => '.',
+                View::Blocks => {
+                    let quadrants = [(x, y), (x + w, y), (x, y + h), (x + w, y + h)];
+                    let index = quadrants
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, &(x, y))| black(x, y, w, h))
+                        .fold(0, |index, (i, _)| index | 1 << i);
+                    BLOCKS[index]
+                }
+            };
+            s.push(c);
+        }
+        let _ = writeln!(s);
+    }
+    s
+}
+
+/// Where and when the screen is saved or printed during a run.
+#[derive(Debug, Default)]
+pub struct ScreenOptions {
+    /// A `.pbm` or `.png` image of the screen at the end of the run.
+    pub path: Option<PathBuf>,
+    /// Steps or cycles the screen is also captured after, the images get the count in their
+    /// name, like `screen.1000.png`.
+    pub at: Vec<u64>,
+    pub view: Option<View>,
+}
+
+impl ScreenOptions {
+    /// Whether the screen is captured during the run.
+    pub fn during_run(&self) -> bool {
+        !self.at.is_empty()
+    }
+
+    /// Saves the screen, and returns it rendered for `view`. `count` is the step or cycle
+    /// count of a capture during the run, `None` at the end.
+    pub fn capture(
+        &self,
+        ram: &[i16],
+        count: Option<u64>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            let path = match count {
+                Some(count) => numbered(path, count),
+                None => path.clone(),
+            };
+            let image = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("pbm") => pbm(ram),
+                Some("png") => png(ram),
+                _ => return Err(format!("`{}` is not a .pbm or .png file", path.display()).into()),
+            };
+            atomic_file::write(&path, image)?;
+        }
+        Ok(self.view.map(|view| {
+            let title = match count {
+                Some(count) => format!("screen after {count}:\n"),
+                None => "screen:\n".to_string(),
+            };
+            title + &render(ram, view)
+        }))
+    }
+}
+
+/// `screen.png` to `screen.{count}.png`.
+fn numbered(path: &Path, count: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}.{count}.{extension}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::RAM_SIZE;
+
+    use super::*;
+
+    fn ram() -> Vec<i16> {
+        let mut ram = vec![0; RAM_SIZE];
+        // the 16 leftmost pixels of the first row, and the pixel at the bottom right
+        ram[SCREEN] = -1;
+        ram[SCREEN + 8191] = i16::MIN;
+        ram
+    }
+
+    #[test]
+    fn test_pbm() {
+        let image = pbm(&ram());
+        let header = b"P4\n512 256\n".len();
+        assert_eq!(image.len(), header + 64 * 256);
+        assert_eq!(&image[header..header + 3], [0xff, 0xff, 0]);
+        assert_eq!(image.last(), Some(&1));
+    }
+
+    #[test]
+    fn test_png() {
+        let image = png(&ram());
+        assert!(image.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"));
+        assert!(image.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_render() {
+        let ascii = render(&ram(), View::Ascii);
+        let lines = ascii.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 32);
+        assert!(lines[0].starts_with("####."));
+        assert!(lines[31].ends_with(".#"));
+        let blocks = render(&ram(), View::Blocks);
+        assert!(blocks.starts_with("▀▀▀▀ "));
+        assert!(blocks.trim_end().ends_with('▗'));
+
+        let options = ScreenOptions {
+            view: Some(View::Ascii),
+            ..Default::default()
+        };
+        let screen = options.capture(&ram(), Some(1000)).unwrap().unwrap();
+        assert_eq!(screen, format!("screen after 1000:\n{ascii}"));
+        assert_eq!(
+            ScreenOptions::default().capture(&ram(), None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(
+            numbered(Path::new("out/screen.png"), 1000),
+            Path::new("out/screen.1000.png")
+        );
+    }
+}
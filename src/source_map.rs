@@ -0,0 +1,133 @@
+use std::fmt;
+
+const HEADER: &str = "# vmtranslator source map v1";
+
+/// Where a VM command comes from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLocation {
+    pub file: String,
+    /// 1-based line number in `file`.
+    pub line: usize,
+    pub command: String,
+}
+
+/// The generated lines `first_line..=last_line` (1-based) come from one VM command.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceMapEntry {
+    pub first_line: usize,
+    pub last_line: usize,
+    /// VM function the command is in, empty before the first `function` command of a file.
+    pub function: String,
+    pub location: SourceLocation,
+}
+
+/// Maps generated code back to VM commands.
+///
+/// It is written as a `.map` sidecar with a header and one entry per line, the fields
+/// `first_line`, `last_line`, `file`, `line`, `function` and `command` are separated by tabs.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct SourceMap {
+    pub entries: Vec<SourceMapEntry>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, entry: SourceMapEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Index of the entry of every generated line of `lines`, like the ROM addresses of the
+    /// assembled code, `None` for lines which don't come from a VM command.
+    ///
+    /// `lines` must be sorted, like the entries.
+    pub fn entry_indexes(&self, lines: &[usize]) -> Vec<Option<usize>> {
+        let mut next = 0;
+        lines
+            .iter()
+            .map(|&line| {
+                while self
+                    .entries
+                    .get(next)
+                    .is_some_and(|entry| entry.last_line < line)
+                {
+                    next += 1;
+                }
+                Some(next).filter(|&i| {
+                    self.entries
+                        .get(i)
+                        .is_some_and(|entry| entry.first_line <= line)
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        writeln!(f, "# first_line\tlast_line\tfile\tline\tfunction\tcommand")?;
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                entry.first_line,
+                entry.last_line,
+                entry.location.file,
+                entry.location.line,
+                entry.function,
+                entry.location.command
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut map = SourceMap::new();
+        map.add(SourceMapEntry {
+            first_line: 3,
+            last_line: 7,
+            function: "Main.main".to_string(),
+            location: SourceLocation {
+                file: "Main.vm".to_string(),
+                line: 2,
+                command: "push constant 1".to_string(),
+            },
+        });
+        assert_eq!(
+            map.to_string(),
+            "# vmtranslator source map v1\n\
+             # first_line\tlast_line\tfile\tline\tfunction\tcommand\n\
+             3\t7\tMain.vm\t2\tMain.main\tpush constant 1\n"
+        );
+    }
+
+    #[test]
+    fn test_entry_indexes() {
+        let mut map = SourceMap::new();
+        for (first_line, last_line) in [(3, 4), (6, 8)] {
+            map.add(SourceMapEntry {
+                first_line,
+                last_line,
+                function: String::new(),
+                location: SourceLocation {
+                    file: "Main.vm".to_string(),
+                    line: first_line,
+                    command: "add".to_string(),
+                },
+            });
+        }
+        assert_eq!(
+            map.entry_indexes(&[1, 3, 4, 5, 7, 9]),
+            [None, Some(0), Some(0), None, Some(1), None]
+        );
+    }
+}
@@ -2,10 +2,15 @@
 use std::{
     fs::{self, File, OpenOptions},
     io::{self, Read, Seek, Write},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 const TEST_FILE_INIT_LINE_TOTAL: usize = 10;
 
+// tests run in parallel, every test file need a unique path
+static TEST_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 pub struct TestFile {
     pub file: File,
     pub path: String,
@@ -14,13 +19,15 @@ pub struct TestFile {
 
 impl TestFile {
     pub fn new() -> io::Result<Self> {
-        let path = "./TestVmtranslator.vm";
+        let id = TEST_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let path = format!("./TestVmtranslator{}_{id}.vm", process::id());
         let lines = TEST_FILE_INIT_LINE_TOTAL;
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)?;
+            .truncate(true)
+            .open(&path)?;
 
         for i in 0..lines {
             file.write_all(format!("{i}\n").as_bytes())?
@@ -28,7 +35,7 @@ impl TestFile {
 
         let mut f = Self {
             file,
-            path: path.to_string(),
+            path,
             lines: vec![],
         };
         f.read_to_lines()?;
@@ -64,7 +71,7 @@ impl TestFile {
 impl Drop for TestFile {
     fn drop(&mut self) {
         fs::remove_file(&mut self.path)
-            .expect(format!("remove test file `{}` fail...", &self.path).as_str());
+            .unwrap_or_else(|_| panic!("remove test file `{}` fail...", &self.path));
     }
 }
 
@@ -0,0 +1,627 @@
+use std::{
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    emulator::Emulator,
+    hack_asm::{assemble, parse_hack},
+    interpreter::Interpreter,
+    ir::Program,
+    translate::{input_files, translate_to_hack},
+};
+
+/// A statement of a nand2tetris test script.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Statement {
+    /// `load`, `None` loads all the `.vm` files of the script folder.
+    Load(Option<String>),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(String, i16),
+    Repeat(u64, Vec<Statement>),
+    TickTock,
+    /// `tick` is the first half of a cycle, it does nothing on this emulator.
+    Tick,
+    Tock,
+    VmStep,
+    Output,
+    Echo(String),
+    ClearEcho,
+}
+
+/// An `output-list` column, `name%Fl.w.r`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Column {
+    name: String,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+impl Column {
+    fn parse(s: &str) -> Result<Self, String> {
+        let error = || format!("invalid output column `{s}`");
+        let (name, format) = s.split_once('%').unwrap_or((s, "D1.6.1"));
+        let mut chars = format.chars();
+        let format = chars
+            .next()
+            .filter(|c| "DXBS".contains(*c))
+            .ok_or_else(error)?;
+        let sizes = chars
+            .as_str()
+            .split('.')
+            .map(|n| n.parse::<usize>().map_err(|_| error()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [left, width, right] = sizes[..] else {
+            return Err(error());
+        };
+        Ok(Self {
+            name: name.to_string(),
+            format,
+            left,
+            width,
+            right,
+        })
+    }
+
+    fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let name = &self.name[..self.name.len().min(total)];
+        let left = (total - name.len()) / 2;
+        format!(
+            "{}{name}{}",
+            " ".repeat(left),
+            " ".repeat(total - left - name.len())
+        )
+    }
+
+    fn cell(&self, value: i16) -> String {
+        let width = self.width;
+        let value = match self.format {
+            'X' => format!("{:0width$X}", value as u16),
+            'B' => format!("{:0width$b}", value as u16),
+            _ => format!("{value:>width$}"),
+        };
+        let value = &value[value.len().saturating_sub(width)..];
+        format!("{}{value}{}", " ".repeat(self.left), " ".repeat(self.right))
+    }
+}
+
+/// The result of a test script.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Outcome {
+    Pass,
+    /// The first output line, 1-based, different from the compare file.
+    Fail {
+        line: usize,
+        expected: String,
+        actual: String,
+    },
+    Skip(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "ok"),
+            Outcome::Fail {
+                line,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "comparison failure at line {line}\n  expected: {expected}\n  actual:   {actual}"
+            ),
+            Outcome::Skip(reason) => write!(f, "skipped, {reason}"),
+        }
+    }
+}
+
+/// The machine a script runs on, chosen by its `load` statement.
+enum Machine {
+    Cpu(Emulator),
+    Vm(Interpreter),
+}
+
+/// A nand2tetris `.tst` script.
+pub struct TestScript {
+    path: PathBuf,
+    statements: Vec<Statement>,
+}
+
+impl TestScript {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let source = fs::read_to_string(path)?;
+        let statements = parse(&source)
+            .map_err(|(line, message)| format!("{}:{line}: {message}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            statements,
+        })
+    }
+
+    /// Whether the script runs on the VM emulator, it loads `.vm` files.
+    pub fn is_vm(&self) -> bool {
+        self.statements.iter().any(|statement| match statement {
+            Statement::Load(None) => true,
+            Statement::Load(Some(file)) => file.ends_with(".vm"),
+            _ => false,
+        })
+    }
+
+    /// Runs the script and compares its output with the `compare-to` file.
+    pub fn run(&self) -> Result<Outcome, Box<dyn Error>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut run = Run {
+            dir: dir.to_path_buf(),
+            machine: None,
+            columns: vec![],
+            output: vec![],
+            compare_to: None,
+        };
+        if let Some(skip) = run.execute(&self.statements)? {
+            return Ok(skip);
+        }
+
+        let Some(compare_to) = run.compare_to else {
+            return Ok(Outcome::Pass);
+        };
+        let expected = fs::read_to_string(dir.join(compare_to))?;
+        let expected = expected.lines().collect::<Vec<_>>();
+        for i in 0..expected.len().max(run.output.len()) {
+            let expected = expected.get(i).copied().unwrap_or_default();
+            let actual = run.output.get(i).map_or("", |line| line.as_str());
+            if !same_line(expected, actual) {
+                return Ok(Outcome::Fail {
+                    line: i + 1,
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+        Ok(Outcome::Pass)
+    }
+}
+
+/// The `.tst` files under a folder, recursively, in path order.
+pub fn discover(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut scripts = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            scripts.append(&mut discover(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == "tst") {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+/// Cells are compared without their padding, `*` cells of the compare file match anything.
+fn same_line(expected: &str, actual: &str) -> bool {
+    let expected = expected.trim().split('|').collect::<Vec<_>>();
+    let actual = actual.trim().split('|').collect::<Vec<_>>();
+    expected.len() == actual.len()
+        && expected.iter().zip(&actual).all(|(e, a)| {
+            let e = e.trim();
+            e == a.trim() || !e.is_empty() && e.chars().all(|c| c == '*')
+        })
+}
+
+/// The state of a running script.
+struct Run {
+    dir: PathBuf,
+    machine: Option<Machine>,
+    columns: Vec<Column>,
+    output: Vec<String>,
+    compare_to: Option<String>,
+}
+
+impl Run {
+    /// Returns an outcome when the script can't run here.
+    fn execute(&mut self, statements: &[Statement]) -> Result<Option<Outcome>, Box<dyn Error>> {
+        for statement in statements {
+            match statement {
+                Statement::Load(file) => {
+                    if let Some(skip) = self.load(file.as_deref())? {
+                        return Ok(Some(skip));
+                    }
+                }
+                Statement::OutputFile(_) => {}
+                Statement::CompareTo(file) => self.compare_to = Some(file.clone()),
+                Statement::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    let headers = columns.iter().map(Column::header).collect::<Vec<_>>();
+                    self.output.push(format!("|{}|", headers.join("|")));
+                }
+                Statement::Set(variable, value) => {
+                    let address = self.variable(variable)?;
+                    self.ram()?[address] = *value;
+                }
+                Statement::Repeat(n, body) => {
+                    for _ in 0..*n {
+                        if let Some(skip) = self.execute(body)? {
+                            return Ok(Some(skip));
+                        }
+                    }
+                }
+                Statement::TickTock | Statement::Tock => match &mut self.machine {
+                    Some(Machine::Cpu(emulator)) => {
+                        emulator.step()?;
+                    }
+                    _ => return Err("`ticktock` need a loaded `.asm` or `.hack` program".into()),
+                },
+                Statement::Tick => {}
+                Statement::VmStep => match &mut self.machine {
+                    Some(Machine::Vm(interpreter)) => {
+                        interpreter.step()?;
+                    }
+                    _ => return Err("`vmstep` need loaded `.vm` files".into()),
+                },
+                Statement::Output => {
+                    let mut cells = vec![];
+                    for column in self.columns.clone() {
+                        let value = match self.variable(&column.name) {
+                            Ok(address) => self.ram()?[address],
+                            Err(_) => self.register(&column.name)?,
+                        };
+                        cells.push(column.cell(value));
+                    }
+                    self.output.push(format!("|{}|", cells.join("|")));
+                }
+                Statement::Echo(message) => println!("{message}"),
+                Statement::ClearEcho => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn load(&mut self, file: Option<&str>) -> Result<Option<Outcome>, Box<dyn Error>> {
+        let vm_files = input_files(&self.dir)?;
+        match file {
+            None => self.load_vm(&vm_files)?,
+            Some(file) if file.ends_with(".vm") => self.load_vm(&[self.dir.join(file)])?,
+            Some(file) if file.ends_with(".hack") => {
+                let rom = parse_hack(&fs::read_to_string(self.dir.join(file))?)?;
+                self.machine = Some(Machine::Cpu(Emulator::new(rom)));
+            }
+            // the program under test is the translation of the `.vm` files of the folder
+            Some(file) if file.ends_with(".asm") => {
+                let asm = if vm_files.is_empty() {
+                    fs::read_to_string(self.dir.join(file))?
+                } else {
+                    let bootstrap = Program::load(&vm_files)?.defines("Sys.init");
+                    translate_to_hack(&vm_files, bootstrap)?.0
+                };
+                self.machine = Some(Machine::Cpu(Emulator::new(assemble(&asm)?.words)));
+            }
+            Some(file) => return Ok(Some(Outcome::Skip(format!("can't load `{file}`")))),
+        }
+        Ok(None)
+    }
+
+    /// Like the VM emulator of nand2tetris, the program starts at `Sys.init` without a
+    /// bootstrap call when it has one.
+    fn load_vm(&mut self, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let mut interpreter = Interpreter::new(&Program::load(files)?)?;
+        if let Some(entry) = interpreter.function_entry("Sys.init") {
+            interpreter.pc = entry;
+        }
+        self.machine = Some(Machine::Vm(interpreter));
+        Ok(())
+    }
+
+    fn ram(&mut self) -> Result<&mut Vec<i16>, String> {
+        match &mut self.machine {
+            Some(Machine::Cpu(emulator)) => Ok(&mut emulator.ram),
+            Some(Machine::Vm(interpreter)) => Ok(&mut interpreter.ram),
+            None => Err("no program is loaded".to_string()),
+        }
+    }
+
+    /// RAM address of a variable, `RAM[n]`, or on the VM emulator `sp`, `local`,
+    /// `argument`, `this`, `that`, `local[n]`, `argument[n]`, `this[n]`, `that[n]`
+    /// and `temp[n]`.
+    fn variable(&mut self, name: &str) -> Result<usize, Box<dyn Error>> {
+        let error = || format!("unknown variable `{name}`");
+        let (name, index) = match name.split_once('[') {
+            Some((name, index)) => {
+                let index = index.strip_suffix(']').ok_or_else(error)?;
+                (name, Some(index.parse::<usize>().map_err(|_| error())?))
+            }
+            None => (name, None),
+        };
+        let is_vm = matches!(self.machine, Some(Machine::Vm(_)));
+        let pointer = ["sp", "local", "argument", "this", "that"]
+            .iter()
+            .position(|pointer| *pointer == name)
+            .filter(|_| is_vm);
+        let ram = self.ram()?;
+        let address = match (name, index, pointer) {
+            ("RAM", Some(index), _) => index,
+            ("temp", Some(index), _) if is_vm && index < 8 => 5 + index,
+            (_, None, Some(pointer)) => pointer,
+            (_, Some(index), Some(pointer)) if pointer > 0 => ram[pointer] as u16 as usize + index,
+            _ => return Err(error().into()),
+        };
+        if address >= ram.len() {
+            return Err(format!("`{name}` is out of the RAM").into());
+        }
+        Ok(address)
+    }
+
+    /// `A`, `D`, `PC` and `time` of the CPU emulator.
+    fn register(&self, name: &str) -> Result<i16, Box<dyn Error>> {
+        match (&self.machine, name) {
+            (Some(Machine::Cpu(emulator)), "A") => Ok(emulator.a),
+            (Some(Machine::Cpu(emulator)), "D") => Ok(emulator.d),
+            (Some(Machine::Cpu(emulator)), "PC") => Ok(emulator.pc as i16),
+            (Some(Machine::Cpu(emulator)), "time") => Ok(emulator.cycles as i16),
+            _ => Err(format!("unknown variable `{name}`").into()),
+        }
+    }
+}
+
+/// A token and its 1-based line.
+type Token = (usize, String);
+
+fn tokenize(source: &str) -> Result<Vec<Token>, (usize, String)> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|c| *c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            last = c;
+                        }
+                        None => return Err((line, "unterminated comment".to_string())),
+                    }
+                }
+            }
+            '{' | '}' | ',' | ';' | '!' => tokens.push((line, c.to_string())),
+            '"' => {
+                let mut s = String::from('"');
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => return Err((line, "unterminated string".to_string())),
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push((line, s));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"{},;!\"".contains(*c))
+                {
+                    word.push(c);
+                }
+                tokens.push((line, word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse(source: &str) -> Result<Vec<Statement>, (usize, String)> {
+    let tokens = tokenize(source)?;
+    let mut tokens = tokens.iter().peekable();
+    let statements = parse_block(&mut tokens, 0)?;
+    Ok(statements)
+}
+
+fn parse_block<'a>(
+    tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a Token>>,
+    depth: usize,
+) -> Result<Vec<Statement>, (usize, String)> {
+    let mut statements = vec![];
+    loop {
+        let Some((line, word)) = tokens.next() else {
+            if depth > 0 {
+                return Err((0, "`}` is missing".to_string()));
+            }
+            return Ok(statements);
+        };
+        let line = *line;
+        let error = |message: String| (line, message);
+        if word == "}" {
+            if depth == 0 {
+                return Err(error("unexpected `}`".to_string()));
+            }
+            return Ok(statements);
+        }
+        if word == "repeat" {
+            // `repeat {` without a count loops forever, it never ends a test
+            let count = match tokens.next() {
+                Some((_, n)) if n == "{" => {
+                    return Err(error("endless `repeat` is not supported".to_string()))
+                }
+                Some((_, n)) => n
+                    .parse()
+                    .map_err(|_| error(format!("`{n}` is not a repeat count")))?,
+                None => return Err(error("`repeat` need a block".to_string())),
+            };
+            if tokens.next().is_none_or(|(_, t)| t != "{") {
+                return Err(error("`{` is missing after `repeat`".to_string()));
+            }
+            let body = parse_block(tokens, depth + 1)?;
+            statements.push(Statement::Repeat(count, body));
+            continue;
+        }
+
+        let mut words = vec![word.as_str()];
+        while let Some((_, word)) = tokens.next_if(|(_, t)| !",;!{}".contains(t.as_str())) {
+            words.push(word);
+        }
+        // a statement ends with `,`, `;` or `!`, the last one of a block may not
+        tokens.next_if(|(_, t)| t == "," || t == ";" || t == "!");
+
+        let arg = |i: usize| {
+            words
+                .get(i)
+                .map(|w| w.to_string())
+                .ok_or_else(|| error(format!("`{}` need an argument", words[0])))
+        };
+        let statement = match words[0] {
+            "load" => Statement::Load(words.get(1).map(|w| w.to_string())),
+            "output-file" => Statement::OutputFile(arg(1)?),
+            "compare-to" => Statement::CompareTo(arg(1)?),
+            "output-list" => Statement::OutputList(
+                words[1..]
+                    .iter()
+                    .map(|w| Column::parse(w).map_err(error))
+                    .collect::<Result<_, _>>()?,
+            ),
+            "set" => {
+                let value = arg(2)?;
+                Statement::Set(
+                    arg(1)?,
+                    parse_value(&value)
+                        .ok_or_else(|| error(format!("`{value}` is not a value")))?,
+                )
+            }
+            "ticktock" => Statement::TickTock,
+            "tick" => Statement::Tick,
+            "tock" => Statement::Tock,
+            "vmstep" => Statement::VmStep,
+            "output" => Statement::Output,
+            "echo" => Statement::Echo(arg(1)?.trim_start_matches('"').to_string()),
+            "clear-echo" => Statement::ClearEcho,
+            word => return Err(error(format!("unsupported statement `{word}`"))),
+        };
+        statements.push(statement);
+    }
+}
+
+/// `-1`, `%D-1`, `%XFFFF` or `%B1111111111111111`.
+fn parse_value(s: &str) -> Option<i16> {
+    let (radix, digits) = match s.strip_prefix('%') {
+        Some(s) if s.starts_with('D') => (10, &s[1..]),
+        Some(s) if s.starts_with('X') => (16, &s[1..]),
+        Some(s) if s.starts_with('B') => (2, &s[1..]),
+        Some(_) => return None,
+        None => (10, s),
+    };
+    if radix == 10 {
+        return digits
+            .parse::<i32>()
+            .ok()
+            .filter(|n| *n >= i16::MIN as i32 && *n <= u16::MAX as i32)
+            .map(|n| n as i16);
+    }
+    u16::from_str_radix(digits, radix).ok().map(|n| n as i16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let statements = parse(
+            "// header\n\
+             load SimpleAdd.asm,\n\
+             compare-to SimpleAdd.cmp,\n\
+             output-list RAM[0]%D2.6.2 RAM[256]%D2.6.2;\n\
+             /* stack\n pointer */ set RAM[0] 256,\n\
+             repeat 60 {\n  ticktock;\n}\n\
+             output;\n",
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                Statement::Load(Some("SimpleAdd.asm".to_string())),
+                Statement::CompareTo("SimpleAdd.cmp".to_string()),
+                Statement::OutputList(vec![
+                    Column::parse("RAM[0]%D2.6.2").unwrap(),
+                    Column::parse("RAM[256]%D2.6.2").unwrap(),
+                ]),
+                Statement::Set("RAM[0]".to_string(), 256),
+                Statement::Repeat(60, vec![Statement::TickTock]),
+                Statement::Output,
+            ]
+        );
+        assert_eq!(parse("load,\nbreakpoint PC 3;").unwrap_err().0, 2);
+    }
+
+    #[test]
+    fn test_column() {
+        let column = Column::parse("RAM[0]%D2.6.2").unwrap();
+        assert_eq!(column.header(), "  RAM[0]  ");
+        assert_eq!(column.cell(257), "     257  ");
+        assert_eq!(Column::parse("A%X1.4.1").unwrap().cell(-1), " FFFF ");
+        assert!(Column::parse("A%Q1.4.1").is_err());
+    }
+
+    #[test]
+    fn test_same_line() {
+        assert!(same_line("|  RAM[0]  |", "|  RAM[0] |"));
+        assert!(same_line("|   257 | ***** |", "|   257 |    3 |"));
+        assert!(!same_line("|   257 |", "|   258 |"));
+        assert!(!same_line("|   257 |", "|   257 |  1 |"));
+    }
+
+    #[test]
+    fn test_run() -> Result<(), Box<dyn Error>> {
+        let dir = Path::new("./test_script_run");
+        fs::create_dir_all(dir)?;
+        fs::write(
+            dir.join("Add.vm"),
+            "push constant 7\npush constant 8\nadd\n",
+        )?;
+        let script =
+            "load Add.asm,\ncompare-to Add.cmp,\noutput-list RAM[0]%D2.6.2 RAM[256]%D2.6.2;\n\
+                      set RAM[0] 256,\nrepeat 60 {\n  ticktock;\n}\noutput;\n";
+        fs::write(dir.join("Add.tst"), script)?;
+        let vm_script = script
+            .replace("Add.asm", "Add.vm")
+            .replace("repeat 60 {\n  ticktock;", "repeat 3 {\n  vmstep;");
+        fs::write(dir.join("AddVME.tst"), vm_script)?;
+        fs::write(
+            dir.join("Add.cmp"),
+            "|  RAM[0]  | RAM[256] |\n|     257  |      15  |\n",
+        )?;
+
+        let scripts = discover(dir)?;
+        let outcomes = scripts
+            .iter()
+            .map(|path| TestScript::load(path)?.run())
+            .collect::<Vec<_>>();
+        let is_vm = TestScript::load(&scripts[1])?.is_vm();
+        fs::write(
+            dir.join("Add.cmp"),
+            "|  RAM[0]  | RAM[256] |\n|     257  |      16  |\n",
+        )?;
+        let failure = TestScript::load(&scripts[0])?.run();
+        fs::remove_dir_all(dir)?;
+
+        assert_eq!(scripts, vec![dir.join("Add.tst"), dir.join("AddVME.tst")]);
+        assert!(is_vm);
+        for outcome in outcomes {
+            assert_eq!(outcome?, Outcome::Pass);
+        }
+        assert!(matches!(failure?, Outcome::Fail { line: 2, .. }));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Display;
+
+use crate::{interpreter::STACK_BASE, source_map::SourceMap};
+
+const SP: usize = 0;
+
+/// The functions `--trace` prints the commands of, all of them when it's empty.
+///
+/// `Main.*` stands for all the functions of the class `Main`.
+#[derive(Debug, Default)]
+pub struct TraceFilter {
+    pub functions: Vec<String>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, function: &str) -> bool {
+        self.functions.is_empty()
+            || self
+                .functions
+                .iter()
+                .any(|name| match name.strip_suffix('*') {
+                    Some(prefix) => function.starts_with(prefix),
+                    None => function == name,
+                })
+    }
+}
+
+/// A traced VM command and the stack after it, like
+/// `Main.vm:14 Main.fibonacci: call Main.fibonacci 1    SP=262 top=10`.
+pub fn trace_line(
+    file: &str,
+    line: usize,
+    function: &str,
+    command: impl Display,
+    ram: &[i16],
+) -> String {
+    let sp = ram[SP];
+    let top = match ram.get((sp as u16 as usize).wrapping_sub(1)) {
+        Some(top) if sp > STACK_BASE => top.to_string(),
+        _ => "-".to_string(),
+    };
+    let command = if function.is_empty() {
+        format!("{file}:{line}: {command}")
+    } else {
+        format!("{file}:{line} {function}: {command}")
+    };
+    format!("{command:<48} SP={sp} top={top}")
+}
+
+/// Follows the Hack translation of a program to tell when a VM command is done.
+pub struct HackTracer<'a> {
+    source_map: &'a SourceMap,
+    /// Source map entry of every ROM address.
+    entries: Vec<Option<usize>>,
+    /// The command executing.
+    current: Option<usize>,
+}
+
+impl<'a> HackTracer<'a> {
+    /// `lines` are the assembly lines of the ROM addresses.
+    pub fn new(source_map: &'a SourceMap, lines: &[usize]) -> Self {
+        Self {
+            source_map,
+            entries: source_map.entry_indexes(lines),
+            current: None,
+        }
+    }
+
+    /// To call after every Hack instruction, the trace line of the VM command done when the
+    /// program counter `pc` starts another one or leaves the translated commands.
+    pub fn step(&mut self, pc: u16, ram: &[i16], filter: &TraceFilter) -> Option<String> {
+        let pc = pc as usize;
+        let entry = self.entries.get(pc).copied().flatten();
+        let start = entry.is_some() && (pc == 0 || self.entries.get(pc - 1) != Some(&entry));
+        if !start && entry.is_some() {
+            // the program started inside the command
+            self.current = self.current.or(entry);
+            return None;
+        }
+        let done = std::mem::replace(&mut self.current, entry)?;
+        let entry = &self.source_map.entries[done];
+        filter.matches(&entry.function).then(|| {
+            trace_line(
+                &entry.location.file,
+                entry.location.line,
+                &entry.function,
+                &entry.location.command,
+                ram,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hack_asm::assemble, translate::translate_to_hack};
+
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = TraceFilter {
+            functions: vec!["Main.*".to_string(), "Sys.init".to_string()],
+        };
+        assert!(filter.matches("Main.fibonacci"));
+        assert!(filter.matches("Sys.init"));
+        assert!(!filter.matches("Sys.halt"));
+        assert!(TraceFilter::default().matches("Sys.halt"));
+    }
+
+    #[test]
+    fn test_hack_tracer() {
+        let path = std::path::PathBuf::from("./test_trace.vm");
+        std::fs::write(&path, "push constant 7\npush constant 8\nadd\n").unwrap();
+        let (asm, source_map) = translate_to_hack(std::slice::from_ref(&path), false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let assembly = assemble(&asm).unwrap();
+
+        let mut emulator = crate::emulator::Emulator::new(assembly.words);
+        emulator.ram[SP] = STACK_BASE;
+        let mut tracer = HackTracer::new(&source_map, &assembly.lines);
+        let mut lines = vec![];
+        while emulator.step().unwrap().is_none() {
+            lines.extend(tracer.step(emulator.pc, &emulator.ram, &TraceFilter::default()));
+        }
+        assert_eq!(
+            lines,
+            [
+                format!("{:<48} SP=257 top=7", "test_trace.vm:1: push constant 7"),
+                format!("{:<48} SP=258 top=8", "test_trace.vm:2: push constant 8"),
+                format!("{:<48} SP=257 top=15", "test_trace.vm:3: add"),
+            ]
+        );
+    }
+}
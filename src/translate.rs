@@ -0,0 +1,204 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    backend::HackBackend,
+    code_writer::{CodeWriter, Comments},
+    ir::{Command, Program, VmFile},
+    source_map::SourceMap,
+};
+
+/// How much the translation optimizes the program, `-O0` or `-O1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    /// Every command is translated.
+    #[default]
+    O0,
+    /// The functions no call from `Sys.init` reaches are left out.
+    O1,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            _ => Err(format!("unknown optimization level `{s}`, 0 or 1")),
+        }
+    }
+}
+
+/// The `.vm` files of the input, a file is used whatever its extension.
+pub fn input_files(input_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if input_path.is_file() {
+        return Ok(vec![input_path.to_path_buf()]);
+    }
+    if !input_path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("`{}` is not a file or folder", input_path.display()),
+        )
+        .into());
+    }
+    let mut input_files = vec![];
+    for entry in fs::read_dir(input_path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|extension| extension == "vm") {
+            input_files.push(path);
+        }
+    }
+    // `read_dir` order depends on the file system
+    input_files.sort();
+    Ok(input_files)
+}
+
+/// Hack assembly of the `.vm` files, without comments, and its source map.
+///
+/// Without `bootstrap` the program starts at its first command, like the programs of the
+/// nand2tetris tests which have no `Sys.init`.
+pub fn translate_to_hack(
+    input_files: &[PathBuf],
+    bootstrap: bool,
+) -> Result<(String, SourceMap), Box<dyn Error>> {
+    let backend = Box::new(HackBackend::new());
+    let mut code_writer = if bootstrap {
+        CodeWriter::from_writer(vec![], backend, Comments::None)?
+    } else {
+        CodeWriter::from_writer_without_bootstrap(vec![], backend, Comments::None)
+    };
+    translate(&mut code_writer, input_files, OptLevel::O0)?;
+    let source_map = code_writer.source_map().clone();
+    Ok((String::from_utf8(code_writer.into_inner())?, source_map))
+}
+
+pub fn translate<W: io::Write>(
+    code_writer: &mut CodeWriter<W>,
+    input_files: &[PathBuf],
+    opt_level: OptLevel,
+) -> Result<(), Box<dyn Error>> {
+    let program = Program::load(input_files)?;
+    let left_out = if opt_level >= OptLevel::O1 {
+        program.unreachable_functions()
+    } else {
+        HashSet::new()
+    };
+    for file in &program.files {
+        translate_file(code_writer, file, &left_out)?;
+    }
+    code_writer.close()?;
+    Ok(())
+}
+
+/// The commands of the functions in `left_out` aren't translated.
+fn translate_file<W: io::Write>(
+    code_writer: &mut CodeWriter<W>,
+    file: &VmFile,
+    left_out: &HashSet<String>,
+) -> io::Result<()> {
+    code_writer.set_source_file(&file.name);
+
+    let mut skip = false;
+    for (i, instruction) in file.instructions.iter().enumerate() {
+        if let Command::Function(name, _) = &instruction.command {
+            skip = left_out.contains(name);
+        }
+        if skip {
+            continue;
+        }
+        let command = &instruction.command;
+        code_writer.set_source_line(instruction.line, &command.to_string());
+        match command {
+            Command::Push(segment, index) => {
+                code_writer.write_push_pop("push", segment.name(), *index as i32)?
+            }
+            Command::Pop(segment, index) => {
+                code_writer.write_push_pop("pop", segment.name(), *index as i32)?
+            }
+            Command::Arithmetic(op) => {
+                code_writer.write_arithmetic(op.name(), &format!("{}.{i}", file.name))?
+            }
+            Command::Label(label) => code_writer.write_label(label)?,
+            Command::Goto(label) => code_writer.write_goto(label)?,
+            Command::IfGoto(label) => code_writer.write_if(label)?,
+            Command::Function(name, n_vars) => code_writer.write_function(name, *n_vars as u32)?,
+            Command::Call(name, n_args) => code_writer.write_call(name, *n_args as u32)?,
+            Command::Return => code_writer.write_return()?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_files() -> Result<(), Box<dyn Error>> {
+        let dir = Path::new("./test_input_files");
+        fs::create_dir_all(dir)?;
+        for name in ["Sys.vm", "Main.vm", "README", "Main.asm"] {
+            fs::write(dir.join(name), "")?;
+        }
+        let files = input_files(dir);
+        fs::remove_dir_all(dir)?;
+        assert_eq!(files?, vec![dir.join("Main.vm"), dir.join("Sys.vm")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_left_out_functions() -> Result<(), Box<dyn Error>> {
+        let path = PathBuf::from("./test_left_out_functions.vm");
+        fs::write(
+            &path,
+            "function Sys.init 0\ncall Sys.used 0\nlabel END\ngoto END\n\
+             function Sys.unused 0\npush constant 1111\nreturn\n\
+             function Sys.used 0\npush constant 2222\nreturn\n",
+        )?;
+        let translate = |opt_level| -> Result<String, Box<dyn Error>> {
+            let backend = Box::new(HackBackend::new());
+            let mut code_writer = CodeWriter::from_writer(vec![], backend, Comments::None)?;
+            translate(&mut code_writer, std::slice::from_ref(&path), opt_level)?;
+            Ok(String::from_utf8(code_writer.into_inner())?)
+        };
+        let (o0, o1) = (translate(OptLevel::O0), translate(OptLevel::O1));
+        fs::remove_file(&path)?;
+        let (o0, o1) = (o0?, o1?);
+        assert!(o0.contains("(Sys.unused)") && o0.contains("@1111\n"));
+        assert!(!o1.contains("(Sys.unused)") && !o1.contains("@1111\n"));
+        assert!(o1.contains("(Sys.used)") && o1.contains("@2222\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_command() -> Result<(), Box<dyn Error>> {
+        let path = PathBuf::from("./test_invalid_command.vm");
+        fs::write(&path, "push constant 1\npushx constant 2\n")?;
+        let translation = translate_to_hack(std::slice::from_ref(&path), false);
+        fs::remove_file(&path)?;
+        assert_eq!(
+            translation.unwrap_err().to_string(),
+            "test_invalid_command.vm:2: unknow command `pushx constant 2`"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_blank_lines() -> Result<(), Box<dyn Error>> {
+        let path = Path::new("./test_trailing_blank_lines.vm");
+        fs::write(path, "push constant 1\n\n// end\n\n")?;
+        let translation = translate_to_hack(&[path.to_path_buf()], false);
+        fs::remove_file(path)?;
+        let (asm, source_map) = translation?;
+        assert_eq!(source_map.entries.len(), 1);
+        assert_eq!(asm.matches("@1\n").count(), 1);
+        Ok(())
+    }
+}